@@ -0,0 +1,16 @@
+// Exposes the version of wasmtime rvm is linked against as `RVM_WASMTIME_VERSION`,
+// read from the lockfile since wasmtime doesn't export it.
+fn main() {
+    println!("cargo:rerun-if-changed=Cargo.lock");
+    let lock = std::fs::read_to_string("Cargo.lock").unwrap_or_default();
+    let version = lock
+        .split("[[package]]")
+        .find(|package| package.contains("\nname = \"wasmtime\"\n"))
+        .and_then(|package| {
+            package
+                .lines()
+                .find_map(|line| line.strip_prefix("version = "))
+        })
+        .map_or("unknown", |version| version.trim_matches('"'));
+    println!("cargo:rustc-env=RVM_WASMTIME_VERSION={version}");
+}
@@ -8,6 +8,33 @@ import weakref
 from ..types import Result, Ok, Err, Some
 
 
+@dataclass
+class ModuleInfo:
+    """
+    Identity of the module that is running.
+    """
+    key: str
+    hash: str
+
+@dataclass
+class LocalRequest:
+    """
+    A request to another module deployed on the same host.
+    """
+    method: str
+    path: str
+    headers: List[Tuple[str, bytes]]
+    body: bytes
+
+@dataclass
+class LocalResponse:
+    """
+    The response of a module invoked with `invoke-local`.
+    """
+    status: int
+    headers: List[Tuple[str, bytes]]
+    body: bytes
+
 
 def multiply(a: float, b: float) -> float:
     raise NotImplementedError
@@ -15,3 +42,35 @@ def multiply(a: float, b: float) -> float:
 def client_secret() -> str:
     raise NotImplementedError
 
+def self_info() -> ModuleInfo:
+    """
+    Returns the key and hash of the calling module.
+    """
+    raise NotImplementedError
+
+def metric_incr(name: str, value: int) -> None:
+    """
+    Adds `value` to the counter `name` of the calling module, exported by
+    the host's `/metrics`. Names are made of letters, digits and `_`.
+    """
+    raise NotImplementedError
+
+def metric_observe(name: str, value: float) -> None:
+    """
+    Records an observation of `value` in the summary `name` of the calling
+    module, exported by the host's `/metrics`.
+    """
+    raise NotImplementedError
+
+def invoke_local(key: str, request: LocalRequest) -> LocalResponse:
+    """
+    Invokes the module deployed as `key`, of the same tenant as the calling
+    module, without leaving the host. The invocation is subject to the
+    limits of the invoked module, as if a client made it, and refusals by
+    the host are returned as responses too. Fails if it would pass through
+    more modules than the host allows, or invoke a module waiting on it.
+    
+    Raises: `rvm.types.Err(builtins.str)`
+    """
+    raise NotImplementedError
+
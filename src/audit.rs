@@ -0,0 +1,73 @@
+use anyhow::Result;
+
+/// Where the audit log is stored, one JSON event per line, oldest first.
+pub const PATH: &str = "audit/events.jsonl";
+
+/// A change to the deployed modules, recorded in the audit log.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct AuditEvent {
+    /// When the change was made, in RFC 3339 format.
+    pub at: String,
+    pub action: AuditAction,
+    pub key: String,
+    /// Hash of the module version that went live.
+    pub hash: String,
+    /// Size of the component, in bytes.
+    pub bytes: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AuditAction {
+    Deploy,
+    Rollback,
+}
+
+impl AuditEvent {
+    pub fn new(action: AuditAction, key: &str, hash: &blake3::Hash, bytes: usize) -> Self {
+        Self {
+            at: chrono::Utc::now().to_rfc3339(),
+            action,
+            key: key.to_owned(),
+            hash: hash.to_string(),
+            bytes,
+        }
+    }
+}
+
+/// Appends `event` to the audit log. Storage that can't append, such as the
+/// in-memory one, gets the whole log rewritten instead.
+pub async fn record(storage: &opendal::Operator, event: &AuditEvent) -> Result<()> {
+    let mut line = serde_json::to_vec(event)?;
+    line.push(b'\n');
+    if storage.info().full_capability().write_can_append {
+        storage.write_with(PATH, line).append(true).await?;
+        return Ok(());
+    }
+    let mut log = match storage.read(PATH).await {
+        Ok(log) => log.to_vec(),
+        Err(e) if e.kind() == opendal::ErrorKind::NotFound => Vec::new(),
+        Err(e) => return Err(e.into()),
+    };
+    log.extend_from_slice(&line);
+    storage.write(PATH, log).await?;
+    Ok(())
+}
+
+/// The last `limit` events of the audit log, oldest first. Lines that can't
+/// be parsed are skipped.
+pub async fn recent(storage: &opendal::Operator, limit: usize) -> Result<Vec<AuditEvent>> {
+    let log = match storage.read(PATH).await {
+        Ok(log) => log.to_vec(),
+        Err(e) if e.kind() == opendal::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+    let mut events: Vec<AuditEvent> = log
+        .split(|&b| b == b'\n')
+        .filter(|line| !line.is_empty())
+        .filter_map(|line| serde_json::from_slice(line).ok())
+        .collect();
+    let skipped = events.len().saturating_sub(limit);
+    events.drain(..skipped);
+    Ok(events)
+}
@@ -0,0 +1,244 @@
+use std::{
+    future::Future,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use wasmtime::{Engine, ResourceLimiter, Trap, UpdateDeadline};
+
+use crate::config::{ModuleConfig, RvmConfig};
+
+/// How often the engine's epoch advances, the granularity of invocation timeouts.
+pub const EPOCH_TICK: Duration = Duration::from_millis(10);
+
+/// Everything a single invocation of a module may use, its own settings taking
+/// precedence over the host's.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+pub struct ResourceBudget {
+    /// Fuel each invocation starts with, however much the previous ones used.
+    pub fuel: u64,
+    /// How long an invocation may run before it is interrupted, unlimited if unset.
+    #[serde(rename = "timeout_secs", serialize_with = "serialize_secs")]
+    pub timeout: Option<Duration>,
+    /// How long the guest may run during an invocation, not counting the time
+    /// it waits on I/O, unlimited if unset.
+    #[serde(rename = "max_cpu_ms", serialize_with = "serialize_ms")]
+    pub max_cpu: Option<Duration>,
+    /// Largest the instance's memory may grow to, only limited by the pool's
+    /// `max_memory_size` if unset.
+    pub max_memory_bytes: Option<usize>,
+}
+
+impl ResourceBudget {
+    pub fn new(module_config: &ModuleConfig, config: &RvmConfig) -> Self {
+        Self {
+            fuel: module_config.fuel.unwrap_or(config.default_fuel),
+            timeout: module_config
+                .timeout_secs
+                .or(config.invocation_timeout_secs)
+                .filter(|&secs| secs > 0)
+                .map(Duration::from_secs),
+            max_cpu: module_config
+                .max_cpu_ms
+                .or(config.max_cpu_ms)
+                .filter(|&ms| ms > 0)
+                .map(Duration::from_millis),
+            max_memory_bytes: module_config.max_memory_bytes,
+        }
+    }
+
+    /// Epoch ticks an invocation may run for, see [`spawn_epoch_ticker`]. With
+    /// a CPU time budget, the deadline is checked by [`ResourceBudget::check_deadline`]
+    /// on every tick instead.
+    pub fn epoch_deadline(&self) -> u64 {
+        if self.max_cpu.is_some() {
+            return 1;
+        }
+        match self.timeout {
+            Some(timeout) => timeout.as_millis().div_ceil(EPOCH_TICK.as_millis()) as u64,
+            // Far enough to never be reached, without overflowing the engine's epoch
+            None => u64::MAX / 2,
+        }
+    }
+
+    /// Interrupts an invocation that ran past its timeout, or used up its CPU
+    /// time as measured by `clock`. Called by the engine on every epoch tick
+    /// while the guest runs, if it has a CPU time budget.
+    pub fn check_deadline(&self, clock: &CpuClock) -> anyhow::Result<UpdateDeadline> {
+        let (cpu, wall) = clock.elapsed();
+        if self.timeout.is_some_and(|timeout| wall >= timeout) {
+            return Err(Trap::Interrupt.into());
+        }
+        if self.max_cpu.is_some_and(|max| cpu >= max) {
+            return Err(CpuTimeExceeded.into());
+        }
+        Ok(UpdateDeadline::Continue(1))
+    }
+
+    /// Keeps track of the memory of an instance, refusing to grow it past the budget.
+    pub fn memory_limiter(&self) -> MemoryLimiter {
+        MemoryLimiter {
+            max_bytes: self.max_memory_bytes,
+            peak_bytes: 0,
+        }
+    }
+}
+
+fn serialize_secs<S: serde::Serializer>(
+    timeout: &Option<Duration>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.serialize_some(&timeout.map(|timeout| timeout.as_secs()))
+}
+
+fn serialize_ms<S: serde::Serializer>(
+    duration: &Option<Duration>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    serializer.serialize_some(&duration.map(|duration| duration.as_millis() as u64))
+}
+
+/// Why an invocation that used up its CPU time budget was interrupted.
+#[derive(Debug)]
+pub struct CpuTimeExceeded;
+
+impl std::fmt::Display for CpuTimeExceeded {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("the module ran out of CPU time")
+    }
+}
+
+impl std::error::Error for CpuTimeExceeded {}
+
+/// Measures the time a guest spends running during an invocation, as opposed
+/// to waiting on I/O. Unlike fuel, which counts instructions, this is what the
+/// invocation actually cost the host.
+#[derive(Clone)]
+pub struct CpuClock(Arc<Mutex<CpuTime>>);
+
+struct CpuTime {
+    /// When the invocation started.
+    started: Instant,
+    /// Time spent running, up to `running_since`.
+    busy: Duration,
+    /// When the guest started running, if it currently is.
+    running_since: Option<Instant>,
+}
+
+impl Default for CpuClock {
+    fn default() -> Self {
+        Self(Arc::new(Mutex::new(CpuTime {
+            started: Instant::now(),
+            busy: Duration::ZERO,
+            running_since: None,
+        })))
+    }
+}
+
+impl CpuClock {
+    /// Starts measuring a new invocation.
+    pub fn reset(&self) {
+        *self.0.lock().unwrap() = CpuTime {
+            started: Instant::now(),
+            busy: Duration::ZERO,
+            running_since: None,
+        };
+    }
+
+    /// Time spent running and time passed since the invocation started.
+    pub fn elapsed(&self) -> (Duration, Duration) {
+        let time = self.0.lock().unwrap();
+        let now = Instant::now();
+        let running = time
+            .running_since
+            .map_or(Duration::ZERO, |since| now - since);
+        (time.busy + running, now - time.started)
+    }
+
+    /// Runs `future`, counting the time spent polling it as running time.
+    pub async fn time<F: Future>(&self, future: F) -> F::Output {
+        let mut future = std::pin::pin!(future);
+        std::future::poll_fn(|cx| {
+            self.0.lock().unwrap().running_since = Some(Instant::now());
+            let polled = future.as_mut().poll(cx);
+            let mut time = self.0.lock().unwrap();
+            if let Some(since) = time.running_since.take() {
+                time.busy += since.elapsed();
+            }
+            polled
+        })
+        .await
+    }
+}
+
+/// Running time of all invocations of a module, across its deploys.
+#[derive(Default)]
+pub struct CpuUsage {
+    micros: AtomicU64,
+    invocations: AtomicU64,
+}
+
+impl CpuUsage {
+    pub fn record(&self, cpu: Duration) {
+        self.micros
+            .fetch_add(cpu.as_micros() as u64, Ordering::Relaxed);
+        self.invocations.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn total(&self) -> Duration {
+        Duration::from_micros(self.micros.load(Ordering::Relaxed))
+    }
+
+    pub fn invocations(&self) -> u64 {
+        self.invocations.load(Ordering::Relaxed)
+    }
+}
+
+/// Limits the memory of an instance to its budget, and tells the most it used.
+pub struct MemoryLimiter {
+    max_bytes: Option<usize>,
+    peak_bytes: usize,
+}
+
+impl MemoryLimiter {
+    /// The largest the instance's memory has been.
+    pub fn peak_bytes(&self) -> usize {
+        self.peak_bytes
+    }
+}
+
+impl ResourceLimiter for MemoryLimiter {
+    fn memory_growing(
+        &mut self,
+        _current: usize,
+        desired: usize,
+        _maximum: Option<usize>,
+    ) -> anyhow::Result<bool> {
+        if self.max_bytes.is_some_and(|max| desired > max) {
+            return Ok(false);
+        }
+        self.peak_bytes = self.peak_bytes.max(desired);
+        Ok(true)
+    }
+
+    fn table_growing(
+        &mut self,
+        _current: usize,
+        _desired: usize,
+        _maximum: Option<usize>,
+    ) -> anyhow::Result<bool> {
+        Ok(true)
+    }
+}
+
+/// Advances the epoch of `engine` every [`EPOCH_TICK`], so invocations running
+/// past their deadline are interrupted.
+pub fn spawn_epoch_ticker(engine: Engine) {
+    std::thread::spawn(move || loop {
+        std::thread::sleep(EPOCH_TICK);
+        engine.increment_epoch();
+    });
+}
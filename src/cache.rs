@@ -0,0 +1,351 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use axum::body::Bytes;
+use http_body_util::{BodyExt, Full};
+use hyper::{
+    body::{Body, Frame, SizeHint},
+    header, HeaderMap, Method, StatusCode, Uri,
+};
+use wasmtime_wasi_http::{bindings::http::types::ErrorCode, body::HyperOutgoingBody};
+
+/// Header telling clients whether a response of a caching module came from the cache.
+pub const X_RVM_CACHE: &str = "x-rvm-cache";
+
+/// Responses of modules that opted into caching, so repeated identical
+/// invocations are answered without entering the guest.
+///
+/// Entries are dropped once expired, or oldest first when the cache would
+/// grow past its size.
+pub struct ResponseCache {
+    max_bytes: usize,
+    max_entry_bytes: usize,
+    inner: Mutex<Inner>,
+}
+
+struct Inner {
+    entries: HashMap<CacheKey, Entry>,
+    /// Keys of the entries, in the order they were stored.
+    order: VecDeque<CacheKey>,
+    bytes: usize,
+    stats: HashMap<String, CacheStats>,
+}
+
+/// What identifies an invocation: the module, the version of it that answers,
+/// and the request it is sent.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct CacheKey {
+    key: String,
+    hash: blake3::Hash,
+    method: Method,
+    path: String,
+    body: blake3::Hash,
+}
+
+impl CacheKey {
+    pub fn new(key: &str, hash: blake3::Hash, method: &Method, uri: &Uri, body: &[u8]) -> Self {
+        Self {
+            key: key.to_owned(),
+            hash,
+            method: method.clone(),
+            path: uri
+                .path_and_query()
+                .map_or("/", |path| path.as_str())
+                .to_owned(),
+            body: blake3::hash(body),
+        }
+    }
+}
+
+struct Entry {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Bytes,
+    expires: Instant,
+}
+
+impl Entry {
+    fn size(&self) -> usize {
+        let headers: usize = self
+            .headers
+            .iter()
+            .map(|(name, value)| name.as_str().len() + value.len())
+            .sum();
+        headers + self.body.len()
+    }
+}
+
+/// How often invocations of a module were answered from the cache.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+}
+
+impl ResponseCache {
+    pub fn new(max_bytes: usize, max_entry_bytes: usize) -> Arc<Self> {
+        Arc::new(Self {
+            max_bytes,
+            max_entry_bytes: max_entry_bytes.min(max_bytes),
+            inner: Mutex::new(Inner {
+                entries: HashMap::new(),
+                order: VecDeque::new(),
+                bytes: 0,
+                stats: HashMap::new(),
+            }),
+        })
+    }
+
+    /// The cached response to `cache_key`, if it hasn't expired.
+    pub fn get(&self, cache_key: &CacheKey) -> Option<hyper::Response<HyperOutgoingBody>> {
+        let mut inner = self.inner.lock().unwrap();
+        let now = Instant::now();
+        let response = match inner.entries.get(cache_key) {
+            Some(entry) if entry.expires > now => {
+                let mut response = hyper::Response::new(
+                    Full::new(entry.body.clone())
+                        .map_err(|e| match e {})
+                        .boxed(),
+                );
+                *response.status_mut() = entry.status;
+                *response.headers_mut() = entry.headers.clone();
+                Some(response)
+            }
+            Some(_) => {
+                inner.remove(cache_key);
+                None
+            }
+            None => None,
+        };
+        let stats = inner.stats.entry(cache_key.key.clone()).or_default();
+        match response {
+            Some(mut response) => {
+                stats.hits += 1;
+                response
+                    .headers_mut()
+                    .insert(X_RVM_CACHE, header::HeaderValue::from_static("hit"));
+                Some(response)
+            }
+            None => {
+                stats.misses += 1;
+                None
+            }
+        }
+    }
+
+    /// Passes `response` on to the client, storing it for `ttl` once its body
+    /// is done if it can be reused.
+    pub fn record(
+        self: &Arc<Self>,
+        cache_key: CacheKey,
+        ttl: Duration,
+        response: hyper::Response<HyperOutgoingBody>,
+    ) -> hyper::Response<HyperOutgoingBody> {
+        let (mut parts, body) = response.into_parts();
+        let recorded = Self::cacheable(&parts.status, &parts.headers).then(|| {
+            let mut headers = parts.headers.clone();
+            // Describe the invocation that produced the response, not the ones it is reused for
+            let internal: Vec<_> = headers
+                .keys()
+                .filter(|name| name.as_str().starts_with("x-rvm-"))
+                .cloned()
+                .collect();
+            for name in internal {
+                headers.remove(name);
+            }
+            Recorded {
+                cache: self.clone(),
+                cache_key,
+                entry: Entry {
+                    status: parts.status,
+                    headers,
+                    body: Bytes::new(),
+                    expires: Instant::now() + ttl,
+                },
+                body: Vec::new(),
+            }
+        });
+        parts
+            .headers
+            .insert(X_RVM_CACHE, header::HeaderValue::from_static("miss"));
+        let mut recording = Recording { body, recorded };
+        // Hyper doesn't poll bodies that are done from the start
+        if recording.body.is_end_stream() {
+            recording.finish();
+        }
+        hyper::Response::from_parts(parts, recording.boxed())
+    }
+
+    /// Whether the response to a request with `headers` may be shared with other
+    /// clients. Credentials make it personal to the client sending them.
+    pub fn shareable(headers: &HeaderMap) -> bool {
+        !headers.contains_key(header::AUTHORIZATION) && !headers.contains_key(header::COOKIE)
+    }
+
+    /// Whether a response can be reused for other clients sending the same request.
+    /// Responses varying on request headers can't, as they aren't part of the key,
+    /// except for the `Origin` that CORS answers to after the cache.
+    fn cacheable(status: &StatusCode, headers: &HeaderMap) -> bool {
+        let no_store = headers
+            .get_all(header::CACHE_CONTROL)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .any(|directive| matches!(directive.trim(), "no-store" | "private"));
+        let varies = headers
+            .get_all(header::VARY)
+            .iter()
+            .flat_map(|value| value.to_str().map_or(vec!["*"], |v| v.split(',').collect()))
+            .map(str::trim)
+            .any(|name| !name.is_empty() && !name.eq_ignore_ascii_case("origin"));
+        status.is_success()
+            && !no_store
+            && !varies
+            && !headers.contains_key(header::SET_COOKIE)
+            && !headers.contains_key(header::TRAILER)
+    }
+
+    fn insert(&self, cache_key: CacheKey, entry: Entry) {
+        let size = entry.size();
+        if size > self.max_entry_bytes {
+            return;
+        }
+        let mut inner = self.inner.lock().unwrap();
+        inner.remove(&cache_key);
+        inner.bytes += size;
+        inner.order.push_back(cache_key.clone());
+        inner.entries.insert(cache_key, entry);
+        while inner.bytes > self.max_bytes {
+            let Some(oldest) = inner.order.front().cloned() else {
+                break;
+            };
+            inner.remove(&oldest);
+        }
+    }
+
+    pub fn stats(&self, key: &str) -> CacheStats {
+        let inner = self.inner.lock().unwrap();
+        inner.stats.get(key).copied().unwrap_or_default()
+    }
+
+    /// Bytes taken by the cached responses.
+    pub fn bytes(&self) -> usize {
+        self.inner.lock().unwrap().bytes
+    }
+}
+
+impl Inner {
+    fn remove(&mut self, cache_key: &CacheKey) {
+        if let Some(entry) = self.entries.remove(cache_key) {
+            self.bytes -= entry.size();
+            self.order.retain(|stored| stored != cache_key);
+        }
+    }
+}
+
+/// A response being stored once its body is done.
+struct Recorded {
+    cache: Arc<ResponseCache>,
+    cache_key: CacheKey,
+    entry: Entry,
+    body: Vec<u8>,
+}
+
+/// Body of a response being recorded, which passes its frames on unchanged.
+struct Recording {
+    body: HyperOutgoingBody,
+    /// Unset once the response turned out not to be cacheable, or was stored.
+    recorded: Option<Recorded>,
+}
+
+impl Recording {
+    fn finish(&mut self) {
+        if let Some(mut recorded) = self.recorded.take() {
+            recorded.entry.body = recorded.body.into();
+            recorded.cache.insert(recorded.cache_key, recorded.entry);
+        }
+    }
+}
+
+impl Body for Recording {
+    type Data = Bytes;
+    type Error = ErrorCode;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let this = &mut *self;
+        let polled = Pin::new(&mut this.body).poll_frame(cx);
+        match &polled {
+            Poll::Ready(Some(Ok(frame))) => match (frame.data_ref(), &mut this.recorded) {
+                (Some(data), Some(recorded)) => {
+                    recorded.body.extend_from_slice(data);
+                    if recorded.body.len() > recorded.cache.max_entry_bytes {
+                        this.recorded = None;
+                    } else if this.body.is_end_stream() {
+                        this.finish();
+                    }
+                }
+                // Trailers aren't replayed
+                _ => this.recorded = None,
+            },
+            Poll::Ready(Some(Err(_))) => this.recorded = None,
+            Poll::Ready(None) => this.finish(),
+            Poll::Pending => {}
+        }
+        polled
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.body.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.body.size_hint()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(pairs: &[(header::HeaderName, &str)]) -> HeaderMap {
+        pairs
+            .iter()
+            .map(|(name, value)| (name.clone(), value.parse().unwrap()))
+            .collect()
+    }
+
+    #[test]
+    fn requests_with_credentials_are_not_shared() {
+        assert!(ResponseCache::shareable(&headers(&[(
+            header::ACCEPT,
+            "*/*"
+        )])));
+        assert!(!ResponseCache::shareable(&headers(&[(
+            header::AUTHORIZATION,
+            "Bearer secret"
+        )])));
+        assert!(!ResponseCache::shareable(&headers(&[(
+            header::COOKIE,
+            "session=1"
+        )])));
+    }
+
+    #[test]
+    fn responses_varying_on_request_headers_are_not_cached() {
+        let cacheable = |vary: &str| {
+            ResponseCache::cacheable(&StatusCode::OK, &headers(&[(header::VARY, vary)]))
+        };
+        assert!(cacheable("Origin"));
+        assert!(!cacheable("Accept-Encoding"));
+        assert!(!cacheable("origin, Cookie"));
+        assert!(!cacheable("*"));
+    }
+}
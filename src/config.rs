@@ -0,0 +1,1045 @@
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use anyhow::Context;
+
+use crate::{fuel::FuelSink, rate_limit::RateLimiter};
+
+/// Settings for the rvm host.
+///
+/// Settings are read from the optional config file named by `RVM_CONFIG`, in
+/// TOML or JSON format depending on its extension, and can be overridden by
+/// `RVM_*` environment variables. Settings missing from both use the defaults.
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RvmConfig {
+    /// Address invocations are served on.
+    pub proxy_addr: SocketAddr,
+    /// Address the admin service is served on.
+    pub admin_addr: SocketAddr,
+    /// Address invocations are also served on over gRPC, with the `Invoker`
+    /// service of `proto/rvm.proto`. Not served if unset.
+    pub grpc_addr: Option<SocketAddr>,
+    /// Largest gRPC message the `Invoker` service accepts or sends.
+    pub grpc_max_message_bytes: usize,
+    /// Most client connections to the proxy open at once, more are closed right away.
+    pub max_connections: usize,
+    /// Whether proxy connections are kept open between requests.
+    pub keep_alive: bool,
+    /// Whether the proxy also speaks HTTP/2, negotiated with ALPN over TLS or
+    /// with prior knowledge over plain connections.
+    pub http2: bool,
+    /// Most headers accepted in a request to the proxy.
+    pub max_headers: usize,
+    /// How long a client may take to send the headers of a request to the proxy.
+    pub header_read_timeout_secs: u64,
+    /// Where modules are stored.
+    pub storage: StorageKind,
+    /// Directory modules are stored in, with [`StorageKind::Fs`].
+    pub storage_root: PathBuf,
+    /// Limits of the instance pool shared by all modules.
+    pub pool: PoolConfig,
+    /// Fuel each instance starts with.
+    pub default_fuel: u64,
+    /// How long an invocation may take before the client gets a `504`, unlimited if unset.
+    pub invocation_timeout_secs: Option<u64>,
+    /// Time the guest may spend running during an invocation, not counting the
+    /// time it waits on I/O, before it is interrupted. Unlimited if unset.
+    pub max_cpu_ms: Option<u64>,
+    /// Most invocations handled at once across all modules. More wait for their
+    /// turn, taken in turns by the modules they are for.
+    pub max_concurrent_invocations: usize,
+    /// How long an invocation may wait for its turn before getting a `503`, `0`
+    /// refuses it right away.
+    pub max_invocation_wait_ms: u64,
+    /// Invocations of a single module that may wait for its worker, more get a `503`.
+    pub worker_queue_capacity: usize,
+    /// Queued invocations of a module at which a warning is logged, as the
+    /// module is falling behind. Never warns if unset.
+    pub queue_depth_warning: Option<usize>,
+    /// What becomes of the stdout and stderr of modules without their own `stdio`.
+    pub stdio: StdioMode,
+    /// Bytes of stdout and stderr kept for `/modules/{key}/logs`, per module
+    /// capturing its output.
+    pub log_buffer_bytes: usize,
+    /// Whether guests see the host's environment variables. Only meant for
+    /// local development, as it leaks the host's environment to every module.
+    pub inherit_env: bool,
+    /// Settings of modules stored without their own, by key.
+    pub modules: HashMap<String, ModuleConfig>,
+    /// How many versions of each module to keep in storage for rollbacks.
+    pub version_retention: usize,
+    /// How many dead letters of each module to keep in storage, the oldest
+    /// are removed beyond that.
+    pub dead_letter_retention: usize,
+    /// How many invocations in a row may fail before a module is restarted
+    /// with a fresh instance, `0` disables restarts. A module that trapped is
+    /// restarted right away, as its instance can't be entered again.
+    pub max_consecutive_failures: usize,
+    /// How many invocations an instance serves before it is replaced with a
+    /// fresh one, bounding how much state it can build up. Never if unset.
+    pub recycle_after_requests: Option<u64>,
+    /// Most modules that can be deployed at once, deploying more gets a `507`.
+    /// Unlimited if unset.
+    pub max_modules: Option<usize>,
+    /// Most deploys and rollbacks in progress at once, from receiving the upload
+    /// until it is stored, more get a `429`. Unlimited if unset.
+    pub max_concurrent_deploys: Option<usize>,
+    /// Largest request body accepted when deploying a module.
+    pub max_deploy_bytes: usize,
+    /// Largest component that is compiled, after decompressing the upload.
+    pub max_component_bytes: usize,
+    /// Size of the writes a module is uploaded to storage in.
+    pub upload_chunk_bytes: usize,
+    /// Base64 encoded Ed25519 public key that deployed components must be
+    /// signed with, in an `x-rvm-signature` header. Deploys aren't verified if unset.
+    pub deploy_public_key: Option<String>,
+    /// Comma separated hosts modules can be deployed from with `/deploy-from-url`,
+    /// `*` for any. Deploying from URLs is refused if unset.
+    pub deploy_url_allowed_hosts: Option<String>,
+    /// How long downloading a module for `/deploy-from-url` may take.
+    pub deploy_url_timeout_secs: u64,
+    /// Largest response body a module may send, longer responses are cut short.
+    /// Unlimited if unset.
+    pub max_response_bytes: Option<u64>,
+    /// Largest request body a module may be invoked with, longer requests get
+    /// a `413`. Unlimited if unset, which leaves the bodies read up front, for
+    /// the response cache and dead letters, unbounded too.
+    pub max_request_bytes: Option<u64>,
+    /// Largest value a module can store with the `kv` interface.
+    pub kv_max_value_bytes: usize,
+    /// Bytes of responses kept for modules that cache them, see `cache_ttl_secs`.
+    pub response_cache_bytes: usize,
+    /// Largest response that is cached.
+    pub response_cache_max_entry_bytes: usize,
+    /// Distinct metric names each module can record through the `host` interface.
+    pub max_guest_metrics: usize,
+    /// Most modules an invocation may pass through by modules invoking each
+    /// other with `invoke-local`, deeper calls fail.
+    pub max_call_depth: usize,
+    /// How long an `invoke-local` call of a module without a timeout of its
+    /// own may wait for the response, unlimited if `0`. Modules invoking each
+    /// other at once would otherwise wait on each other forever.
+    pub local_invocation_timeout_secs: u64,
+    /// Where the fuel consumed by invocations is reported.
+    pub fuel_sink: FuelSink,
+    /// How often fuel totals are persisted when using [`FuelSink::Storage`].
+    pub fuel_flush_interval_secs: u64,
+    /// PEM encoded certificate chain to serve invocations over TLS with.
+    pub tls_cert: Option<PathBuf>,
+    /// PEM encoded private key matching `tls_cert`.
+    pub tls_key: Option<PathBuf>,
+    /// Module that handles every invocation that doesn't match a deployed module.
+    pub default_module: Option<String>,
+    /// Module invoked by deep health checks, instead of a built-in canary.
+    pub health_module: Option<String>,
+    /// Whether a module whose worker stopped is restarted from storage the
+    /// next time it is invoked.
+    pub restart_stopped_modules: bool,
+    /// Bearer token `POST /shutdown` must be sent with. Shutting down over the
+    /// admin API is disabled if unset.
+    pub shutdown_token: Option<String>,
+    /// How long shutting down waits for invocations in flight and background
+    /// uploads to finish before exiting anyway.
+    pub shutdown_timeout_secs: u64,
+    /// Whether responses carry the `x-rvm-fuel-remaining`, `x-rvm-fuel-consumed`,
+    /// `x-rvm-wall-time-ms`, `x-rvm-duration-ms` and `x-rvm-memory-bytes` headers.
+    /// Off by default, as they expose internals to clients.
+    pub expose_fuel_headers: bool,
+    /// Most fuel a single invocation can ask for with `x-rvm-fuel-override`.
+    pub max_fuel_override: u64,
+    /// Longest timeout a single invocation can ask for with `x-rvm-timeout-ms`.
+    pub max_timeout_override_ms: u64,
+    /// Share of its fuel, in percent, past which an invocation is logged as close
+    /// to running out, and its response gets an `x-rvm-fuel-warning` header.
+    pub fuel_warning_percent: Option<u64>,
+    /// Comma separated headers added to every request before it reaches the
+    /// module: `request-id` for an `x-rvm-request-id`, which is echoed on the
+    /// response, and `key` for the `x-rvm-key` of the module.
+    pub inject_headers: String,
+    /// Comma separated built-in transforms applied to every request before it
+    /// reaches the module, and to its response: `strip-hop-by-hop` and `max-headers`.
+    pub transforms: String,
+    /// Statuses invocations that trap are answered with, by the trap's category
+    /// as in `x-rvm-trap`, e.g. `out-of-fuel` or `stack-overflow`. Running out
+    /// of fuel gets a `503` and other traps a `502` unless configured.
+    pub trap_statuses: HashMap<String, u16>,
+    /// Fuel charged on top of what the guest burns for each call of a host
+    /// function, by `interface.function` as in the WIT, e.g. `kv.set` or
+    /// `host.invoke-local`. Host functions are free unless configured.
+    pub host_call_fuel: HashMap<String, u64>,
+    /// How long a module may go without invocations before its instance is
+    /// torn down, to be started again on its next invocation. Never if unset.
+    pub idle_timeout_secs: Option<u64>,
+    /// Comma separated origins browsers may invoke modules from, `*` for any.
+    /// CORS is left to the modules if unset.
+    pub cors_allow_origins: Option<String>,
+    /// Comma separated methods allowed in CORS requests, those requested if unset.
+    pub cors_allow_methods: Option<String>,
+    /// Comma separated headers allowed in CORS requests, those requested if unset.
+    pub cors_allow_headers: Option<String>,
+    /// Whether modules are compiled and instantiated on their first invocation,
+    /// rather than when they are deployed or loaded from storage.
+    pub lazy_start: bool,
+    /// Comma separated keys of modules loaded from storage before the others,
+    /// in this order, e.g. dependencies before the modules depending on them.
+    /// The others are loaded in order of their keys.
+    pub restore_order: Option<String>,
+    /// Whether compiled components are cached on disk between restarts.
+    pub cache_enabled: bool,
+    /// Where compiled components are cached, wasmtime's default location if unset.
+    pub cache_dir: Option<PathBuf>,
+    /// Whether components are compiled on multiple threads.
+    pub parallel_compilation: bool,
+    /// How hard Cranelift optimizes the code it compiles.
+    pub opt_level: OptLevel,
+    /// How module hashes are shown in deploy responses and the `x-rvm-module-hash` header.
+    pub hash_encoding: HashEncoding,
+    /// Whether traps carry a backtrace of the guest's wasm frames.
+    pub wasm_backtrace: bool,
+    /// Whether compiled code is registered with the native unwinder.
+    pub native_unwind_info: bool,
+    /// Whether modules may use the reference types proposal.
+    pub wasm_reference_types: bool,
+    /// Whether NaNs produced by floating point operations are canonicalized,
+    /// so they can't tell which host the guest is running on.
+    pub nan_canonicalization: bool,
+    /// Whether modules may use the bulk memory proposal.
+    pub wasm_bulk_memory: bool,
+    /// Whether modules may use the SIMD proposal.
+    pub wasm_simd: bool,
+    /// Address space reserved for each linear memory, in bytes. Memories that
+    /// fit in it need fewer bounds checks. wasmtime's default if unset.
+    pub memory_reservation: Option<u64>,
+    /// Unmapped address space after each linear memory, in bytes, which lets
+    /// more bounds checks be elided. wasmtime's default if unset.
+    pub memory_guard_size: Option<u64>,
+    /// Address space a memory that outgrew its reservation is given to grow
+    /// into, in bytes. wasmtime's default if unset.
+    pub memory_reservation_for_growth: Option<u64>,
+}
+
+impl Default for RvmConfig {
+    fn default() -> Self {
+        Self {
+            proxy_addr: SocketAddr::from(([127, 0, 0, 1], 8000)),
+            admin_addr: SocketAddr::from(([127, 0, 0, 1], 8002)),
+            grpc_addr: None,
+            grpc_max_message_bytes: 4 * 1024 * 1024,
+            max_connections: 1024,
+            keep_alive: true,
+            http2: false,
+            max_headers: 100,
+            header_read_timeout_secs: 30,
+            storage: StorageKind::Fs,
+            storage_root: PathBuf::from("./module-store"),
+            pool: PoolConfig::default(),
+            default_fuel: 100_000_000,
+            invocation_timeout_secs: None,
+            max_cpu_ms: None,
+            max_concurrent_invocations: 100,
+            max_invocation_wait_ms: 1000,
+            worker_queue_capacity: 1024,
+            queue_depth_warning: None,
+            stdio: StdioMode::Null,
+            log_buffer_bytes: 64 * 1024,
+            inherit_env: false,
+            modules: HashMap::new(),
+            version_retention: 5,
+            dead_letter_retention: 100,
+            max_consecutive_failures: 5,
+            recycle_after_requests: None,
+            max_modules: None,
+            max_concurrent_deploys: None,
+            max_deploy_bytes: 1024 * 256_000,    // ~256mb
+            max_component_bytes: 1024 * 128_000, // ~128mb
+            upload_chunk_bytes: 8 << 20,         // 8mb
+            deploy_public_key: None,
+            deploy_url_allowed_hosts: None,
+            deploy_url_timeout_secs: 60,
+            max_response_bytes: None,
+            max_request_bytes: Some(1 << 28), // ~268mb, the most memory a module has by default
+            kv_max_value_bytes: 64 << 10,     // 64kb
+            response_cache_bytes: 64 << 20,   // 64mb
+            response_cache_max_entry_bytes: 1 << 20, // 1mb
+            max_guest_metrics: 100,
+            max_call_depth: 8,
+            local_invocation_timeout_secs: 30,
+            fuel_sink: FuelSink::None,
+            fuel_flush_interval_secs: 10,
+            tls_cert: None,
+            tls_key: None,
+            default_module: None,
+            health_module: None,
+            restart_stopped_modules: true,
+            shutdown_token: None,
+            shutdown_timeout_secs: 30,
+            expose_fuel_headers: false,
+            max_fuel_override: 1_000_000_000,
+            max_timeout_override_ms: 300_000,
+            fuel_warning_percent: None,
+            inject_headers: String::from("request-id,key"),
+            transforms: String::new(),
+            trap_statuses: HashMap::new(),
+            host_call_fuel: HashMap::new(),
+            idle_timeout_secs: None,
+            cors_allow_origins: None,
+            cors_allow_methods: None,
+            cors_allow_headers: None,
+            lazy_start: false,
+            restore_order: None,
+            cache_enabled: true,
+            cache_dir: None,
+            parallel_compilation: true,
+            opt_level: OptLevel::Speed,
+            hash_encoding: HashEncoding::Hex,
+            wasm_backtrace: true,
+            native_unwind_info: true,
+            wasm_reference_types: true,
+            nan_canonicalization: false,
+            wasm_bulk_memory: true,
+            wasm_simd: true,
+            memory_reservation: None,
+            memory_guard_size: None,
+            memory_reservation_for_growth: None,
+        }
+    }
+}
+
+impl RvmConfig {
+    /// Reads the config file named by `RVM_CONFIG`, if any, and applies the
+    /// environment variable overrides on top of it.
+    pub fn load() -> anyhow::Result<Self> {
+        let config = match std::env::var_os("RVM_CONFIG") {
+            Some(path) => Self::from_file(Path::new(&path))?,
+            None => Self::default(),
+        };
+        Ok(config.with_env())
+    }
+
+    fn from_file(path: &Path) -> anyhow::Result<Self> {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read config file {}", path.display()))?;
+        let config = match path.extension().and_then(|ext| ext.to_str()) {
+            Some("json") => serde_json::from_str(&contents)?,
+            _ => toml::from_str(&contents)?,
+        };
+        Ok(config)
+    }
+
+    fn with_env(self) -> Self {
+        Self {
+            proxy_addr: env_or("RVM_PROXY_ADDR", self.proxy_addr),
+            admin_addr: env_or("RVM_ADMIN_ADDR", self.admin_addr),
+            grpc_addr: env_opt("RVM_GRPC_ADDR", self.grpc_addr),
+            grpc_max_message_bytes: env_or(
+                "RVM_GRPC_MAX_MESSAGE_BYTES",
+                self.grpc_max_message_bytes,
+            ),
+            max_connections: env_or("RVM_MAX_CONNECTIONS", self.max_connections),
+            keep_alive: env_or("RVM_KEEP_ALIVE", self.keep_alive),
+            http2: env_or("RVM_HTTP2", self.http2),
+            max_headers: env_or("RVM_MAX_HEADERS", self.max_headers),
+            header_read_timeout_secs: env_or(
+                "RVM_HEADER_READ_TIMEOUT_SECS",
+                self.header_read_timeout_secs,
+            ),
+            storage: env_or("RVM_STORAGE", self.storage),
+            storage_root: env_or("RVM_STORAGE_ROOT", self.storage_root),
+            pool: self.pool,
+            default_fuel: env_or("RVM_DEFAULT_FUEL", self.default_fuel),
+            // `0` disables the timeout
+            invocation_timeout_secs: Some(env_or(
+                "RVM_INVOCATION_TIMEOUT_SECS",
+                self.invocation_timeout_secs.unwrap_or(0),
+            ))
+            .filter(|&secs| secs > 0),
+            // `0` disables the limit
+            max_cpu_ms: Some(env_or("RVM_MAX_CPU_MS", self.max_cpu_ms.unwrap_or(0)))
+                .filter(|&ms| ms > 0),
+            max_concurrent_invocations: env_or(
+                "RVM_MAX_CONCURRENT_INVOCATIONS",
+                self.max_concurrent_invocations,
+            ),
+            max_invocation_wait_ms: env_or(
+                "RVM_MAX_INVOCATION_WAIT_MS",
+                self.max_invocation_wait_ms,
+            ),
+            worker_queue_capacity: env_or("RVM_WORKER_QUEUE_CAPACITY", self.worker_queue_capacity)
+                .max(1),
+            // `0` disables the warning
+            queue_depth_warning: Some(env_or(
+                "RVM_QUEUE_DEPTH_WARNING",
+                self.queue_depth_warning.unwrap_or(0),
+            ))
+            .filter(|&depth| depth > 0),
+            stdio: env_or("RVM_STDIO", self.stdio),
+            log_buffer_bytes: env_or("RVM_LOG_BUFFER_BYTES", self.log_buffer_bytes),
+            inherit_env: env_or("RVM_INHERIT_ENV", self.inherit_env),
+            modules: self.modules,
+            version_retention: env_or("RVM_VERSION_RETENTION", self.version_retention),
+            dead_letter_retention: env_or("RVM_DEAD_LETTER_RETENTION", self.dead_letter_retention),
+            max_consecutive_failures: env_or(
+                "RVM_MAX_CONSECUTIVE_FAILURES",
+                self.max_consecutive_failures,
+            ),
+            // `0` disables recycling
+            recycle_after_requests: Some(env_or(
+                "RVM_RECYCLE_AFTER_REQUESTS",
+                self.recycle_after_requests.unwrap_or(0),
+            ))
+            .filter(|&requests| requests > 0),
+            // `0` disables the limit
+            max_modules: Some(env_or("RVM_MAX_MODULES", self.max_modules.unwrap_or(0)))
+                .filter(|&modules| modules > 0),
+            max_concurrent_deploys: Some(env_or(
+                "RVM_MAX_CONCURRENT_DEPLOYS",
+                self.max_concurrent_deploys.unwrap_or(0),
+            ))
+            .filter(|&deploys| deploys > 0),
+            max_deploy_bytes: env_or("RVM_MAX_DEPLOY_BYTES", self.max_deploy_bytes),
+            max_component_bytes: env_or("RVM_MAX_COMPONENT_BYTES", self.max_component_bytes),
+            upload_chunk_bytes: env_or("RVM_UPLOAD_CHUNK_BYTES", self.upload_chunk_bytes),
+            deploy_public_key: std::env::var("RVM_DEPLOY_PUBLIC_KEY")
+                .ok()
+                .or(self.deploy_public_key),
+            deploy_url_allowed_hosts: std::env::var("RVM_DEPLOY_URL_ALLOWED_HOSTS")
+                .ok()
+                .or(self.deploy_url_allowed_hosts),
+            deploy_url_timeout_secs: env_or(
+                "RVM_DEPLOY_URL_TIMEOUT_SECS",
+                self.deploy_url_timeout_secs,
+            ),
+            // `0` disables the limit
+            max_response_bytes: Some(env_or(
+                "RVM_MAX_RESPONSE_BYTES",
+                self.max_response_bytes.unwrap_or(0),
+            ))
+            .filter(|&bytes| bytes > 0),
+            // `0` disables the limit
+            max_request_bytes: Some(env_or(
+                "RVM_MAX_REQUEST_BYTES",
+                self.max_request_bytes.unwrap_or(0),
+            ))
+            .filter(|&bytes| bytes > 0),
+            kv_max_value_bytes: env_or("RVM_KV_MAX_VALUE_BYTES", self.kv_max_value_bytes),
+            response_cache_bytes: env_or("RVM_RESPONSE_CACHE_BYTES", self.response_cache_bytes),
+            response_cache_max_entry_bytes: env_or(
+                "RVM_RESPONSE_CACHE_MAX_ENTRY_BYTES",
+                self.response_cache_max_entry_bytes,
+            ),
+            max_guest_metrics: env_or("RVM_MAX_GUEST_METRICS", self.max_guest_metrics),
+            max_call_depth: env_or("RVM_MAX_CALL_DEPTH", self.max_call_depth),
+            local_invocation_timeout_secs: env_or(
+                "RVM_LOCAL_INVOCATION_TIMEOUT_SECS",
+                self.local_invocation_timeout_secs,
+            ),
+            fuel_sink: env_or("RVM_FUEL_SINK", self.fuel_sink),
+            fuel_flush_interval_secs: env_or(
+                "RVM_FUEL_FLUSH_INTERVAL_SECS",
+                self.fuel_flush_interval_secs,
+            ),
+            tls_cert: std::env::var_os("RVM_TLS_CERT")
+                .map(PathBuf::from)
+                .or(self.tls_cert),
+            tls_key: std::env::var_os("RVM_TLS_KEY")
+                .map(PathBuf::from)
+                .or(self.tls_key),
+            default_module: std::env::var("RVM_DEFAULT_MODULE")
+                .ok()
+                .or(self.default_module),
+            health_module: std::env::var("RVM_HEALTH_MODULE")
+                .ok()
+                .or(self.health_module),
+            restart_stopped_modules: env_or(
+                "RVM_RESTART_STOPPED_MODULES",
+                self.restart_stopped_modules,
+            ),
+            shutdown_token: std::env::var("RVM_SHUTDOWN_TOKEN")
+                .ok()
+                .or(self.shutdown_token),
+            shutdown_timeout_secs: env_or("RVM_SHUTDOWN_TIMEOUT_SECS", self.shutdown_timeout_secs),
+            expose_fuel_headers: env_or("RVM_EXPOSE_FUEL_HEADERS", self.expose_fuel_headers),
+            max_fuel_override: env_or("RVM_MAX_FUEL_OVERRIDE", self.max_fuel_override),
+            max_timeout_override_ms: env_or(
+                "RVM_MAX_TIMEOUT_OVERRIDE_MS",
+                self.max_timeout_override_ms,
+            ),
+            // `0` disables the warning
+            fuel_warning_percent: Some(env_or(
+                "RVM_FUEL_WARNING_PERCENT",
+                self.fuel_warning_percent.unwrap_or(0),
+            ))
+            .filter(|&percent| percent > 0),
+            inject_headers: std::env::var("RVM_INJECT_HEADERS").unwrap_or(self.inject_headers),
+            transforms: std::env::var("RVM_TRANSFORMS").unwrap_or(self.transforms),
+            trap_statuses: match std::env::var("RVM_TRAP_STATUSES") {
+                Ok(statuses) => parse_pairs("RVM_TRAP_STATUSES", &statuses, self.trap_statuses),
+                Err(_) => self.trap_statuses,
+            },
+            host_call_fuel: match std::env::var("RVM_HOST_CALL_FUEL") {
+                Ok(costs) => parse_pairs("RVM_HOST_CALL_FUEL", &costs, self.host_call_fuel),
+                Err(_) => self.host_call_fuel,
+            },
+            // `0` disables eviction
+            idle_timeout_secs: Some(env_or(
+                "RVM_IDLE_TIMEOUT_SECS",
+                self.idle_timeout_secs.unwrap_or(0),
+            ))
+            .filter(|&secs| secs > 0),
+            cors_allow_origins: std::env::var("RVM_CORS_ALLOW_ORIGINS")
+                .ok()
+                .or(self.cors_allow_origins),
+            cors_allow_methods: std::env::var("RVM_CORS_ALLOW_METHODS")
+                .ok()
+                .or(self.cors_allow_methods),
+            cors_allow_headers: std::env::var("RVM_CORS_ALLOW_HEADERS")
+                .ok()
+                .or(self.cors_allow_headers),
+            lazy_start: env_or("RVM_LAZY_START", self.lazy_start),
+            restore_order: std::env::var("RVM_RESTORE_ORDER")
+                .ok()
+                .or(self.restore_order),
+            cache_enabled: std::env::var("RVM_CACHE").map_or(self.cache_enabled, |v| v != "off"),
+            cache_dir: std::env::var_os("RVM_CACHE_DIR")
+                .map(PathBuf::from)
+                .or(self.cache_dir),
+            parallel_compilation: env_or("RVM_PARALLEL_COMPILE", self.parallel_compilation),
+            opt_level: env_or("RVM_OPT_LEVEL", self.opt_level),
+            hash_encoding: env_or("RVM_HASH_ENCODING", self.hash_encoding),
+            wasm_backtrace: env_or("RVM_WASM_BACKTRACE", self.wasm_backtrace),
+            native_unwind_info: env_or("RVM_NATIVE_UNWIND_INFO", self.native_unwind_info),
+            wasm_reference_types: env_or("RVM_WASM_REFERENCE_TYPES", self.wasm_reference_types),
+            nan_canonicalization: env_or("RVM_NAN_CANONICALIZATION", self.nan_canonicalization),
+            wasm_bulk_memory: env_or("RVM_WASM_BULK_MEMORY", self.wasm_bulk_memory),
+            wasm_simd: env_or("RVM_WASM_SIMD", self.wasm_simd),
+            memory_reservation: env_opt("RVM_MEMORY_RESERVATION", self.memory_reservation),
+            memory_guard_size: env_opt("RVM_MEMORY_GUARD_SIZE", self.memory_guard_size),
+            memory_reservation_for_growth: env_opt(
+                "RVM_MEMORY_RESERVATION_FOR_GROWTH",
+                self.memory_reservation_for_growth,
+            ),
+        }
+    }
+
+    /// Refuses combinations of wasm features the engine can't be built with.
+    pub fn check_features(&self) -> anyhow::Result<()> {
+        if self.wasm_reference_types && !self.wasm_bulk_memory {
+            anyhow::bail!(
+                "RVM_WASM_REFERENCE_TYPES requires RVM_WASM_BULK_MEMORY, disable both or neither"
+            );
+        }
+        if !self.native_unwind_info && cfg!(windows) {
+            anyhow::bail!("RVM_NATIVE_UNWIND_INFO can't be disabled on Windows");
+        }
+        Ok(())
+    }
+
+    /// Refuses memory settings the pool or the platform's address space can't
+    /// accommodate.
+    pub fn check_memory(&self) -> anyhow::Result<()> {
+        let max_memory_size = self.pool.max_memory_size as u64;
+        if let Some(reservation) = self.memory_reservation {
+            anyhow::ensure!(
+                reservation >= max_memory_size,
+                "RVM_MEMORY_RESERVATION must be at least the pool's max_memory_size of \
+                 {max_memory_size} bytes, as pooled memories never move"
+            );
+        }
+        // wasmtime's defaults, and the address space of a process on common platforms
+        let (reservation, guard, address_space) = match cfg!(target_pointer_width = "64") {
+            true => (1 << 32, 32 << 20, 1 << 47),
+            false => (10 << 20, 1 << 16, u64::from(u32::MAX)),
+        };
+        // Every memory of the pool is reserved up front, along with its guard
+        let slot = self
+            .memory_reservation
+            .unwrap_or(reservation)
+            .checked_add(self.memory_guard_size.unwrap_or(guard));
+        let pool = slot.and_then(|slot| slot.checked_mul(self.pool.total_memories.into()));
+        if pool.is_none_or(|pool| pool > address_space) {
+            anyhow::bail!(
+                "RVM_MEMORY_RESERVATION and RVM_MEMORY_GUARD_SIZE for {} memories exceed \
+                 the address space of this platform",
+                self.pool.total_memories
+            );
+        }
+        Ok(())
+    }
+
+    /// Whether `header` is one of the `inject_headers`.
+    pub fn injects(&self, header: &str) -> bool {
+        self.inject_headers
+            .split(',')
+            .any(|injected| injected.trim() == header)
+    }
+
+    /// The keys of the modules loaded from storage first, in order.
+    pub fn restore_order(&self) -> impl Iterator<Item = &str> {
+        self.restore_order
+            .iter()
+            .flat_map(|keys| keys.split(','))
+            .map(str::trim)
+            .filter(|key| !key.is_empty())
+    }
+
+    /// Settings to restart the stored module `key` with, when it wasn't stored
+    /// with its own.
+    pub fn module_config(&self, key: &str) -> ModuleConfig {
+        self.modules.get(key).cloned().unwrap_or_default()
+    }
+}
+
+/// Limits of the pooling instance allocator.
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PoolConfig {
+    pub total_memories: u32,
+    /// Largest linear memory of an instance, in bytes.
+    pub max_memory_size: usize,
+    pub total_tables: u32,
+    pub table_elements: usize,
+    pub total_core_instances: u32,
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        Self {
+            total_memories: 100,
+            max_memory_size: 1 << 28, // ~268KiB
+            total_tables: 100,
+            table_elements: 10_000,
+            total_core_instances: 100,
+        }
+    }
+}
+
+/// Like [`env_or`], for settings that are unset unless configured.
+fn env_opt<T: FromStr>(name: &str, default: Option<T>) -> Option<T> {
+    match std::env::var(name) {
+        Ok(value) => match value.parse() {
+            Ok(value) => Some(value),
+            Err(_) => {
+                tracing::warn!("Ignoring invalid value `{value}` for {name}");
+                default
+            }
+        },
+        Err(_) => default,
+    }
+}
+
+/// Reads and parses `name` from the environment, falling back to `default`
+/// if it is unset or can't be parsed.
+fn env_or<T: FromStr>(name: &str, default: T) -> T {
+    match std::env::var(name) {
+        Ok(value) => value.parse().unwrap_or_else(|_| {
+            tracing::warn!("Ignoring invalid value `{value}` for {name}");
+            default
+        }),
+        Err(_) => default,
+    }
+}
+
+/// Adds the comma separated `name=value` pairs of the variable `var`, e.g.
+/// `RVM_TRAP_STATUSES`, to the ones from the config file.
+fn parse_pairs<V: FromStr>(
+    var: &str,
+    pairs: &str,
+    mut parsed: HashMap<String, V>,
+) -> HashMap<String, V> {
+    for entry in pairs.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+        match entry
+            .split_once('=')
+            .and_then(|(name, value)| Some((name.trim(), value.trim().parse().ok()?)))
+        {
+            Some((name, value)) => {
+                parsed.insert(name.to_owned(), value);
+            }
+            None => tracing::warn!("Ignoring invalid entry `{entry}` in {var}"),
+        }
+    }
+    parsed
+}
+
+/// Settings for a single module, given as query parameters when deploying it.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct ModuleConfig {
+    /// Whether the module can read the host's wall clock. When disabled the
+    /// wall clock is frozen at the unix epoch.
+    pub allow_wall_clock: bool,
+    /// Whether the module gets real entropy. When disabled `wasi:random` is
+    /// backed by a generator with a fixed seed, so runs are reproducible.
+    pub allow_random: bool,
+    /// Whether the module can use `wasi:sockets`. Denied by default, leaving
+    /// outgoing HTTP as the module's only way onto the network.
+    pub allow_sockets: bool,
+    /// Comma separated addresses, as `ip` or `ip:port`, that the module may
+    /// bind or connect sockets to when `allow_sockets` is set. Any if unset.
+    pub socket_allowlist: Option<String>,
+    /// Whether each new instance is sent a `GET /_rvm_warmup` before it takes
+    /// requests, failing to start if it doesn't respond. Only for HTTP modules.
+    pub warmup: bool,
+    /// Whether the module keeps its instance when idle, see `idle_timeout_secs`.
+    pub pinned: bool,
+    /// Which interface the module exports.
+    pub kind: ModuleKind,
+    /// Origins, methods and headers allowed in CORS requests to the module,
+    /// overriding the host's `cors_allow_*` settings.
+    pub cors_allow_origins: Option<String>,
+    pub cors_allow_methods: Option<String>,
+    pub cors_allow_headers: Option<String>,
+    /// Largest response body the module may send, overriding `max_response_bytes`.
+    pub max_response_bytes: Option<u64>,
+    /// Largest request body the module may be invoked with, overriding `max_request_bytes`.
+    pub max_request_bytes: Option<u64>,
+    /// Fuel the module has for each invocation, overriding `default_fuel`.
+    pub fuel: Option<u64>,
+    /// How long an invocation of the module may run, overriding `invocation_timeout_secs`.
+    pub timeout_secs: Option<u64>,
+    /// Time an invocation of the module may spend running, overriding `max_cpu_ms`.
+    pub max_cpu_ms: Option<u64>,
+    /// Invocations an instance of the module serves before it is replaced,
+    /// overriding `recycle_after_requests`.
+    pub recycle_after_requests: Option<u64>,
+    /// Largest the module's memory may grow to, below the pool's `max_memory_size`.
+    pub max_memory_bytes: Option<usize>,
+    /// Comma separated keys of deployed modules whose exports provide the
+    /// imports of this module that the host doesn't.
+    pub dependencies: Option<String>,
+    /// What becomes of the module's stdout and stderr, overriding `stdio`.
+    pub stdio: Option<StdioMode>,
+    /// Requests per second the module may be invoked with on average, unlimited if unset.
+    pub rate_limit_rps: Option<f64>,
+    /// Most requests the module may be invoked with at once under `rate_limit_rps`,
+    /// one second worth of requests if unset.
+    pub rate_limit_burst: Option<u32>,
+    /// How long the module's responses to `GET` and `HEAD` requests are reused
+    /// for identical requests, which are then answered without invoking it.
+    /// Not cached if unset.
+    pub cache_ttl_secs: Option<u64>,
+    /// Whether invocations of the module that trap or time out are kept in
+    /// storage, to be inspected and replayed.
+    pub dead_letter: bool,
+}
+
+impl ModuleConfig {
+    /// The rate limiter to invoke the module through, if it is limited.
+    pub fn rate_limiter(&self) -> Option<RateLimiter> {
+        let rate = self.rate_limit_rps.filter(|rate| *rate > 0.0)?;
+        let burst = self.rate_limit_burst.unwrap_or(rate.ceil() as u32);
+        Some(RateLimiter::new(rate, burst))
+    }
+
+    /// The addresses in `socket_allowlist`, unset if any address is allowed.
+    pub fn socket_allowlist(&self) -> anyhow::Result<Option<Vec<AllowedAddr>>> {
+        let Some(allowlist) = &self.socket_allowlist else {
+            return Ok(None);
+        };
+        allowlist
+            .split(',')
+            .map(str::trim)
+            .filter(|addr| !addr.is_empty())
+            .map(|addr| {
+                addr.parse()
+                    .map(AllowedAddr::Socket)
+                    .or_else(|_| addr.parse().map(AllowedAddr::Ip))
+                    .with_context(|| format!("invalid address `{addr}` in `socket_allowlist`"))
+            })
+            .collect::<anyhow::Result<_>>()
+            .map(Some)
+    }
+
+    /// The keys of the modules this module depends on.
+    pub fn dependencies(&self) -> impl Iterator<Item = &str> {
+        self.dependencies
+            .iter()
+            .flat_map(|keys| keys.split(','))
+            .map(str::trim)
+            .filter(|key| !key.is_empty())
+    }
+}
+
+/// An address a module may use sockets with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AllowedAddr {
+    /// Any port of the address.
+    Ip(IpAddr),
+    Socket(SocketAddr),
+}
+
+impl AllowedAddr {
+    pub fn allows(&self, addr: SocketAddr) -> bool {
+        match self {
+            AllowedAddr::Ip(ip) => *ip == addr.ip(),
+            AllowedAddr::Socket(allowed) => *allowed == addr,
+        }
+    }
+}
+
+/// Where the stdout and stderr of a module go.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StdioMode {
+    /// Forwarded to the host's stdio, mixed in with the host's logs.
+    Inherit,
+    /// Discarded.
+    Null,
+    /// Kept in a buffer of `log_buffer_bytes`, served on `/modules/{key}/logs`.
+    Capture,
+}
+
+impl FromStr for StdioMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "inherit" => Ok(StdioMode::Inherit),
+            "null" => Ok(StdioMode::Null),
+            "capture" => Ok(StdioMode::Capture),
+            _ => Err(format!("unknown stdio `{s}`")),
+        }
+    }
+}
+
+/// The backing store modules are kept in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum StorageKind {
+    /// Files in `storage_root`.
+    Fs,
+    /// Kept in memory, so modules only last until rvm stops. Meant for tests
+    /// and ephemeral deployments.
+    Memory,
+}
+
+impl FromStr for StorageKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "fs" => Ok(StorageKind::Fs),
+            "memory" => Ok(StorageKind::Memory),
+            _ => Err(format!("unknown storage `{s}`")),
+        }
+    }
+}
+
+/// Optimization level of the code Cranelift generates, trading compile time for speed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OptLevel {
+    None,
+    Speed,
+    SpeedAndSize,
+}
+
+impl FromStr for OptLevel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(OptLevel::None),
+            "speed" => Ok(OptLevel::Speed),
+            "speed_and_size" => Ok(OptLevel::SpeedAndSize),
+            _ => Err(format!("unknown optimization level `{s}`")),
+        }
+    }
+}
+
+impl From<OptLevel> for wasmtime::OptLevel {
+    fn from(level: OptLevel) -> Self {
+        match level {
+            OptLevel::None => wasmtime::OptLevel::None,
+            OptLevel::Speed => wasmtime::OptLevel::Speed,
+            OptLevel::SpeedAndSize => wasmtime::OptLevel::SpeedAndSize,
+        }
+    }
+}
+
+/// How module hashes are shown to clients, in deploy and rollback responses and
+/// the `x-rvm-module-hash` header. Versions are listed in full hex, and rolled
+/// back to by their hash in full hex or in this encoding, unless truncated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize)]
+#[serde(try_from = "String")]
+pub enum HashEncoding {
+    /// The full hash in lowercase hex.
+    Hex,
+    /// The full hash in lowercase base32 without padding, as in RFC 4648.
+    Base32,
+    /// The first given number of hex digits, for display. Short prefixes can
+    /// be shared by different versions.
+    Truncated(usize),
+}
+
+impl HashEncoding {
+    pub fn encode(&self, hash: &blake3::Hash) -> String {
+        match self {
+            HashEncoding::Hex => hash.to_string(),
+            HashEncoding::Base32 => base32(hash.as_bytes()),
+            HashEncoding::Truncated(digits) => hash.to_hex()[..*digits].to_owned(),
+        }
+    }
+
+    /// The hash given by a client, in full hex or as encoded by [`Self::encode`].
+    /// Truncated hashes are refused, as different versions can share them.
+    pub fn decode(&self, encoded: &str) -> Result<blake3::Hash, String> {
+        if let Ok(hash) = blake3::Hash::from_hex(encoded) {
+            return Ok(hash);
+        }
+        match self {
+            HashEncoding::Base32 => unbase32(encoded)
+                .and_then(|bytes| <[u8; 32]>::try_from(bytes).ok())
+                .map(blake3::Hash::from_bytes)
+                .ok_or_else(|| format!("invalid hash `{encoded}`")),
+            HashEncoding::Truncated(_)
+                if encoded.len() < 64 && encoded.bytes().all(|b| b.is_ascii_hexdigit()) =>
+            {
+                Err(format!(
+                    "`{encoded}` is truncated and may match several versions, give the full hash in hex"
+                ))
+            }
+            _ => Err(format!("invalid hash `{encoded}`")),
+        }
+    }
+}
+
+impl FromStr for HashEncoding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "hex" => Ok(HashEncoding::Hex),
+            "base32" => Ok(HashEncoding::Base32),
+            _ => match s.strip_prefix("truncated-").map(str::parse) {
+                Some(Ok(digits @ 1..=64)) => Ok(HashEncoding::Truncated(digits)),
+                _ => Err(format!("unknown hash encoding `{s}`")),
+            },
+        }
+    }
+}
+
+impl TryFrom<String> for HashEncoding {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+/// Encodes `bytes` in lowercase base32 without padding.
+fn base32(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 32] = b"abcdefghijklmnopqrstuvwxyz234567";
+    let mut encoded = String::with_capacity(bytes.len().div_ceil(5) * 8);
+    let (mut buffer, mut bits) = (0u16, 0);
+    for &byte in bytes {
+        buffer = (buffer << 8) | u16::from(byte);
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            encoded.push(ALPHABET[usize::from((buffer >> bits) & 31)] as char);
+        }
+    }
+    if bits > 0 {
+        encoded.push(ALPHABET[usize::from((buffer << (5 - bits)) & 31)] as char);
+    }
+    encoded
+}
+
+/// Decodes what [`base32`] encoded, refusing anything it wouldn't encode to.
+fn unbase32(encoded: &str) -> Option<Vec<u8>> {
+    let mut bytes = Vec::with_capacity(encoded.len() * 5 / 8);
+    let (mut buffer, mut bits) = (0u16, 0);
+    for c in encoded.bytes() {
+        let value = match c {
+            b'a'..=b'z' => c - b'a',
+            b'2'..=b'7' => c - b'2' + 26,
+            _ => return None,
+        };
+        buffer = (buffer << 5) | u16::from(value);
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            bytes.push((buffer >> bits) as u8);
+        }
+    }
+    (base32(&bytes) == encoded).then_some(bytes)
+}
+
+/// The interface a module is invoked through.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ModuleKind {
+    /// Handles HTTP requests, exporting `wasi:http/incoming-handler`.
+    #[default]
+    Http,
+    /// Handles JSON payloads, exporting `rvm:lambda/run`.
+    Run,
+}
+
+impl ModuleKind {
+    /// The interface modules of this kind are invoked through, without its version.
+    pub fn export(&self) -> &'static str {
+        match self {
+            ModuleKind::Http => "wasi:http/incoming-handler",
+            ModuleKind::Run => "rvm:lambda/run",
+        }
+    }
+}
+
+impl Default for ModuleConfig {
+    fn default() -> Self {
+        Self {
+            allow_wall_clock: true,
+            allow_random: true,
+            allow_sockets: false,
+            socket_allowlist: None,
+            warmup: false,
+            pinned: false,
+            kind: ModuleKind::Http,
+            cors_allow_origins: None,
+            cors_allow_methods: None,
+            cors_allow_headers: None,
+            max_response_bytes: None,
+            max_request_bytes: None,
+            fuel: None,
+            timeout_secs: None,
+            max_cpu_ms: None,
+            recycle_after_requests: None,
+            max_memory_bytes: None,
+            dependencies: None,
+            stdio: None,
+            rate_limit_rps: None,
+            rate_limit_burst: None,
+            cache_ttl_secs: None,
+            dead_letter: false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hashes_decode_as_encoded() {
+        let hash = blake3::hash(b"module");
+        for encoding in [HashEncoding::Hex, HashEncoding::Base32] {
+            assert_eq!(encoding.decode(&encoding.encode(&hash)), Ok(hash));
+        }
+        // Versions are listed in hex whatever the encoding
+        assert_eq!(HashEncoding::Base32.decode(&hash.to_hex()), Ok(hash));
+    }
+
+    #[test]
+    fn truncated_hashes_are_refused() {
+        let hash = blake3::hash(b"module");
+        let truncated = HashEncoding::Truncated(12);
+        assert!(truncated.decode(&truncated.encode(&hash)).is_err());
+        assert_eq!(truncated.decode(&hash.to_hex()), Ok(hash));
+    }
+}
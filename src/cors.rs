@@ -0,0 +1,110 @@
+use hyper::header::{self, HeaderMap, HeaderValue};
+use wasmtime_wasi_http::body::HyperOutgoingBody;
+
+use crate::config::{ModuleConfig, RvmConfig};
+
+/// Which browser origins may invoke a module, answering CORS preflights on
+/// the module's behalf.
+pub struct CorsPolicy {
+    origins: Vec<String>,
+    methods: Option<HeaderValue>,
+    headers: Option<HeaderValue>,
+}
+
+impl CorsPolicy {
+    /// The policy of a module, its own settings taking precedence over the
+    /// host's. `None` if no origins are allowed, leaving CORS to the module.
+    pub fn new(module_config: &ModuleConfig, config: &RvmConfig) -> Option<Self> {
+        let origins = module_config
+            .cors_allow_origins
+            .as_ref()
+            .or(config.cors_allow_origins.as_ref())?;
+        let header_value =
+            |list: Option<&String>| list.and_then(|list| HeaderValue::from_str(list).ok());
+        Some(Self {
+            origins: origins
+                .split(',')
+                .map(|origin| origin.trim().to_owned())
+                .filter(|origin| !origin.is_empty())
+                .collect(),
+            methods: header_value(
+                module_config
+                    .cors_allow_methods
+                    .as_ref()
+                    .or(config.cors_allow_methods.as_ref()),
+            ),
+            headers: header_value(
+                module_config
+                    .cors_allow_headers
+                    .as_ref()
+                    .or(config.cors_allow_headers.as_ref()),
+            ),
+        })
+    }
+
+    /// The `Access-Control-Allow-Origin` to answer a request with, if its
+    /// origin is allowed.
+    pub fn allowed_origin(&self, request: &HeaderMap) -> Option<HeaderValue> {
+        let origin = request.get(header::ORIGIN)?;
+        if self.origins.iter().any(|allowed| allowed == "*") {
+            Some(HeaderValue::from_static("*"))
+        } else {
+            let origin_str = origin.to_str().ok()?;
+            self.origins
+                .iter()
+                .any(|allowed| allowed == origin_str)
+                .then(|| origin.clone())
+        }
+    }
+
+    /// Answers `request` if it is a preflight. Methods and headers that aren't
+    /// configured are allowed as requested.
+    pub fn preflight(
+        &self,
+        method: &hyper::Method,
+        request: &HeaderMap,
+    ) -> Option<hyper::Response<HyperOutgoingBody>> {
+        if method != hyper::Method::OPTIONS
+            || !request.contains_key(header::ACCESS_CONTROL_REQUEST_METHOD)
+        {
+            return None;
+        }
+        let mut response = hyper::Response::builder()
+            .status(hyper::StatusCode::NO_CONTENT)
+            .body(Default::default())
+            .expect("valid response");
+        // Without the allow headers the browser refuses the actual request
+        let Some(origin) = self.allowed_origin(request) else {
+            return Some(response);
+        };
+        let requested = |name| request.get(name).cloned();
+        let headers = response.headers_mut();
+        self.apply(origin, headers);
+        if let Some(methods) = self
+            .methods
+            .clone()
+            .or_else(|| requested(header::ACCESS_CONTROL_REQUEST_METHOD))
+        {
+            headers.insert(header::ACCESS_CONTROL_ALLOW_METHODS, methods);
+        }
+        if let Some(allowed) = self
+            .headers
+            .clone()
+            .or_else(|| requested(header::ACCESS_CONTROL_REQUEST_HEADERS))
+        {
+            headers.insert(header::ACCESS_CONTROL_ALLOW_HEADERS, allowed);
+        }
+        Some(response)
+    }
+
+    /// Adds the allowed `origin` to a response, unless the module set its own.
+    pub fn apply(&self, origin: HeaderValue, response: &mut HeaderMap) {
+        if response.contains_key(header::ACCESS_CONTROL_ALLOW_ORIGIN) {
+            return;
+        }
+        if origin != "*" {
+            response.append(header::VARY, HeaderValue::from_static("origin"));
+        }
+        response.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, origin);
+    }
+}
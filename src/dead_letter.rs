@@ -0,0 +1,194 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use anyhow::{Context, Result};
+use axum::body::Bytes;
+use base64::{prelude::BASE64_STANDARD, Engine};
+use http_body_util::{BodyExt, Full};
+
+use crate::host::InvokeBody;
+
+/// Where the dead letters of `key` are stored, one JSON file per failed invocation.
+fn dir(key: &str) -> String {
+    format!("deadletter/{key}/")
+}
+
+fn path(key: &str, id: &str) -> String {
+    format!("deadletter/{key}/{id}.json")
+}
+
+/// An invocation of a module deployed with `dead_letter` that trapped or
+/// timed out, kept so it can be inspected and replayed.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct DeadLetter {
+    /// When the invocation failed, which also names its file.
+    pub id: String,
+    pub reason: String,
+    #[serde(flatten)]
+    pub request: CapturedRequest,
+}
+
+/// Headers carrying credentials, which aren't kept in storage.
+const CREDENTIALS: [hyper::header::HeaderName; 3] = [
+    hyper::header::AUTHORIZATION,
+    hyper::header::PROXY_AUTHORIZATION,
+    hyper::header::COOKIE,
+];
+
+/// A request as it was handed to `invoke_module`.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct CapturedRequest {
+    pub method: String,
+    /// Path and query of the request.
+    pub uri: String,
+    /// Headers of the request, those carrying credentials and values that
+    /// aren't valid UTF-8 are left out.
+    pub headers: Vec<(String, String)>,
+    /// The body, base64 encoded.
+    pub body: String,
+}
+
+impl CapturedRequest {
+    pub fn new(parts: &hyper::http::request::Parts, body: &[u8]) -> Self {
+        Self {
+            method: parts.method.to_string(),
+            uri: parts.uri.to_string(),
+            headers: parts
+                .headers
+                .iter()
+                .filter(|(name, _)| !CREDENTIALS.contains(name))
+                .filter_map(|(name, value)| Some((name.to_string(), value.to_str().ok()?.into())))
+                .collect(),
+            body: BASE64_STANDARD.encode(body),
+        }
+    }
+
+    /// The request to invoke the module with again.
+    pub fn into_request(self) -> Result<hyper::Request<InvokeBody>> {
+        let body = BASE64_STANDARD.decode(&self.body).context("invalid body")?;
+        let mut request = hyper::Request::builder()
+            .method(self.method.as_str())
+            .uri(self.uri);
+        for (name, value) in &self.headers {
+            request = request.header(name, value);
+        }
+        Ok(request.body(Full::new(Bytes::from(body)).map_err(|e| match e {}).boxed())?)
+    }
+}
+
+/// Whether `id` can name a dead letter, rather than a path elsewhere in storage.
+fn is_id(id: &str) -> bool {
+    !id.is_empty()
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '-')
+}
+
+/// Records `request` as a dead letter of `key`, which failed for `reason`,
+/// keeping only the last `retention` dead letters of `key`.
+pub async fn record(
+    storage: &opendal::Operator,
+    key: &str,
+    request: CapturedRequest,
+    reason: String,
+    retention: usize,
+) -> Result<()> {
+    // Sorted by when they failed, the count tells apart those failing at once
+    static RECORDED: AtomicU64 = AtomicU64::new(0);
+    let count = RECORDED.fetch_add(1, Ordering::Relaxed);
+    let letter = DeadLetter {
+        id: format!(
+            "{}-{count:08}",
+            chrono::Utc::now().format("%Y%m%dT%H%M%S%.6fZ")
+        ),
+        reason,
+        request,
+    };
+    storage
+        .write(&path(key, &letter.id), serde_json::to_vec(&letter)?)
+        .await?;
+
+    let paths = paths(storage, key).await?;
+    for path in &paths[..paths.len().saturating_sub(retention)] {
+        storage.delete(path).await?;
+    }
+    Ok(())
+}
+
+/// The dead letter `id` of `key`, if there is one.
+pub async fn read(storage: &opendal::Operator, key: &str, id: &str) -> Result<Option<DeadLetter>> {
+    if !is_id(id) {
+        return Ok(None);
+    }
+    match storage.read(&path(key, id)).await {
+        Ok(letter) => Ok(Some(serde_json::from_slice(&letter.to_vec())?)),
+        Err(e) if e.kind() == opendal::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// The paths of the dead letters of `key`, oldest first.
+async fn paths(storage: &opendal::Operator, key: &str) -> Result<Vec<String>> {
+    let mut paths: Vec<String> = storage
+        .list(&dir(key))
+        .await?
+        .into_iter()
+        .map(|entry| entry.path().to_owned())
+        .filter(|path| path.ends_with(".json"))
+        .collect();
+    paths.sort();
+    Ok(paths)
+}
+
+/// The dead letters of `key`, oldest first. Files that can't be parsed are skipped.
+pub async fn list(storage: &opendal::Operator, key: &str) -> Result<Vec<DeadLetter>> {
+    let paths = paths(storage, key).await?;
+    let mut letters = Vec::with_capacity(paths.len());
+    for path in paths {
+        let letter = storage.read(&path).await?;
+        if let Ok(letter) = serde_json::from_slice(&letter.to_vec()) {
+            letters.push(letter);
+        }
+    }
+    Ok(letters)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn captured(path: &str) -> CapturedRequest {
+        let request = hyper::Request::post(path)
+            .header(hyper::header::AUTHORIZATION, "Bearer secret")
+            .header(hyper::header::COOKIE, "session=secret")
+            .header(hyper::header::CONTENT_TYPE, "application/json")
+            .body(())
+            .unwrap();
+        CapturedRequest::new(&request.into_parts().0, b"{}")
+    }
+
+    #[tokio::test]
+    async fn dead_letters_are_kept_up_to_the_retention_without_credentials() {
+        let storage = opendal::Operator::new(opendal::services::Memory::default())
+            .unwrap()
+            .finish();
+        for n in 0..5 {
+            let request = captured(&format!("/{n}"));
+            record(&storage, "app", request, String::from("trapped"), 3)
+                .await
+                .unwrap();
+        }
+
+        let letters = list(&storage, "app").await.unwrap();
+        let uris: Vec<_> = letters
+            .iter()
+            .map(|letter| letter.request.uri.as_str())
+            .collect();
+        assert_eq!(uris, ["/2", "/3", "/4"]);
+        let letter = &letters[0];
+        assert_eq!(
+            letter.request.headers,
+            [("content-type".into(), "application/json".into())]
+        );
+        assert!(read(&storage, "app", &letter.id).await.unwrap().is_some());
+    }
+}
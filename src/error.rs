@@ -0,0 +1,161 @@
+use axum::{
+    body::Bytes,
+    response::{IntoResponse, Response},
+    Json,
+};
+use http_body_util::{BodyExt, Full};
+use hyper::StatusCode;
+use wasmtime_wasi_http::body::HyperOutgoingBody;
+
+/// Why the host refused or failed a request, answered with a status and a
+/// JSON body of the form `{"error": ...}`.
+///
+/// Invocations the guest failed itself are answered by the worker instead,
+/// see [`crate::host::InvokeError`].
+#[derive(Debug)]
+pub enum RvmError {
+    BadRequest(String),
+    Unauthorized(String),
+    Forbidden(String),
+    /// No module is deployed under the key.
+    NotFound,
+    /// A `run` module was invoked with another method than `POST`.
+    MethodNotAllowed,
+    /// A `run` module was invoked without a JSON body.
+    UnsupportedMediaType,
+    /// A body exceeded a size limit, which the message states.
+    PayloadTooLarge(String),
+    /// The component doesn't export the interface of the kind it's deployed as.
+    MissingExport(String),
+    /// Deploying would make more modules than `max_modules`, which this is.
+    TooManyModules(usize),
+    /// The module's queue is full, or too many invocations are in flight.
+    ModuleBusy,
+    /// More deploys than `max_concurrent_deploys` are in progress.
+    TooManyDeploys,
+    /// The module can't take invocations right now, such as while draining.
+    Unavailable(String),
+    Timeout(String),
+    /// The guest failed while streaming its response.
+    Trapped(String),
+    /// A module couldn't be downloaded.
+    DownloadFailed(String),
+    CompileFailed(String),
+    Storage(String),
+    Internal(String),
+    /// Refused with a bare status, by a transform or by a limit that a later
+    /// layer explains.
+    Rejected(StatusCode),
+}
+
+impl RvmError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            RvmError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            RvmError::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            RvmError::Forbidden(_) => StatusCode::FORBIDDEN,
+            RvmError::NotFound => StatusCode::NOT_FOUND,
+            RvmError::MethodNotAllowed => StatusCode::METHOD_NOT_ALLOWED,
+            RvmError::UnsupportedMediaType => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            RvmError::PayloadTooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            RvmError::MissingExport(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            RvmError::TooManyModules(_) => StatusCode::INSUFFICIENT_STORAGE,
+            RvmError::TooManyDeploys => StatusCode::TOO_MANY_REQUESTS,
+            RvmError::ModuleBusy | RvmError::Unavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+            RvmError::Timeout(_) => StatusCode::GATEWAY_TIMEOUT,
+            RvmError::Trapped(_) | RvmError::DownloadFailed(_) => StatusCode::BAD_GATEWAY,
+            RvmError::CompileFailed(_) | RvmError::Storage(_) | RvmError::Internal(_) => {
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+            RvmError::Rejected(status) => *status,
+        }
+    }
+
+    /// The response for invocations, which are served by hyper rather than axum.
+    pub fn into_invoke_response(self) -> hyper::Response<HyperOutgoingBody> {
+        if let RvmError::Rejected(status) = self {
+            return hyper::Response::builder()
+                .status(status)
+                .body(Default::default())
+                .expect("valid response");
+        }
+        hyper::Response::builder()
+            .status(self.status())
+            .header(hyper::header::CONTENT_TYPE, "application/json")
+            .body(
+                Full::new(Bytes::from(self.body().to_string()))
+                    .map_err(|e| match e {})
+                    .boxed(),
+            )
+            .expect("valid response")
+    }
+
+    fn body(&self) -> serde_json::Value {
+        serde_json::json!({ "error": self.to_string() })
+    }
+}
+
+impl std::fmt::Display for RvmError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RvmError::BadRequest(message)
+            | RvmError::Unauthorized(message)
+            | RvmError::Forbidden(message)
+            | RvmError::PayloadTooLarge(message)
+            | RvmError::MissingExport(message)
+            | RvmError::Unavailable(message)
+            | RvmError::Timeout(message)
+            | RvmError::Trapped(message)
+            | RvmError::DownloadFailed(message)
+            | RvmError::CompileFailed(message)
+            | RvmError::Storage(message)
+            | RvmError::Internal(message) => f.write_str(message),
+            RvmError::NotFound => f.write_str("no module is deployed under this key"),
+            RvmError::MethodNotAllowed => f.write_str("run modules are invoked with a POST"),
+            RvmError::UnsupportedMediaType => {
+                f.write_str("run modules are invoked with a JSON body")
+            }
+            RvmError::TooManyModules(max) => write!(f, "at most {max} modules can be deployed"),
+            RvmError::ModuleBusy => f.write_str("the module is busy, retry later"),
+            RvmError::TooManyDeploys => {
+                f.write_str("too many deploys are in progress, retry later")
+            }
+            RvmError::Rejected(status) => {
+                f.write_str(status.canonical_reason().unwrap_or("rejected"))
+            }
+        }
+    }
+}
+
+impl std::error::Error for RvmError {}
+
+impl From<StatusCode> for RvmError {
+    fn from(status: StatusCode) -> Self {
+        RvmError::Rejected(status)
+    }
+}
+
+impl IntoResponse for RvmError {
+    fn into_response(self) -> Response {
+        if let RvmError::Rejected(status) = self {
+            return status.into_response();
+        }
+        let mut response = (self.status(), Json(self.body())).into_response();
+        match self {
+            RvmError::PayloadTooLarge(_) => {
+                response.extensions_mut().insert(LimitExplained);
+            }
+            RvmError::TooManyDeploys => {
+                response
+                    .headers_mut()
+                    .insert(hyper::header::RETRY_AFTER, 1.into());
+            }
+            _ => {}
+        }
+        response
+    }
+}
+
+/// Marks a `413` response whose body already states the limit that was exceeded.
+#[derive(Clone)]
+pub struct LimitExplained;
@@ -0,0 +1,168 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use crate::state::SharedState;
+
+/// Where the fuel consumed by each invocation is reported, in addition to the
+/// per-module totals kept in memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FuelSink {
+    None,
+    /// Print a JSON line for every invocation.
+    Stdout,
+    /// Periodically persist the per-module totals, so they survive restarts.
+    Storage,
+}
+
+impl FromStr for FuelSink {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(FuelSink::None),
+            "stdout" => Ok(FuelSink::Stdout),
+            "storage" => Ok(FuelSink::Storage),
+            _ => Err(format!("unknown fuel sink `{s}`")),
+        }
+    }
+}
+
+/// Where the per-module totals are stored when using [`FuelSink::Storage`].
+const TOTALS_PATH: &str = "fuel/totals.json";
+
+/// Accumulates the fuel consumed by a module.
+#[derive(Clone)]
+pub struct FuelMeter {
+    key: String,
+    total: Arc<AtomicU64>,
+    sink: FuelSink,
+}
+
+impl FuelMeter {
+    pub fn new(key: String, total: Arc<AtomicU64>, sink: FuelSink) -> Self {
+        Self { key, total, sink }
+    }
+
+    pub fn record(&self, consumed: u64) {
+        let total = self.total.fetch_add(consumed, Ordering::Relaxed) + consumed;
+        if self.sink == FuelSink::Stdout {
+            println!(
+                "{}",
+                serde_json::json!({
+                    "key": self.key,
+                    "fuel_consumed": consumed,
+                    "fuel_total": total,
+                })
+            );
+        }
+    }
+}
+
+/// How often a module called each host function, by `interface.function`
+/// name, across all of its deploys.
+#[derive(Default)]
+pub struct HostCalls(Mutex<BTreeMap<&'static str, u64>>);
+
+impl HostCalls {
+    pub fn counts(&self) -> BTreeMap<&'static str, u64> {
+        self.0.lock().unwrap().clone()
+    }
+}
+
+/// Counts the host functions an instance calls, and the fuel it owes for them
+/// as configured in `host_call_fuel`, charged when the call returns to the guest.
+#[derive(Clone)]
+pub struct HostCallMeter {
+    prices: Arc<HashMap<String, u64>>,
+    calls: Arc<HostCalls>,
+    /// Fuel owed for the calls made since the instance was last charged.
+    owed: u64,
+}
+
+impl HostCallMeter {
+    pub fn new(prices: Arc<HashMap<String, u64>>, calls: Arc<HostCalls>) -> Self {
+        Self {
+            prices,
+            calls,
+            owed: 0,
+        }
+    }
+
+    /// Whether calling any host function costs fuel.
+    pub fn is_priced(&self) -> bool {
+        self.prices.values().any(|&fuel| fuel > 0)
+    }
+
+    /// Counts a call of `function`, owing its price.
+    pub fn record(&mut self, function: &'static str) {
+        *self.calls.0.lock().unwrap().entry(function).or_default() += 1;
+        let price = self.prices.get(function).copied().unwrap_or(0);
+        self.owed = self.owed.saturating_add(price);
+    }
+
+    /// The fuel owed since this was last called.
+    pub fn take_owed(&mut self) -> u64 {
+        std::mem::take(&mut self.owed)
+    }
+}
+
+/// Reads the per-module totals persisted by [`spawn_flusher`].
+pub async fn load_totals(storage: &opendal::Operator) -> anyhow::Result<HashMap<String, u64>> {
+    match storage.read(TOTALS_PATH).await {
+        Ok(buffer) => Ok(serde_json::from_slice(&buffer.to_bytes())?),
+        Err(e) if e.kind() == opendal::ErrorKind::NotFound => Ok(HashMap::new()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Persists the per-module totals every `interval`, whenever they changed.
+pub fn spawn_flusher(state: SharedState, interval: Duration) {
+    tokio::spawn(async move {
+        let mut flushed = HashMap::new();
+        let mut ticker = tokio::time::interval(interval);
+        loop {
+            ticker.tick().await;
+            let (storage, totals) = totals(&state).await;
+            if totals == flushed {
+                continue;
+            }
+            match write_totals(&storage, &totals).await {
+                Ok(_) => flushed = totals,
+                Err(e) => tracing::error!("Failed to persist fuel totals: {e}"),
+            }
+        }
+    });
+}
+
+/// Persists the per-module totals right away.
+pub async fn flush(state: &SharedState) -> anyhow::Result<()> {
+    let (storage, totals) = totals(state).await;
+    write_totals(&storage, &totals).await
+}
+
+async fn totals(state: &SharedState) -> (opendal::Operator, HashMap<String, u64>) {
+    let state = state.read().await;
+    let totals = state
+        .fuel_consumed
+        .iter()
+        .map(|(key, total)| (key.clone(), total.load(Ordering::Relaxed)))
+        .collect();
+    (state.storage.clone(), totals)
+}
+
+async fn write_totals(
+    storage: &opendal::Operator,
+    totals: &HashMap<String, u64>,
+) -> anyhow::Result<()> {
+    let json = serde_json::to_vec(totals).expect("totals serialize");
+    storage.write(TOTALS_PATH, json).await?;
+    Ok(())
+}
@@ -0,0 +1,195 @@
+use std::{
+    convert::Infallible,
+    future::Future,
+    pin::Pin,
+    str::FromStr,
+    task::{Context, Poll},
+};
+
+use axum::body::Bytes;
+use http_body_util::{BodyExt, Full};
+use hyper::{
+    http::{uri::PathAndQuery, HeaderName, HeaderValue, Method, Uri},
+    StatusCode,
+};
+use tonic::{
+    codec::ProstCodec,
+    server::{Grpc, NamedService, UnaryService},
+    Code, Status,
+};
+use wasmtime_wasi_http::bindings::http::types::Scheme;
+
+use crate::{error::RvmError, services, shutdown::Shutdown, state::SharedState, tenant};
+
+type BoxFuture<T> = Pin<Box<dyn Future<Output = T> + Send>>;
+
+/// The request handed to a module, see `proto/rvm.proto`.
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct InvokeRequest {
+    #[prost(string, tag = "1")]
+    pub key: String,
+    #[prost(string, tag = "2")]
+    pub method: String,
+    #[prost(string, tag = "3")]
+    pub path: String,
+    #[prost(message, repeated, tag = "4")]
+    pub headers: Vec<Header>,
+    #[prost(bytes = "bytes", tag = "5")]
+    pub body: Bytes,
+}
+
+/// The response a module answered with.
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct InvokeResponse {
+    #[prost(uint32, tag = "1")]
+    pub status: u32,
+    #[prost(message, repeated, tag = "2")]
+    pub headers: Vec<Header>,
+    #[prost(bytes = "bytes", tag = "3")]
+    pub body: Bytes,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Header {
+    #[prost(string, tag = "1")]
+    pub name: String,
+    #[prost(bytes = "bytes", tag = "2")]
+    pub value: Bytes,
+}
+
+/// The `rvm.Invoker` service, which invokes modules through the same workers,
+/// limits and accounting as the proxy.
+#[derive(Clone)]
+pub struct Invoker {
+    state: SharedState,
+    max_message_bytes: usize,
+}
+
+impl NamedService for Invoker {
+    const NAME: &'static str = "rvm.Invoker";
+}
+
+impl tower::Service<hyper::Request<tonic::body::Body>> for Invoker {
+    type Response = hyper::Response<tonic::body::Body>;
+    type Error = Infallible;
+    type Future = BoxFuture<Result<Self::Response, Infallible>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Infallible>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, request: hyper::Request<tonic::body::Body>) -> Self::Future {
+        let invoker = self.clone();
+        Box::pin(async move {
+            if request.uri().path() != "/rvm.Invoker/Invoke" {
+                return Ok(Status::unimplemented("no such method").into_http());
+            }
+            let mut grpc = Grpc::new(ProstCodec::default()).apply_max_message_size_config(
+                Some(invoker.max_message_bytes),
+                Some(invoker.max_message_bytes),
+            );
+            Ok(grpc.unary(invoker, request).await)
+        })
+    }
+}
+
+impl UnaryService<InvokeRequest> for Invoker {
+    type Response = InvokeResponse;
+    type Future = BoxFuture<Result<tonic::Response<InvokeResponse>, Status>>;
+
+    fn call(&mut self, request: tonic::Request<InvokeRequest>) -> Self::Future {
+        Box::pin(invoke(self.state.clone(), request))
+    }
+}
+
+async fn invoke(
+    state: SharedState,
+    request: tonic::Request<InvokeRequest>,
+) -> Result<tonic::Response<InvokeResponse>, Status> {
+    let client = request.remote_addr();
+    let invocation = request.into_inner();
+
+    let mut req = hyper::Request::new(Full::new(invocation.body).map_err(|e| match e {}).boxed());
+    if !invocation.method.is_empty() {
+        *req.method_mut() = Method::from_str(&invocation.method)
+            .map_err(|_| Status::invalid_argument("invalid method"))?;
+    }
+    if !invocation.path.is_empty() {
+        *req.uri_mut() = PathAndQuery::from_str(&invocation.path)
+            .map(Uri::from)
+            .map_err(|_| Status::invalid_argument("invalid path"))?;
+    }
+    for header in invocation.headers {
+        let name = HeaderName::from_str(&header.name)
+            .map_err(|_| Status::invalid_argument(format!("invalid header {}", header.name)))?;
+        let value = HeaderValue::from_maybe_shared(header.value)
+            .map_err(|_| Status::invalid_argument(format!("invalid value for header {name}")))?;
+        req.headers_mut().append(name, value);
+    }
+    if let Some(client) = client {
+        crate::append_forwarded_for(req.headers_mut(), client.ip());
+    }
+
+    let key = tenant::scoped_key(req.headers(), &invocation.key).map_err(into_status)?;
+    tracing::info!(key=%key, "Invoking module over gRPC");
+    let response = services::invoke_module(&key, req, Scheme::Http, state, None)
+        .await
+        .map_err(into_status)?;
+
+    let (parts, body) = response.into_parts();
+    let body = body
+        .collect()
+        .await
+        .map_err(|e| Status::internal(format!("failed to read the response: {e}")))?
+        .to_bytes();
+    let headers = parts
+        .headers
+        .iter()
+        .map(|(name, value)| Header {
+            name: name.to_string(),
+            value: Bytes::copy_from_slice(value.as_bytes()),
+        })
+        .collect();
+    Ok(tonic::Response::new(InvokeResponse {
+        status: parts.status.as_u16().into(),
+        headers,
+        body,
+    }))
+}
+
+/// The gRPC status for an invocation rvm refused or failed itself.
+fn into_status(error: RvmError) -> Status {
+    let code = match error.status() {
+        StatusCode::BAD_REQUEST
+        | StatusCode::METHOD_NOT_ALLOWED
+        | StatusCode::UNSUPPORTED_MEDIA_TYPE => Code::InvalidArgument,
+        StatusCode::UNAUTHORIZED => Code::Unauthenticated,
+        StatusCode::FORBIDDEN => Code::PermissionDenied,
+        StatusCode::NOT_FOUND => Code::NotFound,
+        StatusCode::PAYLOAD_TOO_LARGE | StatusCode::TOO_MANY_REQUESTS => Code::ResourceExhausted,
+        StatusCode::UNPROCESSABLE_ENTITY => Code::FailedPrecondition,
+        StatusCode::SERVICE_UNAVAILABLE => Code::Unavailable,
+        StatusCode::GATEWAY_TIMEOUT => Code::DeadlineExceeded,
+        _ => Code::Internal,
+    };
+    Status::new(code, error.to_string())
+}
+
+/// Serves the `Invoker` service on `listener` until shutting down.
+pub async fn serve(
+    listener: tokio::net::TcpListener,
+    state: SharedState,
+    shutdown: Shutdown,
+    max_message_bytes: usize,
+) -> Result<(), tonic::transport::Error> {
+    tonic::transport::Server::builder()
+        .add_service(Invoker {
+            state,
+            max_message_bytes,
+        })
+        .serve_with_incoming_shutdown(
+            tonic::transport::server::TcpIncoming::from(listener),
+            async move { shutdown.started().await },
+        )
+        .await
+}
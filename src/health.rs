@@ -0,0 +1,92 @@
+use std::time::Duration;
+
+use anyhow::{Context as _, Result};
+use http_body_util::{BodyExt, Empty};
+use wasmtime::{component::Component, Engine, Store};
+use wasmtime_wasi_http::bindings::http::types::Scheme;
+
+use crate::{budget::EPOCH_TICK, services, state::SharedState};
+
+/// How long a deep health check may take before the host is reported unhealthy.
+pub const DEEP_CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A component exporting a `ping` function that does nothing, compiled,
+/// instantiated and invoked by deep health checks without any module deployed.
+const CANARY: &str = r#"
+(component
+  (core module $canary
+    (memory (export "memory") 1)
+    (func (export "ping") (result i32) i32.const 1))
+  (core instance $canary (instantiate $canary))
+  (func (export "ping") (result u32) (canon lift (core func $canary "ping"))))
+"#;
+
+/// Runs the canary, or invokes `health_module` with a `GET /` if set, failing
+/// if it doesn't answer successfully within [`DEEP_CHECK_TIMEOUT`].
+pub async fn deep_check(state: &SharedState) -> Result<()> {
+    let (engine, health_module) = {
+        let state = state.read().await;
+        (state.engine.clone(), state.config.health_module.clone())
+    };
+    let checked = async {
+        match health_module {
+            Some(key) => invoke_health_module(state, &key).await,
+            None => run_canary(engine).await,
+        }
+    };
+    tokio::time::timeout(DEEP_CHECK_TIMEOUT, checked)
+        .await
+        .map_err(|_| anyhow::anyhow!("timed out after {DEEP_CHECK_TIMEOUT:?}"))?
+}
+
+/// Compiles, instantiates and calls the canary with the host's engine, so its
+/// compiler, allocator and epochs are known to work.
+async fn run_canary(engine: Engine) -> Result<()> {
+    // Off the runtime, like compiling modules
+    let compiled = tokio::task::spawn_blocking({
+        let engine = engine.clone();
+        move || Component::new(&engine, CANARY)
+    });
+    let component = compiled.await?.context("failed to compile the canary")?;
+    let mut store = Store::new(&engine, ());
+    store.set_fuel(10_000)?;
+    store.set_epoch_deadline(
+        DEEP_CHECK_TIMEOUT
+            .as_millis()
+            .div_ceil(EPOCH_TICK.as_millis()) as u64,
+    );
+    let instance = wasmtime::component::Linker::new(&engine)
+        .instantiate_async(&mut store, &component)
+        .await
+        .context("failed to instantiate the canary")?;
+    let ping = instance.get_typed_func::<(), (u32,)>(&mut store, "ping")?;
+    let (pong,) = ping
+        .call_async(&mut store, ())
+        .await
+        .context("the canary trapped")?;
+    ping.post_return_async(&mut store).await?;
+    anyhow::ensure!(pong == 1, "the canary answered {pong}");
+    Ok(())
+}
+
+/// Invokes `key` like a client would, failing on a `5xx`.
+async fn invoke_health_module(state: &SharedState, key: &str) -> Result<()> {
+    let request = hyper::Request::get("/")
+        .header(hyper::header::HOST, "localhost")
+        .body(Empty::new().map_err(|e| match e {}).boxed())?;
+    let response = services::invoke_module(key, request, Scheme::Http, state.clone(), None)
+        .await
+        .with_context(|| format!("failed to invoke `{key}`"))?;
+    let status = response.status();
+    // Reading the body lets a streaming guest run to completion
+    response
+        .into_body()
+        .collect()
+        .await
+        .map_err(|e| anyhow::anyhow!("failed to read the response of `{key}`: {e:?}"))?;
+    anyhow::ensure!(
+        !status.is_server_error(),
+        "`{key}` answered with a {status}"
+    );
+    Ok(())
+}
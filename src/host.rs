@@ -1,16 +1,44 @@
+use std::{
+    collections::HashMap,
+    future::Future,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    task::{ready, Context, Poll},
+};
+
+use anyhow::Context as _;
 use axum::body::Bytes;
+use http_body_util::{combinators::BoxBody, BodyExt, Empty, Full};
+use hyper::{
+    body::{Body, Frame},
+    HeaderMap, StatusCode,
+};
 use tokio::sync::{mpsc, oneshot};
 use wasmtime::{
     component::{bindgen, Component},
     *,
 };
-use wasmtime_wasi::{IoView, ResourceTable, WasiCtx, WasiCtxBuilder, WasiView};
+
+use cap_rand::{rngs::StdRng, SeedableRng};
+use wasmtime_wasi::{HostWallClock, IoView, ResourceTable, WasiCtx, WasiCtxBuilder, WasiView};
 use wasmtime_wasi_http::{
     bindings::http::types::{ErrorCode, Scheme},
     body::HyperOutgoingBody,
     WasiHttpCtx, WasiHttpView,
 };
 
+use crate::{
+    budget::{CpuClock, CpuTimeExceeded, CpuUsage, MemoryLimiter, ResourceBudget},
+    config::{ModuleConfig, ModuleKind, RvmConfig},
+    fuel::{FuelMeter, HostCallMeter},
+    local::{CallChain, LocalInvoker},
+    metrics::GuestMetrics,
+    stdio::GuestStdio,
+};
+
 // Generate bindings of the guest and host components.
 bindgen!({
     path: "./wit",
@@ -22,18 +50,145 @@ bindgen!({
     }
 });
 
+/// Bindings of modules that export `rvm:lambda/run` instead of an HTTP handler.
+mod lambda {
+    wasmtime::component::bindgen!({
+        path: "./wit",
+        world: "lambda",
+        async: true,
+        with: {
+            "rvm:lambda/host": super::rvm::lambda::host,
+            "rvm:lambda/kv": super::rvm::lambda::kv,
+            "wasi:http/types@0.2.3": wasmtime_wasi_http::bindings::http::types,
+            "wasi:http@0.2.3": wasmtime_wasi_http::bindings::http,
+        }
+    });
+}
+
 #[derive(Clone)]
-pub struct HostComponent;
+pub struct HostComponent {
+    key: String,
+    hash: blake3::Hash,
+    /// Storage backing the module's `kv` interface, under `kv/{key}/`.
+    storage: opendal::Operator,
+    kv_max_value_bytes: usize,
+    /// Where the module's `metric-*` calls are recorded.
+    metrics: Arc<GuestMetrics>,
+    /// Dispatches the module's `invoke-local` calls.
+    local: LocalInvoker,
+    /// The modules waiting on the invocation running.
+    chain: CallChain,
+    /// Counts the host functions the module calls, and what it owes for them.
+    calls: HostCallMeter,
+}
+
+impl HostComponent {
+    pub fn new(
+        key: String,
+        hash: blake3::Hash,
+        storage: opendal::Operator,
+        kv_max_value_bytes: usize,
+        metrics: Arc<GuestMetrics>,
+        local: LocalInvoker,
+        calls: HostCallMeter,
+    ) -> Self {
+        Self {
+            key,
+            hash,
+            storage,
+            kv_max_value_bytes,
+            metrics,
+            local,
+            chain: CallChain::default(),
+            calls,
+        }
+    }
+
+    /// Path of `key` in the module's key-value storage. Keys can't escape it.
+    fn kv_path(&self, key: &str) -> Result<String, rvm::lambda::kv::Error> {
+        if key.is_empty() || key == "." || key == ".." || key.contains(['/', '\\']) {
+            return Err(rvm::lambda::kv::Error::InvalidKey);
+        }
+        Ok(format!("kv/{}/{key}", self.key))
+    }
+}
 
 // Implementation of the host interface defined in the wit file.
 impl rvm::lambda::host::Host for HostComponent {
     async fn multiply(&mut self, a: f32, b: f32) -> f32 {
+        self.calls.record("host.multiply");
         a * b
     }
 
     async fn client_secret(&mut self) -> String {
+        self.calls.record("host.client-secret");
         String::from("THIS IS A SECRET!")
     }
+
+    async fn self_info(&mut self) -> rvm::lambda::host::ModuleInfo {
+        self.calls.record("host.self-info");
+        rvm::lambda::host::ModuleInfo {
+            key: self.key.clone(),
+            hash: self.hash.to_string(),
+        }
+    }
+
+    async fn metric_incr(&mut self, name: String, value: u64) {
+        self.calls.record("host.metric-incr");
+        self.metrics.incr(&self.key, &name, value);
+    }
+
+    async fn metric_observe(&mut self, name: String, value: f64) {
+        self.calls.record("host.metric-observe");
+        self.metrics.observe(&self.key, &name, value);
+    }
+
+    async fn invoke_local(
+        &mut self,
+        key: String,
+        request: rvm::lambda::host::LocalRequest,
+    ) -> Result<rvm::lambda::host::LocalResponse, String> {
+        self.calls.record("host.invoke-local");
+        self.local
+            .invoke(&self.key, &self.chain, &key, request)
+            .await
+            .map_err(|e| format!("{e:#}"))
+    }
+}
+
+impl rvm::lambda::kv::Host for HostComponent {
+    async fn get(&mut self, key: String) -> Result<Option<Vec<u8>>, rvm::lambda::kv::Error> {
+        self.calls.record("kv.get");
+        let path = self.kv_path(&key)?;
+        match self.storage.read(&path).await {
+            Ok(value) => Ok(Some(value.to_vec())),
+            Err(e) if e.kind() == opendal::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(rvm::lambda::kv::Error::Storage(e.to_string())),
+        }
+    }
+
+    async fn set(&mut self, key: String, value: Vec<u8>) -> Result<(), rvm::lambda::kv::Error> {
+        self.calls.record("kv.set");
+        let path = self.kv_path(&key)?;
+        if value.len() > self.kv_max_value_bytes {
+            let limit = self.kv_max_value_bytes as u64;
+            return Err(rvm::lambda::kv::Error::ValueTooLarge(limit));
+        }
+        self.storage
+            .write(&path, value)
+            .await
+            .map(|_| ())
+            .map_err(|e| rvm::lambda::kv::Error::Storage(e.to_string()))
+    }
+
+    async fn delete(&mut self, key: String) -> Result<(), rvm::lambda::kv::Error> {
+        self.calls.record("kv.delete");
+        let path = self.kv_path(&key)?;
+        self.storage
+            .delete(&path)
+            .await
+            .map_err(|e| rvm::lambda::kv::Error::Storage(e.to_string()))
+    }
 }
 
 pub struct RvmState {
@@ -41,6 +196,11 @@ pub struct RvmState {
     wasi: WasiCtx,
     http: WasiHttpCtx,
     table: ResourceTable,
+    memory: MemoryLimiter,
+    cpu: CpuClock,
+    /// Budget of the invocation running, checked on every epoch tick if it
+    /// limits CPU time.
+    budget: ResourceBudget,
 }
 
 impl RvmState {
@@ -66,79 +226,1150 @@ impl WasiHttpView for RvmState {
     }
 }
 
+/// Why an invocation failed to produce a response.
+#[derive(Debug)]
+pub enum InvokeError {
+    /// The guest trapped or ran out of fuel, `code` is a short kebab-case name
+    /// of the trap and `status` what the client is answered with.
+    Trap {
+        code: &'static str,
+        reason: String,
+        status: StatusCode,
+    },
+    /// The guest handled the request but responded with an error code.
+    Guest(ErrorCode),
+}
+
+impl InvokeError {
+    /// Categorizes a failed call, answered with the status configured for its
+    /// category in `trap_statuses`, if any.
+    fn from_call_error(e: anyhow::Error, trap_statuses: &HashMap<String, u16>) -> Self {
+        let (code, reason) = match e.downcast_ref::<Trap>() {
+            Some(Trap::OutOfFuel) => (
+                "out-of-fuel",
+                String::from("all fuel consumed by the module"),
+            ),
+            Some(trap) => (trap_code(trap), trap.to_string()),
+            None if e.is::<CpuTimeExceeded>() => ("cpu-time-exceeded", CpuTimeExceeded.to_string()),
+            None => ("host-error", e.to_string()),
+        };
+        let status = trap_statuses
+            .get(code)
+            .and_then(|&status| StatusCode::from_u16(status).ok())
+            .unwrap_or(match code {
+                "out-of-fuel" | "cpu-time-exceeded" => StatusCode::SERVICE_UNAVAILABLE,
+                _ => StatusCode::BAD_GATEWAY,
+            });
+        InvokeError::Trap {
+            code,
+            reason,
+            status,
+        }
+    }
+
+    /// Builds the response sent back to the client when the invocation failed.
+    pub fn into_response(self) -> hyper::Response<HyperOutgoingBody> {
+        let (status, code, reason) = match self {
+            InvokeError::Trap {
+                code,
+                reason,
+                status,
+            } => (status, code, reason),
+            InvokeError::Guest(code) => {
+                tracing::warn!(code=?code, "Guest responded with an error");
+                return hyper::Response::builder()
+                    .status(StatusCode::INTERNAL_SERVER_ERROR)
+                    .body(Default::default())
+                    .expect("valid response");
+            }
+        };
+        let body = serde_json::json!({ "trap": code, "reason": reason }).to_string();
+        hyper::Response::builder()
+            .status(status)
+            .header("x-rvm-trap", code)
+            .header(hyper::header::CONTENT_TYPE, "application/json")
+            .body(Full::new(Bytes::from(body)).map_err(|e| match e {}).boxed())
+            .expect("valid response")
+    }
+}
+
+fn trap_code(trap: &Trap) -> &'static str {
+    match trap {
+        Trap::StackOverflow => "stack-overflow",
+        Trap::MemoryOutOfBounds => "memory-out-of-bounds",
+        Trap::HeapMisaligned => "heap-misaligned",
+        Trap::TableOutOfBounds => "table-out-of-bounds",
+        Trap::IndirectCallToNull => "indirect-call-to-null",
+        Trap::BadSignature => "bad-signature",
+        Trap::IntegerOverflow => "integer-overflow",
+        Trap::IntegerDivisionByZero => "integer-division-by-zero",
+        Trap::BadConversionToInteger => "bad-conversion-to-integer",
+        Trap::UnreachableCodeReached => "unreachable-code-reached",
+        Trap::Interrupt => "interrupt",
+        Trap::OutOfFuel => "out-of-fuel",
+        _ => "trap",
+    }
+}
+
+/// Body of a request to invoke a module with.
+pub type InvokeBody = BoxBody<Bytes, hyper::Error>;
+
 pub struct InvokeRequest {
-    pub response: oneshot::Sender<Result<hyper::Response<HyperOutgoingBody>, ErrorCode>>,
-    pub request: hyper::Request<hyper::body::Incoming>,
+    pub response: oneshot::Sender<Result<hyper::Response<HyperOutgoingBody>, InvokeError>>,
+    pub request: hyper::Request<InvokeBody>,
+    /// The scheme the request was received over.
+    pub scheme: Scheme,
+    /// Fuel to run just this invocation with, instead of the instance's remaining fuel.
+    pub fuel: Option<u64>,
+    /// How long just this invocation may run, instead of the module's timeout.
+    pub timeout: Option<std::time::Duration>,
+    /// Receives a report of the invocation once the guest is done, capturing
+    /// its output instead of forwarding it to the host's stdio.
+    pub report: Option<oneshot::Sender<InvocationReport>>,
+    /// Span of the invocation, where the worker records the fuel it consumed.
+    pub span: tracing::Span,
 }
 
-#[tracing::instrument(err, skip(engine, linker, receiver, bytes))]
-pub async fn compile_and_start_instance_worker(
+/// What happened during a single invocation, for debugging modules.
+#[derive(Debug, serde::Serialize)]
+pub struct InvocationReport {
+    pub stdout: String,
+    pub stderr: String,
+    pub fuel_consumed: u64,
+    pub wall_time_ms: u128,
+    /// Time the guest spent running, not counting the time it waited on I/O.
+    pub cpu_time_ms: u128,
+    /// Size of the instance's memory.
+    pub memory_bytes: usize,
+    /// Why the guest failed, if it trapped.
+    pub trap: Option<String>,
+}
+
+/// The most recent failure of a module.
+#[derive(Clone, serde::Serialize)]
+pub struct LastError {
+    pub error: String,
+    /// When it failed, in RFC 3339 format.
+    pub at: String,
+}
+
+/// Keeps the most recent failure of a module, shared by all of its instances.
+#[derive(Clone, Default)]
+pub struct ErrorLog(Arc<Mutex<Option<LastError>>>);
+
+impl ErrorLog {
+    pub fn record(&self, error: impl Into<String>) {
+        *self.0.lock().unwrap() = Some(LastError {
+            error: error.into(),
+            at: chrono::Utc::now().to_rfc3339(),
+        });
+    }
+
+    pub fn last(&self) -> Option<LastError> {
+        self.0.lock().unwrap().clone()
+    }
+}
+
+/// Name of the header, or trailer for streamed responses, with the fuel an invocation consumed.
+const X_RVM_FUEL_CONSUMED: &str = "x-rvm-fuel-consumed";
+
+/// What an invocation with a buffered response used.
+struct Usage {
+    fuel_remaining: u64,
+    fuel_consumed: u64,
+    wall_time_ms: u64,
+    cpu_time_ms: u64,
+    memory_bytes: usize,
+}
+
+impl Usage {
+    /// Reports the usage in `headers`, only if `expose_fuel_headers` is set
+    /// as it tells clients about the host's internals.
+    fn append_headers(&self, headers: &mut HeaderMap, expose_fuel_headers: bool) {
+        if !expose_fuel_headers {
+            return;
+        }
+        headers.append("x-rvm-fuel-remaining", self.fuel_remaining.into());
+        headers.append(X_RVM_FUEL_CONSUMED, self.fuel_consumed.into());
+        headers.append("x-rvm-wall-time-ms", self.wall_time_ms.into());
+        headers.append("x-rvm-duration-ms", self.cpu_time_ms.into());
+        headers.append("x-rvm-memory-bytes", self.memory_bytes.into());
+    }
+}
+
+/// Lets the trailers of `response` reach HTTP/1.1 clients, which are only sent
+/// those it announces in a `Trailer` header, and only in a chunked body.
+fn forward_trailers(
+    mut response: hyper::Response<HyperOutgoingBody>,
+) -> hyper::Response<HyperOutgoingBody> {
+    let headers = response.headers_mut();
+    if headers.contains_key(hyper::header::TRAILER) {
+        // A known length would be sent as is, without room for trailers
+        headers.remove(hyper::header::CONTENT_LENGTH);
+    }
+    response
+}
+
+/// Runs the guest's `call`, handing the response it sets over to `stream` right
+/// away if it keeps running after setting it, as it is still producing the body.
+/// Returns what the call returned, and what `stream` did if it got the response.
+async fn stream_early<C: Future, R, T>(
+    call: C,
+    response: &mut oneshot::Receiver<R>,
+    stream: impl FnOnce(R) -> T,
+) -> (C::Output, Option<Result<T, oneshot::error::RecvError>>) {
+    tokio::pin!(call);
+    tokio::select! {
+        biased;
+        output = &mut call => (output, None),
+        received = response => {
+            // Handed over before the guest finishes writing the body
+            let streamed = received.map(stream);
+            (call.await, Some(streamed))
+        }
+    }
+}
+
+/// Wraps the body of a streamed response to end it with an `x-rvm-fuel-consumed`
+/// trailer, once the guest has finished and its fuel usage is known.
+///
+/// Trailers are only sent to clients that ask for them with `TE: trailers`.
+struct FuelTrailerBody {
+    inner: HyperOutgoingBody,
+    /// Trailers sent by the guest, held back until the fuel is known.
+    trailers: Option<HeaderMap>,
+    inner_done: bool,
+    fuel_consumed: Option<oneshot::Receiver<u64>>,
+}
+
+impl FuelTrailerBody {
+    fn wrap(
+        mut response: hyper::Response<HyperOutgoingBody>,
+        fuel_consumed: oneshot::Receiver<u64>,
+    ) -> hyper::Response<HyperOutgoingBody> {
+        response
+            .headers_mut()
+            .append(hyper::header::TRAILER, X_RVM_FUEL_CONSUMED.parse().unwrap());
+        response.map(|inner| {
+            FuelTrailerBody {
+                inner,
+                trailers: None,
+                inner_done: false,
+                fuel_consumed: Some(fuel_consumed),
+            }
+            .boxed()
+        })
+    }
+}
+
+impl Body for FuelTrailerBody {
+    type Data = Bytes;
+    type Error = ErrorCode;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let this = &mut *self;
+        while !this.inner_done {
+            match ready!(Pin::new(&mut this.inner).poll_frame(cx)) {
+                Some(Ok(frame)) => match frame.into_trailers() {
+                    Ok(trailers) => this.trailers = Some(trailers),
+                    Err(frame) => return Poll::Ready(Some(Ok(frame))),
+                },
+                Some(Err(e)) => return Poll::Ready(Some(Err(e))),
+                None => this.inner_done = true,
+            }
+        }
+
+        let Some(fuel_consumed) = &mut this.fuel_consumed else {
+            return Poll::Ready(None);
+        };
+        let consumed = ready!(Pin::new(fuel_consumed).poll(cx));
+        this.fuel_consumed = None;
+        let mut trailers = this.trailers.take().unwrap_or_default();
+        if let Ok(consumed) = consumed {
+            trailers.insert(X_RVM_FUEL_CONSUMED, consumed.into());
+        }
+        Poll::Ready(Some(Ok(Frame::trailers(trailers))))
+    }
+}
+
+/// Wraps the body of a response to end it with an error once the guest has
+/// written more than `limit` bytes of it, cutting runaway output short.
+struct LimitedBody {
+    inner: HyperOutgoingBody,
+    remaining: u64,
+    limit: u64,
     key: String,
-    engine: &wasmtime::Engine,
-    linker: &wasmtime::component::Linker<RvmState>,
-    mut receiver: mpsc::UnboundedReceiver<InvokeRequest>,
-    bytes: Bytes,
+}
+
+impl LimitedBody {
+    fn wrap(
+        response: hyper::Response<HyperOutgoingBody>,
+        limit: Option<u64>,
+        key: &str,
+    ) -> hyper::Response<HyperOutgoingBody> {
+        let Some(limit) = limit else {
+            return response;
+        };
+        response.map(|inner| {
+            LimitedBody {
+                inner,
+                remaining: limit,
+                limit,
+                key: key.to_owned(),
+            }
+            .boxed()
+        })
+    }
+}
+
+impl Body for LimitedBody {
+    type Data = Bytes;
+    type Error = ErrorCode;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let this = &mut *self;
+        let frame = ready!(Pin::new(&mut this.inner).poll_frame(cx));
+        if let Some(data) = frame.as_ref().and_then(|f| f.as_ref().ok()?.data_ref()) {
+            let len = data.len() as u64;
+            if len > this.remaining {
+                tracing::warn!(key=%this.key, "Response body exceeded {} bytes", this.limit);
+                let error = ErrorCode::HttpResponseBodySize(Some(this.limit));
+                return Poll::Ready(Some(Err(error)));
+            }
+            this.remaining -= len;
+        }
+        Poll::Ready(frame)
+    }
+}
+
+/// Where the output, fuel usage and failures of a module's instances are recorded.
+pub struct ModuleOutput {
+    pub fuel: FuelMeter,
+    pub cpu: Arc<CpuUsage>,
+    /// Counts the instances replaced after serving `recycle_after_requests`.
+    pub recycled: Arc<AtomicU64>,
+    pub stdio: GuestStdio,
+    pub errors: ErrorLog,
+}
+
+/// A wall clock that is stuck at the unix epoch, for modules that must not
+/// observe the current time.
+struct FrozenClock;
+
+impl HostWallClock for FrozenClock {
+    fn resolution(&self) -> std::time::Duration {
+        std::time::Duration::from_secs(1)
+    }
+
+    fn now(&self) -> std::time::Duration {
+        std::time::Duration::ZERO
+    }
+}
+
+/// Builds the WASI context of a module, granting only the capabilities its
+/// configuration allows.
+fn wasi_ctx(module_config: &ModuleConfig, stdio: &GuestStdio, inherit_env: bool) -> WasiCtx {
+    let mut builder = WasiCtxBuilder::new();
+    builder
+        .stdout(stdio.stdout.clone())
+        .stderr(stdio.stderr.clone());
+    if inherit_env {
+        builder.inherit_env();
+    }
+    if !module_config.allow_wall_clock {
+        builder.wall_clock(FrozenClock);
+    }
+    if !module_config.allow_random {
+        builder
+            .secure_random(StdRng::seed_from_u64(0))
+            .insecure_random(StdRng::seed_from_u64(0))
+            .insecure_random_seed(0);
+    }
+    // The allowlist was checked when the module was deployed
+    match module_config.socket_allowlist() {
+        Ok(allowlist) if module_config.allow_sockets => {
+            builder
+                .allow_ip_name_lookup(true)
+                .socket_addr_check(move |addr, _| {
+                    let allowed = allowlist
+                        .as_ref()
+                        .is_none_or(|allowlist| allowlist.iter().any(|a| a.allows(addr)));
+                    Box::pin(async move { allowed })
+                });
+        }
+        _ => {
+            builder.allow_tcp(false).allow_udp(false);
+        }
+    }
+    builder.build()
+}
+
+/// A module that is ready to be instantiated, by the interface it exports.
+enum GuestPre {
+    Http(RvmPre<RvmState>),
+    Run(lambda::LambdaPre<RvmState>),
+}
+
+/// An instance of a module, by the interface it exports.
+enum Guest {
+    Http(Rvm),
+    Run(lambda::Lambda),
+}
+
+/// Everything needed to start a fresh instance of a module.
+struct InstanceTemplate {
+    pre: GuestPre,
+    store: StoreTemplate,
+}
+
+/// Everything needed to create the store of a fresh instance.
+struct StoreTemplate {
+    engine: Engine,
+    host: HostComponent,
+    module_config: ModuleConfig,
+    stdio: GuestStdio,
+    budget: ResourceBudget,
+    inherit_env: bool,
+}
+
+impl StoreTemplate {
+    /// Creates a store for a fresh instance of the module, limited to its budget.
+    fn new_store(&self) -> Result<Store<RvmState>> {
+        let mut store = Store::new(
+            &self.engine,
+            RvmState {
+                host: self.host.clone(),
+                table: ResourceTable::new(),
+                wasi: wasi_ctx(&self.module_config, &self.stdio, self.inherit_env),
+                http: WasiHttpCtx::new(),
+                memory: self.budget.memory_limiter(),
+                cpu: CpuClock::default(),
+                budget: self.budget,
+            },
+        );
+        store.limiter(|state| &mut state.memory);
+        store.set_fuel(self.budget.fuel)?;
+        if self.host.calls.is_priced() {
+            store.call_hook(charge_host_calls);
+        }
+        store.set_epoch_deadline(self.budget.epoch_deadline());
+        if self.budget.max_cpu.is_some() {
+            store.epoch_deadline_callback(|store| {
+                let state = store.data();
+                state.budget.check_deadline(&state.cpu)
+            });
+        }
+        Ok(store)
+    }
+}
+
+/// Charges the fuel owed for a host function once it returns to the guest,
+/// which runs out of fuel if it can't pay.
+fn charge_host_calls(mut store: StoreContextMut<'_, RvmState>, hook: CallHook) -> Result<()> {
+    if !matches!(hook, CallHook::ReturningFromHost) {
+        return Ok(());
+    }
+    let owed = store.data_mut().host.calls.take_owed();
+    if owed == 0 {
+        return Ok(());
+    }
+    let fuel = store.get_fuel()?;
+    store.set_fuel(fuel.saturating_sub(owed))?;
+    if owed > fuel {
+        return Err(Trap::OutOfFuel.into());
+    }
+    Ok(())
+}
+
+impl InstanceTemplate {
+    /// Instantiates the module, warming it up first if it's configured to.
+    async fn start(&self) -> Result<(Store<RvmState>, Guest)> {
+        let mut store = self.store.new_store()?;
+        let guest = match &self.pre {
+            GuestPre::Http(pre) => {
+                let rvm = pre.instantiate_async(&mut store).await?;
+                if self.store.module_config.warmup {
+                    warm_up(&mut store, &rvm)
+                        .await
+                        .context("module failed to warm up")?;
+                }
+                Guest::Http(rvm)
+            }
+            GuestPre::Run(pre) => Guest::Run(pre.instantiate_async(&mut store).await?),
+        };
+        Ok((store, guest))
+    }
+}
+
+/// A module linked into another to provide some of its imports, see
+/// [`ModuleConfig::dependencies`]. It's instantiated on the first call, and
+/// again after a call fails, so a trap doesn't break the modules using it.
+pub struct Dependency {
+    pre: component::InstancePre<RvmState>,
+    store: StoreTemplate,
+    fuel: FuelMeter,
+    instance: tokio::sync::Mutex<Option<(Store<RvmState>, component::Instance)>>,
+}
+
+impl Dependency {
+    /// Prepares `component` to be instantiated with the host's imports only,
+    /// dependencies don't have dependencies of their own.
+    pub fn new(
+        host: HostComponent,
+        linker: &component::Linker<RvmState>,
+        component: &Component,
+        config: &RvmConfig,
+        module_config: ModuleConfig,
+        stdio: GuestStdio,
+        fuel: FuelMeter,
+    ) -> Result<Arc<Self>> {
+        check_wasi_versions(linker.engine(), component)
+            .with_context(|| format!("dependency `{}` can't be instantiated", host.key))?;
+        let pre = linker
+            .instantiate_pre(component)
+            .with_context(|| format!("dependency `{}` can't be instantiated", host.key))?;
+        Ok(Arc::new(Self {
+            store: StoreTemplate {
+                engine: pre.engine().clone(),
+                host,
+                stdio,
+                budget: ResourceBudget::new(&module_config, config),
+                module_config,
+                inherit_env: config.inherit_env,
+            },
+            pre,
+            fuel,
+            instance: tokio::sync::Mutex::new(None),
+        }))
+    }
+
+    /// Defines the interfaces `component` imports that this module exports in
+    /// `linker`, with functions forwarding to this module. Interfaces the host
+    /// already provides, or with resources, which can't cross instances, are
+    /// left alone. Returns the names of the linked interfaces.
+    pub fn link(
+        self: &Arc<Self>,
+        linker: &mut component::Linker<RvmState>,
+        component: &Component,
+    ) -> Result<Vec<String>> {
+        let engine = self.pre.engine();
+        let exports = self.pre.component().component_type();
+        let mut linked = Vec::new();
+        for (name, import) in component.component_type().imports(engine) {
+            let component::types::ComponentItem::ComponentInstance(import) = import else {
+                continue;
+            };
+            if exports.get_export(engine, name).is_none() {
+                continue;
+            }
+            let items: Vec<_> = import.exports(engine).collect();
+            if items
+                .iter()
+                .any(|(_, item)| matches!(item, component::types::ComponentItem::Resource(_)))
+            {
+                tracing::warn!(interface=%name, "Not linking an interface with resources");
+                continue;
+            }
+            // Already defined by the host
+            let Ok(mut instance) = linker.instance(name) else {
+                continue;
+            };
+            for (func, item) in items {
+                if !matches!(item, component::types::ComponentItem::ComponentFunc(_)) {
+                    continue;
+                }
+                let dependency = self.clone();
+                let (interface, func_name) = (name.to_owned(), func.to_owned());
+                instance.func_new_async(func, move |store, params, results| {
+                    let dependency = dependency.clone();
+                    let (interface, func_name) = (interface.clone(), func_name.clone());
+                    // The module waits on the dependency, which must not invoke it
+                    let host = &store.data().host;
+                    let chain = host.chain.through(&host.key);
+                    Box::new(async move {
+                        dependency
+                            .call(&interface, &func_name, chain, params, results)
+                            .await
+                    })
+                })?;
+            }
+            linked.push(name.to_owned());
+        }
+        Ok(linked)
+    }
+
+    /// Calls `func` of `interface`, with the module's budget.
+    async fn call(
+        &self,
+        interface: &str,
+        func: &str,
+        chain: CallChain,
+        params: &[component::Val],
+        results: &mut [component::Val],
+    ) -> Result<()> {
+        let key = &self.store.host.key;
+        // Resources belong to the instance that created them
+        if params.iter().any(holds_resource) {
+            anyhow::bail!("resources can't be passed to dependency `{key}`");
+        }
+        let mut instance = self.instance.lock().await;
+        if instance.is_none() {
+            let mut store = self.store.new_store()?;
+            let started = self.pre.instantiate_async(&mut store).await?;
+            *instance = Some((store, started));
+        }
+        let (store, started) = instance.as_mut().expect("instantiated above");
+        store.set_fuel(self.store.budget.fuel)?;
+        store.set_epoch_deadline(self.store.budget.epoch_deadline());
+        store.data().cpu.reset();
+        store.data_mut().host.chain = chain;
+        let called = async {
+            let func = started
+                .get_export(&mut *store, None, interface)
+                .and_then(|index| started.get_export(&mut *store, Some(&index), func))
+                .and_then(|index| started.get_func(&mut *store, index))
+                .with_context(|| {
+                    format!("dependency `{key}` doesn't export `{interface}#{func}`")
+                })?;
+            func.call_async(&mut *store, params, results).await?;
+            func.post_return_async(&mut *store).await
+        }
+        .await;
+        self.fuel
+            .record(self.store.budget.fuel.saturating_sub(store.get_fuel()?));
+        if called.is_err() {
+            *instance = None;
+        }
+        called.with_context(|| format!("dependency `{key}` failed"))?;
+        if results.iter().any(holds_resource) {
+            anyhow::bail!("dependency `{key}` returned a resource, which can't be passed on");
+        }
+        Ok(())
+    }
+}
+
+/// Whether `val` is, or contains, a resource.
+fn holds_resource(val: &component::Val) -> bool {
+    use component::Val;
+    match val {
+        Val::Resource(_) => true,
+        Val::List(vals) | Val::Tuple(vals) => vals.iter().any(holds_resource),
+        Val::Record(fields) => fields.iter().any(|(_, val)| holds_resource(val)),
+        Val::Variant(_, val) | Val::Option(val) => val.as_deref().is_some_and(holds_resource),
+        Val::Result(Ok(val) | Err(val)) => val.as_deref().is_some_and(holds_resource),
+        _ => false,
+    }
+}
+
+/// Sends a synthetic `GET /_rvm_warmup` through the module, so its hot paths
+/// are warm and it's known to respond. The fuel it uses is given back.
+async fn warm_up(store: &mut Store<RvmState>, rvm: &Rvm) -> Result<()> {
+    let fuel = store.get_fuel()?;
+    let request = hyper::Request::get("http://localhost/_rvm_warmup")
+        .body(Empty::new().map_err(|e| match e {}).boxed())?;
+    let req = store
+        .data_mut()
+        .new_incoming_request(Scheme::Http, request)?;
+    let (tx, rx) = oneshot::channel::<Result<hyper::Response<HyperOutgoingBody>, ErrorCode>>();
+    let out = store.data_mut().new_response_outparam(tx)?;
+
+    let call = rvm
+        .wasi_http_incoming_handler()
+        .call_handle(&mut *store, req, out);
+    let received = async {
+        match rx.await {
+            Ok(Ok(response)) => {
+                // Read the body so a streaming guest can finish
+                let _ = response.into_body().collect().await;
+                Ok(())
+            }
+            Ok(Err(code)) => Err(anyhow::anyhow!("guest responded with {code:?}")),
+            Err(_) => Err(anyhow::anyhow!("guest did not respond")),
+        }
+    };
+    let (called, received) = tokio::join!(call, received);
+    store.set_fuel(fuel)?;
+    called?;
+    received
+}
+
+/// The JSON body of `request`, which a `run` module is invoked with.
+async fn run_input(request: hyper::Request<InvokeBody>) -> Result<String> {
+    let input = request.into_body().collect().await?.to_bytes();
+    Ok(String::from_utf8(input.to_vec())?)
+}
+
+/// Invokes a `run` module with `input`, responding with the JSON it returns,
+/// or a `422` with its error message.
+async fn call_run(
+    store: &mut Store<RvmState>,
+    lambda: &lambda::Lambda,
+    input: &str,
+    tx: oneshot::Sender<Result<hyper::Response<HyperOutgoingBody>, ErrorCode>>,
 ) -> Result<()> {
+    let (status, body) = match lambda.rvm_lambda_run().call_run(store, input).await? {
+        Ok(output) => (StatusCode::OK, output),
+        Err(message) => (
+            StatusCode::UNPROCESSABLE_ENTITY,
+            serde_json::json!({ "error": message }).to_string(),
+        ),
+    };
+    let response = hyper::Response::builder()
+        .status(status)
+        .header(hyper::header::CONTENT_TYPE, "application/json")
+        .body(Full::new(Bytes::from(body)).map_err(|e| match e {}).boxed())?;
+    let _ = tx.send(Ok(response));
+    Ok(())
+}
 
-    let component = Component::from_binary(engine, &bytes)?;
-    let pre = RvmPre::new(linker.instantiate_pre(&component)?)?;
-
-    // Create a store with limited fuel
-    let mut store = Store::new(
-        pre.engine(),
-        RvmState {
-            host: HostComponent,
-            table: ResourceTable::new(),
-            wasi: WasiCtxBuilder::new().inherit_stdio().build(),
-            http: WasiHttpCtx::new(),
+/// Patch versions of WASI 0.2 the host provides. Imports of any of them are
+/// linked to the host's implementation of the newest.
+const WASI_PATCH_VERSIONS: std::ops::RangeInclusive<u64> = 0..=3;
+
+/// Refuses components importing WASI interfaces in a version the host doesn't
+/// provide, telling which are supported rather than which import failed to link.
+fn check_wasi_versions(engine: &Engine, component: &Component) -> Result<()> {
+    for (name, _) in component.component_type().imports(engine) {
+        let Some(version) = name
+            .strip_prefix("wasi:")
+            .and_then(|name| name.split_once('@'))
+        else {
+            continue;
+        };
+        let supported = version
+            .1
+            .strip_prefix("0.2.")
+            .and_then(|patch| patch.parse().ok())
+            .is_some_and(|patch| WASI_PATCH_VERSIONS.contains(&patch));
+        if !supported {
+            anyhow::bail!(
+                "the module imports `{name}`, but only WASI 0.2.{} through 0.2.{} are supported",
+                WASI_PATCH_VERSIONS.start(),
+                WASI_PATCH_VERSIONS.end(),
+            );
+        }
+    }
+    Ok(())
+}
+
+#[tracing::instrument(err, skip_all, fields(key = %host.key, hash = %host.hash))]
+pub async fn compile_and_start_instance_worker(
+    host: HostComponent,
+    linker: &wasmtime::component::Linker<RvmState>,
+    mut receiver: mpsc::Receiver<InvokeRequest>,
+    component: Arc<Component>,
+    config: &RvmConfig,
+    module_config: ModuleConfig,
+    output: ModuleOutput,
+) -> Result<tokio::task::JoinHandle<()>> {
+    let max_consecutive_failures = config.max_consecutive_failures;
+    let expose_fuel_headers = config.expose_fuel_headers;
+    let fuel_warning_percent = config.fuel_warning_percent;
+    let trap_statuses = config.trap_statuses.clone();
+    let max_response_bytes = module_config
+        .max_response_bytes
+        .or(config.max_response_bytes);
+    let recycle_after_requests = module_config
+        .recycle_after_requests
+        .or(config.recycle_after_requests)
+        .filter(|&requests| requests > 0);
+    let ModuleOutput {
+        fuel,
+        cpu: cpu_usage,
+        recycled,
+        stdio,
+        errors,
+    } = output;
+    let key = host.key.clone();
+    check_wasi_versions(linker.engine(), &component)?;
+    let instance_pre = linker.instantiate_pre(&component)?;
+    let template = InstanceTemplate {
+        store: StoreTemplate {
+            engine: instance_pre.engine().clone(),
+            host,
+            budget: ResourceBudget::new(&module_config, config),
+            module_config: module_config.clone(),
+            stdio: stdio.clone(),
+            inherit_env: config.inherit_env,
+        },
+        pre: match module_config.kind {
+            ModuleKind::Http => GuestPre::Http(RvmPre::new(instance_pre)?),
+            ModuleKind::Run => GuestPre::Run(lambda::LambdaPre::new(instance_pre)?),
         },
-    );
-    store.set_fuel(100_000_000)?;
+    };
 
     // Instantiate and listen for requests
-    let rvm = pre.instantiate_async(&mut store).await?;
-    tokio::spawn(async move {
+    let budget = template.store.budget;
+    let (mut store, mut guest) = template.start().await?;
+    let worker = tokio::spawn(async move {
+        let mut consecutive_failures = 0;
+        // Whether the guest was unwound by a trap, after which it can't be entered again
+        let mut trapped = false;
+        // Invocations served by the current instance
+        let mut served = 0;
         while let Some(request) = receiver.recv().await {
+            // A guest that keeps failing might have ended up in a broken state,
+            // so start over with a fresh instance.
+            let failing = trapped
+                || max_consecutive_failures > 0 && consecutive_failures >= max_consecutive_failures;
+            let worn = recycle_after_requests.is_some_and(|requests| served >= requests);
+            if failing || worn {
+                match (trapped, failing) {
+                    (true, _) => tracing::warn!(key=%key, "Restarting trapped module"),
+                    (false, true) => {
+                        tracing::warn!(key=%key, consecutive_failures, "Restarting failing module")
+                    }
+                    (false, false) => tracing::info!(key=%key, served, "Recycling module instance"),
+                }
+                match template.start().await {
+                    Ok((fresh, instance)) => {
+                        store = fresh;
+                        guest = instance;
+                        consecutive_failures = 0;
+                        trapped = false;
+                        served = 0;
+                        if !failing {
+                            recycled.fetch_add(1, Ordering::Relaxed);
+                        }
+                    }
+                    Err(e) => {
+                        tracing::error!(key=%key, "Failed to restart module, stopping worker: {e:?}");
+                        errors.record(format!("failed to instantiate: {e:#}"));
+                        break;
+                    }
+                }
+            }
+
+            served += 1;
             let uri = request.request.uri();
             tracing::info!(uri=%uri, "Invoking");
 
-            let req = store
-                .data_mut()
-                .new_incoming_request(Scheme::Http, request.request)
-                .unwrap();
-            let (tx, rx) =
+            let (tx, mut rx) =
                 oneshot::channel::<Result<hyper::Response<HyperOutgoingBody>, ErrorCode>>();
-            let out = store.data_mut().new_response_outparam(tx).unwrap();
+            let mut response = Some(request.response);
+
+            // Every invocation gets the module's fuel, or the budget overridden
+            // for it, however much the previous ones used.
+            store
+                .set_fuel(request.fuel.unwrap_or(budget.fuel))
+                .expect("fuel is enabled");
+            let report = request.report;
+            let span = request.span;
+            if report.is_some() {
+                stdio.start_capture();
+            }
+            let started = std::time::Instant::now();
+            let fuel_before = store.get_fuel().expect("fuel is enabled");
+            let budget = ResourceBudget {
+                timeout: request.timeout.or(budget.timeout),
+                ..budget
+            };
+            store.data_mut().budget = budget;
+            store.data_mut().host.chain = request
+                .request
+                .extensions()
+                .get::<CallChain>()
+                .cloned()
+                .unwrap_or_default();
+            store.set_epoch_deadline(budget.epoch_deadline());
+            let cpu = store.data().cpu.clone();
+            cpu.reset();
 
-            let fuel_before = store.get_fuel().unwrap();
+            // If the guest sets a response and keeps running, it is still producing
+            // the body, so hand the response over right away and let it stream to
+            // the client. Guests that finish first get a buffered response instead.
+            // Failures before the guest is called leave it as it was
+            let (resp, streamed, entered) = match &guest {
+                Guest::Run(lambda) => match run_input(request.request).await {
+                    Err(e) => (Err(e), None, false),
+                    Ok(input) => (
+                        cpu.time(call_run(&mut store, lambda, &input, tx)).await,
+                        None,
+                        true,
+                    ),
+                },
+                Guest::Http(rvm) => {
+                    // Failing to hand the request over fails the invocation like a host error would
+                    let handed = store
+                        .data_mut()
+                        .new_incoming_request(request.scheme, request.request)
+                        .and_then(|req| Ok((req, store.data_mut().new_response_outparam(tx)?)));
+                    match handed {
+                        Err(e) => (Err(e), None, false),
+                        Ok((req, out)) => {
+                            let call = cpu.time(
+                                rvm.wasi_http_incoming_handler()
+                                    .call_handle(&mut store, req, out),
+                            );
+                            let (resp, streamed) = stream_early(call, &mut rx, |resp| {
+                                let (fuel_tx, fuel_rx) = oneshot::channel();
+                                if let Some(response) = response.take() {
+                                    let resp = resp
+                                        .map(|r| LimitedBody::wrap(r, max_response_bytes, &key))
+                                        .map(|r| match expose_fuel_headers {
+                                            true => FuelTrailerBody::wrap(r, fuel_rx),
+                                            false => r,
+                                        })
+                                        .map(forward_trailers)
+                                        .map_err(InvokeError::Guest);
+                                    let _ = response.send(resp);
+                                }
+                                fuel_tx
+                            })
+                            .await;
+                            (resp, streamed, true)
+                        }
+                    }
+                }
+            };
+            let fuel_after = store.get_fuel().expect("fuel is enabled");
+            fuel.record(fuel_before.saturating_sub(fuel_after));
+            let (cpu_time, _) = cpu.elapsed();
+            cpu_usage.record(cpu_time);
+            span.record("fuel_consumed", fuel_before.saturating_sub(fuel_after));
+            let streamed = streamed.map(|streamed| {
+                streamed.map(|fuel_tx| {
+                    let _ = fuel_tx.send(fuel_before.saturating_sub(fuel_after));
+                })
+            });
+            // Tells invocations that got close to running out of fuel apart,
+            // before they start getting cut off
+            let consumed = fuel_before.saturating_sub(fuel_after);
+            let used_percent = (consumed as u128 * 100 / fuel_before.max(1) as u128) as u64;
+            let fuel_warning = resp.is_ok()
+                && fuel_warning_percent.is_some_and(|threshold| used_percent >= threshold);
+            if fuel_warning {
+                tracing::warn!(
+                    fuel_consumed = consumed,
+                    used_percent,
+                    "Close to running out of fuel"
+                );
+            }
 
-            let resp = rvm
-                .wasi_http_incoming_handler()
-                .call_handle(&mut store, req, out)
-                .await;
+            if let Some(report) = report {
+                let (stdout, stderr) = stdio.end_capture();
+                let _ = report.send(InvocationReport {
+                    stdout: String::from_utf8_lossy(&stdout).into_owned(),
+                    stderr: String::from_utf8_lossy(&stderr).into_owned(),
+                    fuel_consumed: fuel_before.saturating_sub(fuel_after),
+                    wall_time_ms: started.elapsed().as_millis(),
+                    cpu_time_ms: cpu_time.as_millis(),
+                    memory_bytes: store.data().memory.peak_bytes(),
+                    trap: resp.as_ref().err().map(|e| format!("{e:?}")),
+                });
+            }
 
             if let Err(e) = resp {
-                if matches!(e.downcast::<Trap>(), Ok(Trap::OutOfFuel)) {
-                    tracing::warn!("Fuel exhausted")
+                consecutive_failures += 1;
+                trapped = entered;
+                let error = InvokeError::from_call_error(e, &trap_statuses);
+                match &error {
+                    InvokeError::Trap {
+                        code: "out-of-fuel",
+                        ..
+                    } => {
+                        tracing::warn!("Fuel exhausted");
+                        errors.record("out of fuel");
+                    }
+                    InvokeError::Trap { code, reason, .. } => {
+                        tracing::warn!(reason=%reason, "Guest trapped");
+                        errors.record(format!("{code}: {reason}"));
+                    }
+                    InvokeError::Guest(_) => {}
+                }
+                // A streamed response has already been sent, its body is cut short.
+                if let Some(response) = response {
+                    let _ = response.send(Err(error));
                 }
-                let _ = request.response.send(Err(ErrorCode::ConfigurationError));
                 continue;
             };
 
-            if let Ok(resp) = rx.await {
-                let _ = request.response.send(resp.map(|mut r| {
-                    let fuel_after = store.get_fuel().unwrap();
-                    r.headers_mut()
-                        .append("x-rvm-fuel-remaining", fuel_after.into());
-                    r.headers_mut().append(
-                        "x-rvm-fuel-consumed",
-                        fuel_before.saturating_sub(fuel_after).into(),
-                    );
+            let received = match streamed {
+                Some(Ok(())) => {
+                    consecutive_failures = 0;
+                    continue;
+                }
+                Some(Err(e)) => Err(e),
+                None => rx.await,
+            };
+            match received {
+                Ok(resp) => {
+                    consecutive_failures = 0;
+                    if let Err(code) = &resp {
+                        errors.record(format!("guest responded with an error: {code:?}"));
+                    }
+                    let resp = resp.map_err(InvokeError::Guest);
+                    let Some(response) = response else { continue };
+                    let wall_time_ms = started.elapsed().as_millis() as u64;
+                    let memory_bytes = store.data().memory.peak_bytes();
+                    let usage = Usage {
+                        fuel_remaining: fuel_after,
+                        fuel_consumed: fuel_before.saturating_sub(fuel_after),
+                        wall_time_ms,
+                        cpu_time_ms: cpu_time.as_millis() as u64,
+                        memory_bytes,
+                    };
+                    let _ = response.send(resp.map(|r| {
+                        let mut r = LimitedBody::wrap(r, max_response_bytes, &key);
+                        usage.append_headers(r.headers_mut(), expose_fuel_headers);
+                        if fuel_warning {
+                            r.headers_mut()
+                                .insert("x-rvm-fuel-warning", used_percent.into());
+                        }
 
-                    r
-                }));
+                        forward_trailers(r)
+                    }));
+                }
+                // The guest returned without ever setting a response.
+                Err(_) => {
+                    consecutive_failures += 1;
+                    errors.record("returned without setting a response");
+                }
             }
         }
     });
-    Ok(())
+    Ok(worker)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const USAGE_HEADERS: [&str; 5] = [
+        "x-rvm-fuel-remaining",
+        X_RVM_FUEL_CONSUMED,
+        "x-rvm-wall-time-ms",
+        "x-rvm-duration-ms",
+        "x-rvm-memory-bytes",
+    ];
+
+    fn usage() -> Usage {
+        Usage {
+            fuel_remaining: 900,
+            fuel_consumed: 100,
+            wall_time_ms: 3,
+            cpu_time_ms: 2,
+            memory_bytes: 65536,
+        }
+    }
+
+    #[test]
+    fn usage_headers_are_absent_unless_exposed() {
+        let mut headers = HeaderMap::new();
+        usage().append_headers(&mut headers, false);
+        for name in USAGE_HEADERS {
+            assert!(!headers.contains_key(name), "{name} was sent");
+        }
+    }
+
+    #[test]
+    fn usage_headers_are_present_when_exposed() {
+        let mut headers = HeaderMap::new();
+        usage().append_headers(&mut headers, true);
+        for name in USAGE_HEADERS {
+            assert!(headers.contains_key(name), "{name} is missing");
+        }
+        assert_eq!(headers["x-rvm-fuel-remaining"], "900");
+        assert_eq!(headers[X_RVM_FUEL_CONSUMED], "100");
+    }
+
+    /// A guest response that announced and ends with a `grpc-status` trailer.
+    fn response_with_trailer() -> hyper::Response<HyperOutgoingBody> {
+        let mut trailers = HeaderMap::new();
+        trailers.insert("grpc-status", "0".parse().unwrap());
+        let frames = futures::stream::iter([
+            Ok(Frame::data(Bytes::from_static(b"hello"))),
+            Ok(Frame::trailers(trailers)),
+        ]);
+        hyper::Response::builder()
+            .header(hyper::header::CONTENT_LENGTH, "5")
+            .header(hyper::header::TRAILER, "grpc-status")
+            .body(http_body_util::StreamBody::new(frames).boxed())
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn guest_trailers_reach_the_client_next_to_the_fuel_trailer() {
+        let (fuel_tx, fuel_rx) = oneshot::channel();
+        let response = forward_trailers(FuelTrailerBody::wrap(response_with_trailer(), fuel_rx));
+        fuel_tx.send(42).unwrap();
+
+        let announced: Vec<_> = response
+            .headers()
+            .get_all(hyper::header::TRAILER)
+            .iter()
+            .collect();
+        assert_eq!(announced, ["grpc-status", X_RVM_FUEL_CONSUMED]);
+        assert!(!response
+            .headers()
+            .contains_key(hyper::header::CONTENT_LENGTH));
+
+        let collected = response.into_body().collect().await.unwrap();
+        let trailers = collected.trailers().cloned().unwrap();
+        assert_eq!(trailers["grpc-status"], "0");
+        assert_eq!(trailers[X_RVM_FUEL_CONSUMED], "42");
+        assert_eq!(collected.to_bytes(), "hello");
+    }
+
+    #[tokio::test]
+    async fn responses_set_before_the_guest_returns_stream_to_the_client() {
+        let (set_response, mut response) = oneshot::channel();
+        let (acks, mut acked) = mpsc::unbounded_channel();
+        // Only writes the next chunk once the client got the previous one
+        let guest = async move {
+            let (mut frames, body) = futures::channel::mpsc::channel(0);
+            let streamed: HyperOutgoingBody = http_body_util::StreamBody::new(body).boxed();
+            let _ = set_response.send(hyper::Response::new(streamed));
+            for chunk in ["first", "second"] {
+                futures::SinkExt::send(&mut frames, Ok(Frame::data(Bytes::from(chunk))))
+                    .await
+                    .unwrap();
+                acked.recv().await.unwrap();
+            }
+            "returned"
+        };
+        let (to_client, client_response) = oneshot::channel();
+        let client = tokio::spawn(async move {
+            let mut body = client_response
+                .await
+                .map(hyper::Response::<HyperOutgoingBody>::into_body)
+                .unwrap();
+            let mut received = Vec::new();
+            while let Some(frame) = body.frame().await {
+                received.push(frame.unwrap().into_data().unwrap());
+                let _ = acks.send(());
+            }
+            received
+        });
+
+        let streamed = stream_early(guest, &mut response, |resp| {
+            let _ = to_client.send(resp);
+        });
+        let (returned, streamed) =
+            tokio::time::timeout(std::time::Duration::from_secs(5), streamed)
+                .await
+                .expect("the response was held back until the guest returned");
+        assert_eq!(returned, "returned");
+        assert!(matches!(streamed, Some(Ok(()))));
+        assert_eq!(client.await.unwrap(), ["first", "second"]);
+    }
+
+    #[tokio::test]
+    async fn responses_of_guests_that_returned_are_left_to_buffer() {
+        let (set_response, mut response) = oneshot::channel();
+        let guest = async move {
+            let _ = set_response.send(hyper::Response::new(()));
+            "returned"
+        };
+        let (returned, streamed) = stream_early(guest, &mut response, |_| ()).await;
+        assert_eq!(returned, "returned");
+        assert!(streamed.is_none());
+        assert!(response.await.is_ok());
+    }
 }
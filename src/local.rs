@@ -0,0 +1,181 @@
+use std::{str::FromStr, time::Duration};
+
+use anyhow::{Context as _, Result};
+use http_body_util::{BodyExt, Full};
+use hyper::http::{uri::PathAndQuery, HeaderName, HeaderValue, Method, Uri};
+use tokio::sync::{mpsc, oneshot};
+use wasmtime_wasi_http::{bindings::http::types::Scheme, body::HyperOutgoingBody};
+
+use crate::{
+    budget::ResourceBudget,
+    error::RvmError,
+    host::{
+        rvm::lambda::host::{LocalRequest, LocalResponse},
+        InvokeBody,
+    },
+    services,
+    state::SharedState,
+    tenant,
+};
+
+/// The modules waiting on an invocation, by invoking each other with
+/// `invoke-local`, outermost first. Kept in the extensions of the request,
+/// where clients can't set it.
+#[derive(Debug, Clone, Default)]
+pub struct CallChain(Vec<String>);
+
+impl CallChain {
+    /// Whether `key` is waiting on the invocation. Its worker is busy until
+    /// the invocation returns, so invoking it again would never be answered.
+    pub fn contains(&self, key: &str) -> bool {
+        self.0.iter().any(|waiting| waiting == key)
+    }
+
+    /// The chain of the invocations `key` makes while invoked through this one.
+    pub fn through(&self, key: &str) -> CallChain {
+        let mut chain = self.clone();
+        chain.0.push(key.to_owned());
+        chain
+    }
+}
+
+/// An invocation of a module by another, waiting to be dispatched.
+pub struct LocalInvocation {
+    key: String,
+    request: hyper::Request<InvokeBody>,
+    response: oneshot::Sender<hyper::Response<HyperOutgoingBody>>,
+}
+
+/// Hands the invocations modules make of each other to the host, which
+/// invokes them like those of clients, see [`spawn_dispatcher`].
+#[derive(Clone)]
+pub struct LocalInvoker {
+    invocations: mpsc::UnboundedSender<LocalInvocation>,
+    max_call_depth: usize,
+}
+
+impl LocalInvoker {
+    pub fn new(invocations: mpsc::UnboundedSender<LocalInvocation>, max_call_depth: usize) -> Self {
+        Self {
+            invocations,
+            max_call_depth,
+        }
+    }
+
+    /// Invokes `key` on behalf of `caller`, which is invoked through `chain`.
+    /// Modules of a tenant can only invoke the modules of the same tenant.
+    pub async fn invoke(
+        &self,
+        caller: &str,
+        chain: &CallChain,
+        key: &str,
+        request: LocalRequest,
+    ) -> Result<LocalResponse> {
+        anyhow::ensure!(
+            chain.0.len() < self.max_call_depth,
+            "invocations can pass through at most {} modules",
+            self.max_call_depth
+        );
+        anyhow::ensure!(tenant::is_key(key), "invalid key `{key}`");
+        let key = match caller.split_once('/') {
+            Some((tenant, _)) if tenant::is_tenant(tenant) => format!("{tenant}/{key}"),
+            _ => key.to_owned(),
+        };
+
+        let mut req = hyper::Request::new(Full::from(request.body).map_err(|e| match e {}).boxed());
+        if !request.method.is_empty() {
+            *req.method_mut() = Method::from_str(&request.method).context("invalid method")?;
+        }
+        if !request.path.is_empty() {
+            *req.uri_mut() = PathAndQuery::from_str(&request.path)
+                .map(Uri::from)
+                .context("invalid path")?;
+        }
+        for (name, value) in request.headers {
+            let name =
+                HeaderName::from_str(&name).with_context(|| format!("invalid header {name}"))?;
+            let value = HeaderValue::from_bytes(&value)
+                .with_context(|| format!("invalid value for header {name}"))?;
+            req.headers_mut().append(name, value);
+        }
+        // Guests can't be handed a request without an authority
+        if !req.headers().contains_key(hyper::header::HOST) {
+            req.headers_mut()
+                .insert(hyper::header::HOST, HeaderValue::from_static("localhost"));
+        }
+        req.extensions_mut().insert(chain.through(caller));
+
+        let (tx, rx) = oneshot::channel();
+        self.invocations
+            .send(LocalInvocation {
+                key,
+                request: req,
+                response: tx,
+            })
+            .ok()
+            .context("the host is not taking invocations")?;
+        let response = rx.await.context("the invocation was dropped")?;
+
+        let (parts, body) = response.into_parts();
+        let body = body
+            .collect()
+            .await
+            .map_err(|e| anyhow::anyhow!("failed to read the response: {e:?}"))?
+            .to_bytes();
+        let headers = parts
+            .headers
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.as_bytes().to_vec()))
+            .collect();
+        Ok(LocalResponse {
+            status: parts.status.as_u16(),
+            headers,
+            body: body.to_vec(),
+        })
+    }
+}
+
+/// How long an invocation of `key` by another module may wait for its response,
+/// if `key` has no timeout of its own bounding it.
+async fn local_wait(state: &SharedState, key: &str) -> Option<Duration> {
+    let state = state.read().await;
+    let timeout = state
+        .instances
+        .get(&state.resolve(key))
+        .and_then(|module| ResourceBudget::new(&module.config, &state.config).timeout);
+    let secs = state.config.local_invocation_timeout_secs;
+    (timeout.is_none() && secs > 0).then(|| Duration::from_secs(secs))
+}
+
+/// Invokes the modules that modules invoke, each invocation in a task of its
+/// own so a slow module doesn't hold up the others.
+pub fn spawn_dispatcher(
+    state: SharedState,
+    mut invocations: mpsc::UnboundedReceiver<LocalInvocation>,
+) {
+    tokio::spawn(async move {
+        while let Some(invocation) = invocations.recv().await {
+            let state = state.clone();
+            tokio::spawn(async move {
+                let LocalInvocation {
+                    key,
+                    request,
+                    response,
+                } = invocation;
+                tracing::info!(key=%key, "Invoking module from another module");
+                let wait = local_wait(&state, &key).await;
+                let invoked = services::invoke_module(&key, request, Scheme::Http, state, None);
+                let invoked = match wait {
+                    Some(wait) => tokio::time::timeout(wait, invoked).await.unwrap_or_else(|_| {
+                        tracing::warn!(key=%key, "Module invoked by another didn't answer within {wait:?}");
+                        Err(RvmError::Timeout(format!(
+                            "`{key}` didn't answer within {wait:?}, it may be waiting on the module invoking it"
+                        )))
+                    }),
+                    None => invoked.await,
+                };
+                let _ = response.send(invoked.unwrap_or_else(|e| e.into_invoke_response()));
+            });
+        }
+    });
+}
@@ -1,26 +1,72 @@
-use std::{str::FromStr, sync::Arc};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs::File,
+    io::BufReader,
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+    sync::Arc,
+    time::Duration,
+};
 
 use axum::{
     body::Bytes,
-    extract::{DefaultBodyLimit, Path, State},
+    extract::{DefaultBodyLimit, Multipart, Path, Query, RawQuery, State},
     handler::Handler,
-    http::{uri::PathAndQuery, StatusCode},
-    routing::post_service,
+    http::{uri::PathAndQuery, HeaderMap, HeaderName, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+    routing::{get, post, post_service},
     Json, Router,
 };
-use hyper::{server::conn::http1, Uri};
-use tokio::sync::{oneshot, RwLock};
-use tower_http::limit::RequestBodyLimitLayer;
+use http_body_util::BodyExt;
+use hyper::Uri;
+use hyper_util::rt::{TokioExecutor, TokioTimer};
+use tokio::sync::{oneshot, OwnedSemaphorePermit, RwLock, Semaphore};
+use tokio_rustls::{rustls, TlsAcceptor};
+use tower_http::{
+    decompression::RequestDecompressionLayer, limit::RequestBodyLimitLayer,
+    validate_request::ValidateRequestHeaderLayer,
+};
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 use wasmtime::*;
-use wasmtime_wasi_http::{bindings::http::types::ErrorCode, body::HyperOutgoingBody, io::TokioIo};
+use wasmtime_wasi_http::{bindings::http::types::Scheme, body::HyperOutgoingBody, io::TokioIo};
 
+mod audit;
+mod budget;
+mod cache;
+mod config;
+mod cors;
+mod dead_letter;
+mod error;
+mod fuel;
+mod grpc;
+mod health;
 mod host;
+mod local;
+mod manifest;
+mod metrics;
+mod otlp;
+mod rate_limit;
+mod scheduler;
+mod shutdown;
 mod state;
+mod stdio;
+mod tenant;
+mod transform;
 
+use crate::audit::{AuditAction, AuditEvent};
+use crate::budget::ResourceBudget;
+use crate::cache::{CacheKey, ResponseCache};
+use crate::config::{ModuleKind, RvmConfig};
+use crate::cors::CorsPolicy;
+use crate::dead_letter::{CapturedRequest, DeadLetter};
+use crate::error::{LimitExplained, RvmError};
+use crate::fuel::FuelSink;
 use crate::host::*;
+use crate::rate_limit::RateLimiter;
 use crate::state::*;
 
+const X_FORWARDED_FOR: HeaderName = HeaderName::from_static("x-forwarded-for");
+
 #[tokio::main]
 async fn main() {
     tracing_subscriber::registry()
@@ -29,13 +75,41 @@ async fn main() {
                 .unwrap_or_else(|_| format!("{}=debug", env!("CARGO_CRATE_NAME")).into()),
         )
         .with(tracing_subscriber::fmt::layer())
+        .with(
+            std::env::var("RVM_OTLP_ENDPOINT").ok().map(|endpoint| {
+                otlp::layer(&endpoint).expect("Failed to set up the OTLP exporter")
+            }),
+        )
         .init();
 
+    let config = RvmConfig::load().expect("Failed to load configuration");
+    let (stopped_workers, stopped) = tokio::sync::mpsc::unbounded_channel();
+    let (local_invocations, invocations) = tokio::sync::mpsc::unbounded_channel();
     let state = Arc::new(RwLock::new(
-        AppState::new().await.expect("failed to init state"),
+        AppState::new(config.clone(), stopped_workers, local_invocations)
+            .await
+            .expect("failed to init state"),
     ));
+    state::spawn_supervisor(state.clone(), stopped);
+    local::spawn_dispatcher(state.clone(), invocations);
+    let shutdown = state.read().await.shutdown.clone();
+    shutdown::spawn_signal_handler(shutdown.clone());
+    shutdown::spawn_drain(
+        state.clone(),
+        shutdown.clone(),
+        Duration::from_secs(config.shutdown_timeout_secs),
+    );
+    if config.fuel_sink == FuelSink::Storage {
+        fuel::spawn_flusher(
+            state.clone(),
+            std::time::Duration::from_secs(config.fuel_flush_interval_secs),
+        );
+    }
+    if let Some(secs) = config.idle_timeout_secs {
+        state::spawn_reaper(state.clone(), Duration::from_secs(secs));
+    }
 
-    let listener = tokio::net::TcpListener::bind("127.0.0.1:8000")
+    let listener = tokio::net::TcpListener::bind(config.proxy_addr)
         .await
         .expect("Failed to setup listener");
     tracing::info!(
@@ -44,76 +118,172 @@ async fn main() {
     );
 
     // Start a hyper server to listen for invokations
-    let service_fn = move |state: SharedState| {
-        hyper::service::service_fn(move |mut req| {
+    let default_module = config.default_module.clone();
+    let service_fn = move |state: SharedState, scheme: Scheme, addr: SocketAddr| {
+        let default_module = default_module.clone();
+        hyper::service::service_fn(move |req: hyper::Request<hyper::body::Incoming>| {
             let state = state.clone();
+            let default_module = default_module.clone();
+            let mut req = req.map(|body| body.boxed());
+            append_forwarded_for(req.headers_mut(), addr.ip());
+            // Prefer the scheme reported by a TLS-terminating proxy in front of us
+            let scheme = forwarded_scheme(req.headers()).unwrap_or_else(|| scheme.clone());
+            set_authority(&mut req);
             async move {
                 // Strip the first part of the path and use it as the identifier for the instance.
                 // A real app should probably use a host and subdomain to specify module.
                 let mut uri_parts = req.uri().clone().into_parts();
-                if let Some(path_and_query) = &mut uri_parts.path_and_query {
-                    let path_and_query_string = path_and_query.to_string();
-                    let (key, forward) = path_and_query_string
-                        .trim_start_matches('/')
-                        .split_once('/')
-                        .map(|(p, q)| (p.to_owned(), q.to_owned()))
-                        .unwrap_or_else(|| {
-                            (
-                                path_and_query.path().to_owned(),
-                                path_and_query.query().unwrap_or("").to_owned(),
-                            )
-                        });
-                    let Ok(new_uri) = PathAndQuery::from_str(&format!("/{forward}"))
-                        .map_err(|_| StatusCode::BAD_REQUEST)
-                        .and_then(|q| {
-                            uri_parts.path_and_query = Some(q);
-                            Uri::from_parts(uri_parts).map_err(|_| StatusCode::BAD_REQUEST)
-                        })
-                    else {
+                let route = uri_parts
+                    .path_and_query
+                    .as_ref()
+                    .and_then(split_key_and_forward);
+                let route = match route.map(|(key, forward)| {
+                    tenant::scoped_key(req.headers(), &key).map(|key| (key, forward))
+                }) {
+                    Some(Err(e)) => {
                         return hyper::Response::builder()
-                            .status(StatusCode::BAD_REQUEST)
-                            .body(Default::default());
-                    };
-                    *req.uri_mut() = new_uri;
-
-                    tracing::info!(key=%key, "Invoking module");
-                    return match services::invoke_module(&key, req, state).await {
-                        Ok(ok) => Ok(ok),
-                        Err(code) => hyper::Response::builder()
-                            .status(code)
-                            .body(Default::default()),
+                            .status(e.status())
+                            .body(Default::default())
+                    }
+                    route => route.and_then(Result::ok),
+                };
+
+                // Requests that don't match a deployed module go to the default module,
+                // with the original path untouched.
+                if let Some(default_module) = &default_module {
+                    let deployed = match &route {
+                        Some((key, _)) => state.read().await.is_routable(key),
+                        None => false,
                     };
+                    if !deployed {
+                        tracing::info!(key=%default_module, "Invoking default module");
+                        return respond(
+                            services::invoke_module(default_module, req, scheme, state, None).await,
+                        );
+                    }
                 }
 
-                hyper::Response::builder()
-                    .status(StatusCode::BAD_REQUEST)
-                    .body(Default::default())
+                let Some((key, forward)) = route else {
+                    return hyper::Response::builder()
+                        .status(StatusCode::BAD_REQUEST)
+                        .body(Default::default());
+                };
+                let Ok(new_uri) = PathAndQuery::from_str(&forward)
+                    .map_err(|_| StatusCode::BAD_REQUEST)
+                    .and_then(|q| {
+                        uri_parts.path_and_query = Some(q);
+                        Uri::from_parts(uri_parts).map_err(|_| StatusCode::BAD_REQUEST)
+                    })
+                else {
+                    return hyper::Response::builder()
+                        .status(StatusCode::BAD_REQUEST)
+                        .body(Default::default());
+                };
+                *req.uri_mut() = new_uri;
+
+                tracing::info!(key=%key, "Invoking module");
+                respond(services::invoke_module(&key, req, scheme, state, None).await)
             }
         })
     };
+    let tls = match (&config.tls_cert, &config.tls_key) {
+        (Some(cert), Some(key)) => {
+            Some(tls_acceptor(cert, key, config.http2).expect("Failed to load TLS certificate"))
+        }
+        (None, None) => None,
+        _ => panic!("RVM_TLS_CERT and RVM_TLS_KEY must be set together"),
+    };
+    let mut http = hyper_util::server::conn::auto::Builder::new(TokioExecutor::new());
+    if !config.http2 {
+        http = http.http1_only();
+    }
+    http.http1()
+        .keep_alive(config.keep_alive)
+        .max_headers(config.max_headers)
+        .timer(TokioTimer::new())
+        .header_read_timeout(Duration::from_secs(config.header_read_timeout_secs));
+    http.http2().timer(TokioTimer::new());
+    let connections = Arc::new(Semaphore::new(config.max_connections));
     let state_clone = state.clone();
     let serve_proxy = async move {
         let state = state_clone;
         loop {
-            let (client, addr) = listener
-                .accept()
-                .await
-                .expect("failed to accept connection");
+            // New connections are refused once shutting down
+            let (client, addr) = tokio::select! {
+                accepted = listener.accept() => accepted.expect("failed to accept connection"),
+                _ = shutdown.started() => break,
+            };
+            // Close connections beyond the limit, rather than letting them queue up
+            let Ok(permit) = connections.clone().try_acquire_owned() else {
+                tracing::warn!("Too many connections, closing client[{addr}]");
+                continue;
+            };
             let state = state.clone();
+            let tls = tls.clone();
+            let service_fn = service_fn.clone();
+            let http = http.clone();
             tokio::task::spawn(async move {
-                if let Err(e) = http1::Builder::new()
-                    .keep_alive(true)
-                    .serve_connection(TokioIo::new(client), service_fn(state))
-                    .await
-                {
+                let _permit = permit;
+                let result = match tls {
+                    Some(acceptor) => {
+                        let stream = match acceptor.accept(client).await {
+                            Ok(stream) => stream,
+                            Err(e) => {
+                                tracing::warn!("TLS handshake with client[{addr}] failed: {e}");
+                                return;
+                            }
+                        };
+                        http.serve_connection(
+                            TokioIo::new(stream),
+                            service_fn(state, Scheme::Https, addr),
+                        )
+                        .await
+                    }
+                    None => {
+                        http.serve_connection(
+                            TokioIo::new(client),
+                            service_fn(state, Scheme::Http, addr),
+                        )
+                        .await
+                    }
+                };
+                if let Err(e) = result {
                     tracing::error!("error serving client[{addr}]: {e:?}");
                 }
             });
         }
+        Ok(())
+    };
+
+    // Start a gRPC server, if configured, to also listen for invokations
+    let serve_grpc = match config.grpc_addr {
+        Some(addr) => {
+            let listener = tokio::net::TcpListener::bind(addr)
+                .await
+                .expect("Failed to setup gRPC listener");
+            tracing::info!(
+                "Listening for gRPC invokations on {}",
+                listener.local_addr().expect("Failed to listen on addres")
+            );
+            let shutdown = state.read().await.shutdown.clone();
+            Some(grpc::serve(
+                listener,
+                state.clone(),
+                shutdown,
+                config.grpc_max_message_bytes,
+            ))
+        }
+        None => None,
+    };
+    let serve_grpc = async move {
+        match serve_grpc {
+            Some(serve) => serve.await,
+            None => Ok(()),
+        }
     };
 
     // Start an axum server to act as an admin service
-    let listener_axum = tokio::net::TcpListener::bind("127.0.0.1:8002")
+    let listener_axum = tokio::net::TcpListener::bind(config.admin_addr)
         .await
         .unwrap();
 
@@ -125,90 +295,2358 @@ async fn main() {
     );
 
     // build our application with a route
-    let app = Router::new().route(
-        "/deploy/{key}",
-        post_service(
-            services::deploy_module
-                .layer((
-                    DefaultBodyLimit::disable(),
-                    RequestBodyLimitLayer::new(1024 * 256_000 /* ~256mb */),
-                ))
-                .with_state(state),
+    let max_deploy_bytes = config.max_deploy_bytes;
+    let app = Router::new()
+        .route(
+            "/deploy/{key}",
+            post_service(
+                services::deploy_module
+                    .layer((
+                        DefaultBodyLimit::disable(),
+                        RequestDecompressionLayer::new(),
+                        RequestBodyLimitLayer::new(max_deploy_bytes),
+                    ))
+                    .with_state(state.clone()),
+            )
+            .layer(axum::middleware::map_response(move |response: Response| {
+                services::explain_payload_too_large(max_deploy_bytes, response)
+            })),
+        )
+        .route(
+            "/deploy-batch",
+            post_service(
+                services::deploy_batch
+                    .layer((
+                        DefaultBodyLimit::disable(),
+                        RequestDecompressionLayer::new(),
+                        RequestBodyLimitLayer::new(max_deploy_bytes),
+                    ))
+                    .with_state(state.clone()),
+            )
+            .layer(axum::middleware::map_response(move |response: Response| {
+                services::explain_payload_too_large(max_deploy_bytes, response)
+            })),
+        )
+        .route("/deploy-from-url/{key}", post(services::deploy_from_url))
+        .route("/reload-all", post(services::reload_all))
+        .route("/gc", post(services::gc))
+        .route("/audit", get(services::audit_log))
+        .route("/invoke-debug/{key}", post(services::invoke_debug))
+        .route("/rpc/{key}", post(services::invoke_rpc))
+        .route("/rollback/{key}/{hash}", post(services::rollback_module))
+        .route("/alias/{name}", post(services::set_alias))
+        .route("/metrics", get(services::metrics))
+        .route("/healthz", get(services::healthz))
+        .route("/version", get(services::version))
+        .route("/modules/{key}", get(services::module_details))
+        .route("/modules/{key}/versions", get(services::list_versions))
+        .route("/modules/{key}/logs", get(services::module_logs))
+        .route("/modules/{key}/artifact", get(services::module_artifact))
+        .route("/modules/{key}/selftest", post(services::selftest_module))
+        .route(
+            "/modules/{key}/dead-letters",
+            get(services::list_dead_letters),
+        )
+        .route(
+            "/modules/{key}/replay/{id}",
+            post(services::replay_dead_letter),
+        )
+        .route("/modules/{key}/drain", post(services::drain_module))
+        .route("/modules/{key}/undrain", post(services::undrain_module));
+    let app = match &config.shutdown_token {
+        Some(token) => app.route(
+            "/shutdown",
+            post(services::shutdown).layer(ValidateRequestHeaderLayer::bearer(token)),
         ),
+        None => app,
+    };
+    let app = app.with_state(state);
+    // axum sets the `Allow` header outside of the router's own layers
+    let app = tower::ServiceExt::<axum::extract::Request>::map_response(
+        app,
+        services::explain_method_not_allowed,
     );
-    let serve_admin = axum::serve(listener_axum, app);
-    let (admin_res, proxy_res): (Result<(), std::io::Error>, Result<(), std::io::Error>) =
-        tokio::join!(serve_admin, serve_proxy);
+    let serve_admin = axum::serve(listener_axum, tower::make::Shared::new(app));
+    let (admin_res, proxy_res, grpc_res): (
+        Result<(), std::io::Error>,
+        Result<(), std::io::Error>,
+        Result<(), tonic::transport::Error>,
+    ) = tokio::join!(serve_admin, serve_proxy, serve_grpc);
     admin_res.expect("admin service failed");
     proxy_res.expect("invoke service failed");
+    grpc_res.expect("gRPC service failed");
+}
+
+/// Turns the outcome of an invocation into the response for the client.
+fn respond(
+    result: Result<hyper::Response<HyperOutgoingBody>, RvmError>,
+) -> hyper::http::Result<hyper::Response<HyperOutgoingBody>> {
+    Ok(result.unwrap_or_else(RvmError::into_invoke_response))
+}
+
+/// Loads the TLS configuration of the proxy from PEM encoded files.
+fn tls_acceptor(
+    cert: &std::path::Path,
+    key: &std::path::Path,
+    http2: bool,
+) -> anyhow::Result<TlsAcceptor> {
+    let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(cert)?))
+        .collect::<Result<Vec<_>, _>>()?;
+    let key = rustls_pemfile::private_key(&mut BufReader::new(File::open(key)?))?
+        .ok_or_else(|| anyhow::anyhow!("no private key found in {}", key.display()))?;
+    let mut config = rustls::ServerConfig::builder_with_provider(Arc::new(
+        rustls::crypto::ring::default_provider(),
+    ))
+    .with_safe_default_protocol_versions()?
+    .with_no_client_auth()
+    .with_single_cert(certs, key)?;
+    if http2 {
+        config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    }
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// The scheme a client used to reach a proxy in front of us, taken from the
+/// first `X-Forwarded-Proto` value.
+fn forwarded_scheme(headers: &HeaderMap) -> Option<Scheme> {
+    let proto = headers.get("x-forwarded-proto")?.to_str().ok()?;
+    let proto = proto.split(',').next()?.trim();
+    Some(match proto.to_ascii_lowercase().as_str() {
+        "http" => Scheme::Http,
+        "https" => Scheme::Https,
+        "" => return None,
+        other => Scheme::Other(other.to_owned()),
+    })
+}
+
+/// Moves the authority the client used to the `Host` header, which is where
+/// guests read it from: the first `X-Forwarded-Host` value of a proxy in front
+/// of us, or else the authority of the URI, as sent over HTTP/2, or the `Host` header.
+fn set_authority(req: &mut hyper::Request<InvokeBody>) {
+    let forwarded = req
+        .headers()
+        .get("x-forwarded-host")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|hosts| hosts.split(',').next())
+        .map(str::trim)
+        .filter(|host| !host.is_empty())
+        .and_then(|host| HeaderValue::from_str(host).ok());
+    let from_uri = req
+        .uri()
+        .authority()
+        .and_then(|authority| HeaderValue::from_str(authority.as_str()).ok());
+    if let Some(authority) = forwarded.or(from_uri) {
+        req.headers_mut().insert(hyper::header::HOST, authority);
+    }
+    // Otherwise the URI's authority would take precedence over the header
+    if let Some(path_and_query) = req.uri().path_and_query().cloned() {
+        *req.uri_mut() = Uri::from(path_and_query);
+    }
+}
+
+/// Adds the address of the client to `X-Forwarded-For`, after any addresses
+/// already listed by proxies in front of us.
+fn append_forwarded_for(headers: &mut HeaderMap, client: IpAddr) {
+    let mut forwarded_for: Vec<String> = headers
+        .get_all(X_FORWARDED_FOR)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .map(str::to_owned)
+        .collect();
+    forwarded_for.push(client.to_string());
+    if let Ok(value) = HeaderValue::from_str(&forwarded_for.join(", ")) {
+        headers.insert(X_FORWARDED_FOR, value);
+    }
+}
+
+/// Splits the path of an invocation into the key of the module to invoke and
+/// the path and query to forward to it.
+///
+/// The first path segment is the key, and everything after it is forwarded,
+/// always starting with a `/`:
+/// * `/` has no key, so `None` is returned.
+/// * `/key` and `/key/` both forward `/`.
+/// * `/key?q=1` forwards `/?q=1`.
+/// * `/key/rest?q=1` forwards `/rest?q=1`.
+fn split_key_and_forward(path_and_query: &PathAndQuery) -> Option<(String, String)> {
+    let path = path_and_query.path();
+    let path = path.strip_prefix('/').unwrap_or(path);
+    let (key, rest) = path.split_once('/').unwrap_or((path, ""));
+    if key.is_empty() {
+        return None;
+    }
+
+    let forward = match path_and_query.query() {
+        Some(query) => format!("/{rest}?{query}"),
+        None => format!("/{rest}"),
+    };
+    Some((key.to_owned(), forward))
 }
 
 mod services {
+    use std::sync::atomic::Ordering;
+    use tokio::sync::mpsc::error::TrySendError;
+
     use super::*;
 
-    #[tracing::instrument(skip(state, request))]
+    const X_RVM_REQUEST_ID: &str = "x-rvm-request-id";
+    const X_RVM_KEY: &str = "x-rvm-key";
+
+    #[tracing::instrument(
+        skip(state, request, report),
+        fields(
+            status = tracing::field::Empty,
+            fuel_consumed = tracing::field::Empty,
+            request_id = tracing::field::Empty
+        )
+    )]
     pub async fn invoke_module(
         key: &str,
-        request: hyper::Request<hyper::body::Incoming>,
+        request: hyper::Request<InvokeBody>,
+        scheme: Scheme,
         state: SharedState,
-    ) -> Result<hyper::Response<HyperOutgoingBody>, StatusCode> {
-        let (tx, rx) = oneshot::channel::<Result<hyper::Response<HyperOutgoingBody>, ErrorCode>>();
+        report: Option<oneshot::Sender<InvocationReport>>,
+    ) -> Result<hyper::Response<HyperOutgoingBody>, RvmError> {
+        let response = invoke(key, request, scheme, state, report).await;
+        // Recorded on every way out, refused invocations included
+        let status = match &response {
+            Ok(response) => response.status(),
+            Err(e) => e.status(),
+        };
+        tracing::Span::current().record("status", status.as_u16());
+        response
+    }
+
+    /// Invokes `key` for [`invoke_module`], within its span.
+    async fn invoke(
+        key: &str,
+        request: hyper::Request<InvokeBody>,
+        scheme: Scheme,
+        state: SharedState,
+        report: Option<oneshot::Sender<InvocationReport>>,
+    ) -> Result<hyper::Response<HyperOutgoingBody>, RvmError> {
+        // Guests can't be handed a request without an authority
+        if !request.headers().contains_key(hyper::header::HOST)
+            && request.uri().authority().is_none()
         {
+            return Err(RvmError::BadRequest(String::from(
+                "the request has no host",
+            )));
+        }
+        let key = {
             let state = state.read().await;
-            let state = state.instances.get(key).ok_or(StatusCode::NOT_FOUND)?;
-            state
-                .send(InvokeRequest {
-                    response: tx,
-                    request,
+            if state.shutdown.is_started() {
+                tracing::warn!("Shutting down, refusing invocation");
+                return Err(RvmError::Unavailable(String::from("shutting down")));
+            }
+            state.resolve(key)
+        };
+        let key = &key;
+        if let Some(chain) = request.extensions().get::<local::CallChain>() {
+            if chain.contains(key) {
+                tracing::warn!("Module invoked by a module waiting on it");
+                return Err(RvmError::BadRequest(format!(
+                    "`{key}` is waiting on this invocation, which it can't be invoked by"
+                )));
+            }
+        }
+        let idle = {
+            let state = state.read().await;
+            let module = state.instances.get(key).ok_or(RvmError::NotFound)?;
+            match module.readiness {
+                Readiness::Draining => {
+                    tracing::warn!("Module is draining");
+                    return Err(RvmError::Unavailable(String::from(
+                        "the module is draining",
+                    )));
+                }
+                Readiness::Starting => return Ok(starting()),
+                Readiness::Ready | Readiness::Failed => {}
+            }
+            *module.last_invoked.lock().unwrap() = std::time::Instant::now();
+            module.sender.is_none()
+        };
+        if idle {
+            // Started in its own task, so the module isn't left starting if
+            // the client gives up waiting
+            let waking = tokio::spawn({
+                let (state, key) = (state.clone(), key.clone());
+                async move { AppState::wake_module(&state, &key).await }
+            });
+            let running = waking
+                .await
+                .map_err(anyhow::Error::from)
+                .and_then(|woken| woken)
+                .map_err(|e| {
+                    tracing::error!("Failed to wake up module: {e:?}");
+                    RvmError::Unavailable(String::from("the module failed to start"))
+                })?;
+            if !running {
+                return Ok(starting());
+            }
+        }
+        let (
+            kind,
+            cors,
+            max_request_bytes,
+            transforms,
+            inject_request_id,
+            inject_key,
+            cache,
+            cache_ttl,
+            hash,
+            hash_encoding,
+            dead_letters,
+        ) = {
+            let state = state.read().await;
+            let module = state.instances.get(key).ok_or(RvmError::NotFound)?;
+            let cors = CorsPolicy::new(&module.config, &state.config);
+            let config = &state.config;
+            (
+                module.config.kind,
+                cors,
+                module.config.max_request_bytes.or(config.max_request_bytes),
+                state.transforms.clone(),
+                config.injects("request-id"),
+                config.injects("key"),
+                state.cache.clone(),
+                module.config.cache_ttl_secs.map(Duration::from_secs),
+                module.hash,
+                config.hash_encoding,
+                module
+                    .config
+                    .dead_letter
+                    .then(|| (state.storage.clone(), config.dead_letter_retention)),
+            )
+        };
+        // Preflights are answered here, the module only sees the actual request
+        if let Some(preflight) = cors
+            .as_ref()
+            .and_then(|cors| cors.preflight(request.method(), request.headers()))
+        {
+            return Ok(preflight);
+        }
+        let allowed_origin = cors
+            .as_ref()
+            .and_then(|cors| cors.allowed_origin(request.headers()));
+        let request = match max_request_bytes {
+            Some(limit) => limit_request_body(request, limit).await?,
+            None => request,
+        };
+        // Captured as it arrived, so a replay goes through the same steps again
+        let (request, mut dead_letter) = match dead_letters {
+            Some((storage, retention)) => {
+                let (parts, body) = request.into_parts();
+                let body = body.collect().await.map_err(read_failed)?.to_bytes();
+                let captured = CapturedRequest::new(&parts, &body);
+                let body = http_body_util::Full::new(body)
+                    .map_err(|e| match e {})
+                    .boxed();
+                (
+                    hyper::Request::from_parts(parts, body),
+                    Some((storage, retention, captured)),
+                )
+            }
+            None => (request, None),
+        };
+        let mut request = match kind {
+            ModuleKind::Http => request,
+            ModuleKind::Run => run_request(request).await?,
+        };
+        transforms.request(&mut request)?;
+        // Lets modules correlate what they log, a request ID from the client is kept
+        let request_id = inject_request_id.then(|| {
+            let request_id = request
+                .headers()
+                .get(X_RVM_REQUEST_ID)
+                .cloned()
+                .unwrap_or_else(|| {
+                    let id = uuid::Uuid::new_v4().to_string();
+                    id.parse().expect("valid header value")
+                });
+            request
+                .headers_mut()
+                .insert(X_RVM_REQUEST_ID, request_id.clone());
+            if let Ok(id) = request_id.to_str() {
+                tracing::Span::current().record("request_id", id);
+            }
+            request_id
+        });
+        request.headers_mut().remove(X_RVM_KEY);
+        if let (true, Ok(value)) = (inject_key, key.parse()) {
+            request.headers_mut().insert(X_RVM_KEY, value);
+        }
+
+        // Only safe methods are cached, and invocations that are reported on
+        // have to reach the guest, as do those with credentials
+        let cache_key = match (cache_ttl, &report) {
+            (Some(_), None)
+                if matches!(*request.method(), hyper::Method::GET | hyper::Method::HEAD)
+                    && ResponseCache::shareable(request.headers()) =>
+            {
+                let (parts, body) = request.into_parts();
+                let body = body.collect().await.map_err(read_failed)?.to_bytes();
+                let cache_key = CacheKey::new(key, hash, &parts.method, &parts.uri, &body);
+                let body = http_body_util::Full::new(body)
+                    .map_err(|e| match e {})
+                    .boxed();
+                request = hyper::Request::from_parts(parts, body);
+                Some(cache_key)
+            }
+            _ => None,
+        };
+        let cached = cache_key
+            .as_ref()
+            .and_then(|cache_key| cache.get(cache_key));
+
+        let (mut response, permit, clamped, hash) = match cached {
+            Some(response) => (response, None, Clamped::default(), hash),
+            None => {
+                // Waits for a turn without holding up deploys
+                let (scheduler, max_wait) = {
+                    let state = state.read().await;
+                    let max_wait = Duration::from_millis(state.config.max_invocation_wait_ms);
+                    (state.invocations.clone(), max_wait)
+                };
+                let permit = scheduler.acquire(key, max_wait).await.ok_or_else(|| {
+                    tracing::warn!("Too many concurrent invocations");
+                    RvmError::ModuleBusy
+                })?;
+
+                let (tx, rx) =
+                    oneshot::channel::<Result<hyper::Response<HyperOutgoingBody>, InvokeError>>();
+                let sent = {
+                    let state = state.read().await;
+                    let module = state.instances.get(key).ok_or(RvmError::NotFound)?;
+                    if let Some(Err(wait)) = module.rate_limiter.as_deref().map(RateLimiter::check)
+                    {
+                        tracing::warn!("Rate limit exceeded");
+                        let retry_after = wait.as_secs_f64().ceil().max(1.0) as u64;
+                        return Ok(hyper::Response::builder()
+                            .status(StatusCode::TOO_MANY_REQUESTS)
+                            .header(hyper::header::RETRY_AFTER, retry_after)
+                            .body(Default::default())
+                            .expect("valid response"));
+                    }
+                    let mut clamped = Clamped::default();
+                    let fuel =
+                        override_header(request.headers(), X_RVM_FUEL_OVERRIDE)?.map(|requested| {
+                            let max = state.config.max_fuel_override;
+                            clamped.fuel = (requested > max).then_some(max);
+                            requested.min(max)
+                        });
+                    let requested_timeout = override_header(request.headers(), X_RVM_TIMEOUT_MS)?
+                        .map(|requested| {
+                            let max = state.config.max_timeout_override_ms;
+                            clamped.timeout_ms = (requested > max).then_some(max);
+                            Duration::from_millis(requested.min(max))
+                        });
+                    // Evicted again since it was woken up, retrying is up to the client
+                    let sender = module.sender.as_ref().ok_or_else(|| {
+                        RvmError::Unavailable(String::from("the module was stopped while idle"))
+                    })?;
+                    let sent = sender.try_send(InvokeRequest {
+                        response: tx,
+                        request,
+                        scheme,
+                        fuel,
+                        timeout: requested_timeout,
+                        report,
+                        span: tracing::Span::current(),
+                    });
+                    if let Err(TrySendError::Full(_)) = sent {
+                        tracing::warn!("Worker queue is full");
+                        return Err(RvmError::ModuleBusy);
+                    }
+                    let queued = module.queued();
+                    module.queue_high_water.fetch_max(queued, Ordering::Relaxed);
+                    if state.config.queue_depth_warning == Some(queued) {
+                        tracing::warn!(queued, "Module is falling behind");
+                    }
+                    sent.map(|_| {
+                        let timeout = requested_timeout.or(ResourceBudget::new(
+                            &module.config,
+                            &state.config,
+                        )
+                        .timeout);
+                        let errors = module.errors.clone();
+                        (permit, clamped, timeout, module.hash, errors)
+                    })
+                };
+                let (permit, clamped, timeout, hash, errors) = match sent {
+                    Ok(sent) => sent,
+                    Err(_) => {
+                        // The worker is gone, don't keep routing requests to it.
+                        return Err(recover(&state, key));
+                    }
+                };
+                let received = match timeout {
+                    Some(timeout) => tokio::time::timeout(timeout, rx).await.map_err(|_| {
+                        let message = format!("timed out after {timeout:?}");
+                        errors.record(message.clone());
+                        record_dead_letter(key, dead_letter.take(), message.clone());
+                        RvmError::Timeout(message)
+                    })?,
+                    None => rx.await,
+                };
+                let response = match received {
+                    Ok(Ok(resp)) => resp,
+                    Ok(Err(error)) => {
+                        if let InvokeError::Trap { reason, .. } = &error {
+                            record_dead_letter(key, dead_letter.take(), reason.clone());
+                        }
+                        error.into_response()
+                    }
+                    Err(_) => return Err(recover(&state, key)),
+                };
+                let response = match (cache_key, cache_ttl) {
+                    (Some(cache_key), Some(ttl)) => cache.record(cache_key, ttl, response),
+                    _ => response,
+                };
+                (response, Some(permit), clamped, hash)
+            }
+        };
+        transforms.response(&mut response)?;
+        // Lets clients tell which version of the module served them
+        response.headers_mut().insert(
+            "x-rvm-module-hash",
+            hash_encoding.encode(&hash).parse().unwrap(),
+        );
+        if let Some(max) = clamped.fuel {
+            response
+                .headers_mut()
+                .insert("x-rvm-fuel-clamped", max.into());
+        }
+        if let Some(max) = clamped.timeout_ms {
+            response
+                .headers_mut()
+                .insert("x-rvm-timeout-clamped", max.into());
+        }
+        if let Some(request_id) = request_id {
+            response.headers_mut().insert(X_RVM_REQUEST_ID, request_id);
+        }
+        if let (Some(cors), Some(origin)) = (&cors, allowed_origin) {
+            cors.apply(origin, response.headers_mut());
+        }
+        // Keep the permit until the body is done, a streamed response is still in flight.
+        Ok(response.map(|body| {
+            body.map_frame(move |frame| {
+                let _permit = &permit;
+                frame
+            })
+            .boxed()
+        }))
+    }
+
+    /// Refuses `request` with a `413` if its body is longer than `limit`. Bodies of
+    /// unknown length are read up front, as a guest can't be stopped midway through one.
+    async fn limit_request_body(
+        request: hyper::Request<InvokeBody>,
+        limit: u64,
+    ) -> Result<hyper::Request<InvokeBody>, RvmError> {
+        let too_large = || {
+            tracing::warn!("Request body exceeded {limit} bytes");
+            RvmError::PayloadTooLarge(format!("the body exceeds the limit of {limit} bytes"))
+        };
+        let size = hyper::body::Body::size_hint(request.body());
+        if size.lower() > limit {
+            return Err(too_large());
+        }
+        if size.upper().is_some_and(|upper| upper <= limit) {
+            return Ok(request);
+        }
+        let (parts, body) = request.into_parts();
+        let body = http_body_util::Limited::new(body, limit as usize)
+            .collect()
+            .await
+            .map_err(|e| match e.is::<http_body_util::LengthLimitError>() {
+                true => too_large(),
+                false => read_failed(e),
+            })?
+            .to_bytes();
+        let body = http_body_util::Full::new(body)
+            .map_err(|e| match e {})
+            .boxed();
+        Ok(hyper::Request::from_parts(parts, body))
+    }
+
+    /// Records a failed invocation captured for a module deployed with
+    /// `dead_letter`, in the background.
+    fn record_dead_letter(
+        key: &str,
+        captured: Option<(opendal::Operator, usize, CapturedRequest)>,
+        reason: String,
+    ) {
+        let Some((storage, retention, captured)) = captured else {
+            return;
+        };
+        let key = key.to_owned();
+        tokio::spawn(async move {
+            let recorded = dead_letter::record(&storage, &key, captured, reason, retention);
+            if let Err(e) = recorded.await {
+                tracing::error!(key=%key, "Failed to record dead letter: {e:?}");
+            }
+        });
+    }
+
+    fn read_failed(e: impl std::fmt::Display) -> RvmError {
+        RvmError::BadRequest(format!("failed to read the body: {e}"))
+    }
+
+    fn worker_stopped() -> RvmError {
+        RvmError::Unavailable(String::from("the module's worker stopped, retry later"))
+    }
+
+    /// Recovers `key` from its stopped worker in the background, telling the
+    /// client to retry meanwhile.
+    fn recover(state: &SharedState, key: &str) -> RvmError {
+        let (state, key) = (state.clone(), key.to_owned());
+        tokio::spawn(async move { AppState::recover_module(&state, &key).await });
+        worker_stopped()
+    }
+
+    /// Tells the client to retry an invocation of a module that is still starting.
+    fn starting() -> hyper::Response<HyperOutgoingBody> {
+        tracing::info!("Module is starting");
+        hyper::Response::builder()
+            .status(StatusCode::SERVICE_UNAVAILABLE)
+            .header(hyper::header::RETRY_AFTER, 1)
+            .body(Default::default())
+            .expect("valid response")
+    }
+
+    /// Checks that `request` is shaped like an invocation of a `run` module,
+    /// a `POST` with a JSON body.
+    async fn run_request(
+        request: hyper::Request<InvokeBody>,
+    ) -> Result<hyper::Request<InvokeBody>, RvmError> {
+        if request.method() != hyper::Method::POST {
+            return Err(RvmError::MethodNotAllowed);
+        }
+        let is_json = request
+            .headers()
+            .get(hyper::header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.split(';').next())
+            .is_some_and(|mime| mime.trim() == "application/json");
+        if !is_json {
+            return Err(RvmError::UnsupportedMediaType);
+        }
+        let (parts, body) = request.into_parts();
+        let body = body.collect().await.map_err(read_failed)?.to_bytes();
+        serde_json::from_slice::<serde::de::IgnoredAny>(&body).map_err(invalid_json)?;
+        Ok(hyper::Request::from_parts(
+            parts,
+            http_body_util::Full::new(body)
+                .map_err(|e| match e {})
+                .boxed(),
+        ))
+    }
+
+    fn invalid_json(e: serde_json::Error) -> RvmError {
+        RvmError::BadRequest(format!("the body isn't valid JSON: {e}"))
+    }
+
+    /// Request header asking for a specific amount of fuel for one invocation.
+    const X_RVM_FUEL_OVERRIDE: &str = "x-rvm-fuel-override";
+    /// Request header asking for a specific timeout for one invocation, in milliseconds.
+    const X_RVM_TIMEOUT_MS: &str = "x-rvm-timeout-ms";
+
+    /// The caps that the overrides an invocation asked for were clamped to, if any.
+    #[derive(Default)]
+    struct Clamped {
+        fuel: Option<u64>,
+        timeout_ms: Option<u64>,
+    }
+
+    /// The number in the `name` header, if the request has one.
+    fn override_header(headers: &HeaderMap, name: &str) -> Result<Option<u64>, RvmError> {
+        headers
+            .get(name)
+            .map(|value| {
+                value
+                    .to_str()
+                    .ok()
+                    .and_then(|v| v.parse().ok())
+                    .ok_or_else(|| RvmError::BadRequest(format!("invalid {name}")))
+            })
+            .transpose()
+    }
+
+    /// Outcome of an RPC invocation, holding either a `result` or an `error`.
+    #[derive(serde::Serialize)]
+    #[serde(rename_all = "lowercase")]
+    pub enum RpcResponse {
+        Result(serde_json::Value),
+        Error(RpcError),
+    }
+
+    #[derive(serde::Serialize)]
+    pub struct RpcError {
+        /// Status the module responded with.
+        status: u16,
+        /// The body of the module's response, as JSON if it is valid JSON.
+        message: serde_json::Value,
+    }
+
+    /// Invokes `key` with a JSON body, sent as a `POST /` with a JSON content
+    /// type, and wraps the module's response as `{"result": ...}` when it
+    /// succeeds, or `{"error": ...}` with the module's status when it doesn't.
+    #[tracing::instrument(skip(state, headers, body))]
+    pub async fn invoke_rpc(
+        Path(key): Path<String>,
+        State(state): State<SharedState>,
+        headers: HeaderMap,
+        body: Bytes,
+    ) -> Result<(StatusCode, Json<RpcResponse>), RvmError> {
+        let key = tenant::scoped_key(&headers, &key)?;
+        serde_json::from_slice::<serde::de::IgnoredAny>(&body).map_err(invalid_json)?;
+        let mut request = hyper::Request::post("/")
+            .header(hyper::header::CONTENT_TYPE, "application/json")
+            .body(
+                http_body_util::Full::new(body)
+                    .map_err(|e| match e {})
+                    .boxed(),
+            )
+            .expect("valid request");
+        if let Some(host) = headers.get(hyper::header::HOST) {
+            request
+                .headers_mut()
+                .insert(hyper::header::HOST, host.clone());
+        }
+
+        let response = invoke_module(&key, request, Scheme::Http, state, None).await?;
+        let status = response.status();
+        let body = response
+            .into_body()
+            .collect()
+            .await
+            .map_err(|e| RvmError::Trapped(format!("failed to read the response: {e}")))?
+            .to_bytes();
+        let value = serde_json::from_slice(&body)
+            .unwrap_or_else(|_| String::from_utf8_lossy(&body).into_owned().into());
+
+        if status.is_success() {
+            Ok((StatusCode::OK, Json(RpcResponse::Result(value))))
+        } else {
+            let error = RpcError {
+                status: status.as_u16(),
+                message: value,
+            };
+            Ok((status, Json(RpcResponse::Error(error))))
+        }
+    }
+
+    #[derive(serde::Serialize)]
+    pub struct DebugReport {
+        status: u16,
+        headers: BTreeMap<String, String>,
+        body: String,
+        #[serde(flatten)]
+        invocation: InvocationReport,
+    }
+
+    /// Invokes `key` once and reports everything the invocation did, including
+    /// the guest's output, instead of proxying its response.
+    #[tracing::instrument(skip(state, request))]
+    pub async fn invoke_debug(
+        Path(key): Path<String>,
+        State(state): State<SharedState>,
+        request: axum::extract::Request,
+    ) -> Result<Json<DebugReport>, RvmError> {
+        let key = tenant::scoped_key(request.headers(), &key)?;
+        let (mut parts, body) = request.into_parts();
+        let body = axum::body::to_bytes(body, usize::MAX)
+            .await
+            .map_err(read_failed)?;
+        // The guest sees the request as if it was sent to the root of the module.
+        parts.uri = match parts.uri.query() {
+            Some(query) => format!("/?{query}").parse(),
+            None => "/".parse(),
+        }
+        .map_err(|e| RvmError::BadRequest(format!("invalid query: {e}")))?;
+        let request = hyper::Request::from_parts(
+            parts,
+            http_body_util::Full::new(body)
+                .map_err(|e| match e {})
+                .boxed(),
+        );
+
+        let (tx, rx) = oneshot::channel();
+        let response = invoke_module(&key, request, Scheme::Http, state, Some(tx)).await?;
+        let (parts, body) = response.into_parts();
+        // Reading the body lets a streaming guest run to completion.
+        let body = match body.collect().await {
+            Ok(body) => String::from_utf8_lossy(&body.to_bytes()).into_owned(),
+            Err(e) => format!("<failed to read body: {e}>"),
+        };
+        let invocation = rx.await.map_err(|_| worker_stopped())?;
+        tracing::info!(
+            fuel_consumed = invocation.fuel_consumed,
+            "Debug invocation done"
+        );
+
+        Ok(Json(DebugReport {
+            status: parts.status.as_u16(),
+            headers: parts
+                .headers
+                .iter()
+                .map(|(name, value)| {
+                    let value = String::from_utf8_lossy(value.as_bytes()).into_owned();
+                    (name.to_string(), value)
                 })
-                .map_err(|_| StatusCode::SERVICE_UNAVAILABLE)?;
+                .collect(),
+            body,
+            invocation,
+        }))
+    }
+
+    /// A request to send a module in a self-test, and the status it should answer with.
+    #[derive(serde::Deserialize)]
+    pub struct SelftestCase {
+        #[serde(default = "SelftestCase::default_method")]
+        method: String,
+        /// Path the module sees, such as `/` or `/secret?name=rvm`.
+        path: String,
+        #[serde(default)]
+        body: String,
+        expected_status: u16,
+    }
+
+    impl SelftestCase {
+        fn default_method() -> String {
+            String::from("GET")
         }
-        match rx.await {
-            Ok(Ok(resp)) => Ok(resp),
-            Ok(Err(_)) => Err(StatusCode::INTERNAL_SERVER_ERROR),
-            Err(_) => Err(StatusCode::SERVICE_UNAVAILABLE),
+
+        fn request(&self) -> anyhow::Result<hyper::Request<InvokeBody>> {
+            let body = http_body_util::Full::new(Bytes::from(self.body.clone()))
+                .map_err(|e| match e {})
+                .boxed();
+            Ok(hyper::Request::builder()
+                .method(self.method.as_str())
+                .uri(&self.path)
+                .header(hyper::header::HOST, "localhost")
+                .body(body)?)
         }
     }
 
+    #[derive(serde::Serialize)]
+    pub struct SelftestResult {
+        method: String,
+        path: String,
+        expected_status: u16,
+        /// What the module answered with, unset if the request couldn't be sent.
+        status: Option<u16>,
+        passed: bool,
+        fuel_consumed: Option<u64>,
+        wall_time_ms: Option<u128>,
+        error: Option<String>,
+    }
+
+    #[derive(serde::Serialize)]
+    pub struct SelftestReport {
+        passed: usize,
+        failed: usize,
+        cases: Vec<SelftestResult>,
+    }
+
+    /// Sends each of the cases to `key` in turn, reporting which answered with
+    /// the expected status.
+    #[tracing::instrument(skip(state, headers, cases))]
+    pub async fn selftest_module(
+        Path(key): Path<String>,
+        State(state): State<SharedState>,
+        headers: HeaderMap,
+        Json(cases): Json<Vec<SelftestCase>>,
+    ) -> Result<Json<SelftestReport>, RvmError> {
+        let key = tenant::scoped_key(&headers, &key)?;
+        {
+            let state = state.read().await;
+            if !state.instances.contains_key(&state.resolve(&key)) {
+                return Err(RvmError::NotFound);
+            }
+        }
+
+        let mut results = Vec::with_capacity(cases.len());
+        for case in cases {
+            let mut result = SelftestResult {
+                method: case.method.clone(),
+                path: case.path.clone(),
+                expected_status: case.expected_status,
+                status: None,
+                passed: false,
+                fuel_consumed: None,
+                wall_time_ms: None,
+                error: None,
+            };
+            let request = match case.request() {
+                Ok(request) => request,
+                Err(e) => {
+                    result.error = Some(format!("Invalid request: {e}"));
+                    results.push(result);
+                    continue;
+                }
+            };
+
+            let (tx, rx) = oneshot::channel();
+            match invoke_module(&key, request, Scheme::Http, state.clone(), Some(tx)).await {
+                Ok(response) => {
+                    let (parts, body) = response.into_parts();
+                    // Reading the body lets a streaming guest run to completion.
+                    if let Err(e) = body.collect().await {
+                        result.error = Some(format!("Failed to read body: {e}"));
+                    }
+                    result.status = Some(parts.status.as_u16());
+                    if let Ok(invocation) = rx.await {
+                        result.fuel_consumed = Some(invocation.fuel_consumed);
+                        result.wall_time_ms = Some(invocation.wall_time_ms);
+                        result.error = invocation.trap.or(result.error);
+                    }
+                }
+                // Refused before reaching the module, such as when its queue is full
+                Err(error) => {
+                    result.status = Some(error.status().as_u16());
+                    result.error = Some(error.to_string());
+                }
+            }
+            result.passed = result.status == Some(case.expected_status);
+            results.push(result);
+        }
+
+        let passed = results.iter().filter(|result| result.passed).count();
+        tracing::info!(passed, failed = results.len() - passed, "Self-test done");
+        Ok(Json(SelftestReport {
+            passed,
+            failed: results.len() - passed,
+            cases: results,
+        }))
+    }
+
     #[derive(serde::Serialize)]
     pub struct DeployResponse {
         hash: String,
     }
 
-    #[tracing::instrument(skip(state, bytes))]
+    #[tracing::instrument(skip(state, body))]
     pub async fn deploy_module(
         Path(key): Path<String>,
+        RawQuery(query): RawQuery,
         State(state): State<SharedState>,
+        headers: HeaderMap,
+        body: axum::body::Body,
+    ) -> Result<(StatusCode, Json<DeployResponse>), RvmError> {
+        // Taken before the upload is read, which is part of the deploy's cost
+        let permit = deploy_permit(&state).await?;
+        let (bytes, hash) = read_hashed(body)
+            .await
+            .map_err(|e| match is_length_limit(&*e) {
+                // Explained by `explain_payload_too_large`, which knows the limit
+                true => RvmError::Rejected(StatusCode::PAYLOAD_TOO_LARGE),
+                false => read_failed(e),
+            })?;
+        deploy(key, query, state, headers, bytes, hash, permit).await
+    }
+
+    /// A permit for a deploy, refused while `max_concurrent_deploys` are in progress.
+    async fn deploy_permit(state: &SharedState) -> Result<OwnedSemaphorePermit, RvmError> {
+        state.read().await.try_deploy().ok_or_else(|| {
+            tracing::warn!("Too many deploys in progress");
+            RvmError::TooManyDeploys
+        })
+    }
+
+    /// Reads `body` to the end, hashing it as it arrives rather than in another
+    /// pass over the whole component.
+    async fn read_hashed<B>(body: B) -> Result<(Bytes, blake3::Hash), axum::BoxError>
+    where
+        B: hyper::body::Body<Data = Bytes>,
+        B::Error: Into<axum::BoxError>,
+    {
+        let mut body = std::pin::pin!(body);
+        let mut hasher = blake3::Hasher::new();
+        let mut bytes = Vec::with_capacity(body.size_hint().lower() as usize);
+        while let Some(frame) = body.frame().await {
+            if let Ok(data) = frame.map_err(Into::into)?.into_data() {
+                hasher.update(&data);
+                bytes.extend_from_slice(&data);
+            }
+        }
+        Ok((Bytes::from(bytes), hasher.finalize()))
+    }
+
+    /// Whether reading a body failed because it exceeded a size limit.
+    fn is_length_limit(mut error: &(dyn std::error::Error + 'static)) -> bool {
+        loop {
+            if error.is::<http_body_util::LengthLimitError>() {
+                return true;
+            }
+            match error.source() {
+                Some(source) => error = source,
+                None => return false,
+            }
+        }
+    }
+
+    /// Verifies, compiles, starts and stores a module received in full, with
+    /// the settings in its manifest overridden by those in `query`. The deploy
+    /// is in progress until `permit` is dropped, once the module is stored.
+    async fn deploy(
+        key: String,
+        query: Option<String>,
+        state: SharedState,
+        headers: HeaderMap,
         bytes: Bytes,
-    ) -> Result<Json<DeployResponse>, StatusCode> {
-        let hash = blake3::hash(&bytes);
+        hash: blake3::Hash,
+        permit: OwnedSemaphorePermit,
+    ) -> Result<(StatusCode, Json<DeployResponse>), RvmError> {
+        let key = tenant::scoped_key(&headers, &key)?;
+        let module_config = manifest::module_config(&bytes, query.as_deref())
+            .map_err(|e| RvmError::BadRequest(format!("{e:#}")))?;
         let mut state = state.write().await;
-        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+        verify_signature(&state.config, &headers, &bytes)?;
+        if state.exceeds_max_modules([key.as_str()]) {
+            tracing::warn!("Too many modules deployed");
+            return Err(too_many_modules(&state.config));
+        }
 
-        // Worker gets killed when tx is dropped
-        compile_and_start_instance_worker(key.clone(), &state.engine, &state.linker, rx, bytes.clone())
+        // Compiling huge components stalls the engine, however small the upload was
+        if bytes.len() > state.config.max_component_bytes {
+            tracing::warn!(size = bytes.len(), "Component is too large to compile");
+            let limit = state.config.max_component_bytes;
+            return Err(RvmError::PayloadTooLarge(format!(
+                "component exceeds the compile size limit of {limit} bytes"
+            )));
+        }
+
+        // Catches components built for the wrong target before their first invocation
+        let kind = module_config.kind;
+        match state.exports_kind(hash, &bytes, kind) {
+            Ok(true) => {}
+            Ok(false) => {
+                tracing::warn!(
+                    export = kind.export(),
+                    "Component lacks the required export"
+                );
+                state.evict_unused_components();
+                return Err(RvmError::MissingExport(format!(
+                    "component doesn't export `{}`, deploy it with the `kind` it exports",
+                    kind.export()
+                )));
+            }
+            Err(e) => return Err(RvmError::CompileFailed(format!("{e:#}"))),
+        }
+
+        let module = state
+            .start_worker(&key, hash, bytes.clone(), module_config)
+            .await
+            .map_err(|e| RvmError::CompileFailed(format!("{e:#}")))?;
+
+        let response = Json(DeployResponse {
+            hash: state.config.hash_encoding.encode(&hash),
+        });
+
+        // Respond as soon as the module runs, it isn't persisted until the upload is done.
+        // Lazily started modules run from storage, so they're always uploaded first.
+        if headers
+            .get("x-rvm-async-upload")
+            .is_some_and(|value| value == "true")
+            && !state.config.lazy_start
+        {
+            let event = AuditEvent::new(AuditAction::Deploy, &key, &hash, bytes.len());
+            let upload = state.spawn_store_module(&key, &hash, bytes, &module.config);
+            state.insert_module(key.clone(), module);
+            state.audit(event).await;
+            tokio::spawn(async move {
+                let _permit = permit;
+                match upload.await {
+                    Ok(Ok(())) => {}
+                    Ok(Err(e)) => tracing::error!(key=%key, "Background upload failed: {e:?}"),
+                    Err(e) => tracing::error!(key=%key, "Background upload failed: {e:?}"),
+                }
+            });
+            return Ok((StatusCode::ACCEPTED, response));
+        }
+
+        let event = AuditEvent::new(AuditAction::Deploy, &key, &hash, bytes.len());
+        if let Err(e) = state.store_module(&key, &hash, bytes, &module.config).await {
+            drop(module);
+            state.evict_unused_components();
+            return Err(RvmError::Storage(format!("failed to store module: {e:#}")));
+        }
+        state.insert_module(key, module);
+        state.audit(event).await;
+
+        Ok((StatusCode::OK, response))
+    }
+
+    #[derive(Debug, serde::Deserialize)]
+    pub struct DeployFromUrl {
+        url: String,
+    }
+
+    /// Deploys the component downloaded from an https URL, the same way as an
+    /// uploaded one.
+    #[tracing::instrument(skip(state, headers))]
+    pub async fn deploy_from_url(
+        Path(key): Path<String>,
+        RawQuery(query): RawQuery,
+        State(state): State<SharedState>,
+        headers: HeaderMap,
+        Json(body): Json<DeployFromUrl>,
+    ) -> Result<(StatusCode, Json<DeployResponse>), RvmError> {
+        let permit = deploy_permit(&state).await?;
+        let (allowed_hosts, limit, timeout) = {
+            let state = state.read().await;
+            (
+                state.config.deploy_url_allowed_hosts.clone(),
+                state.config.max_component_bytes,
+                Duration::from_secs(state.config.deploy_url_timeout_secs),
+            )
+        };
+        let Some(allowed_hosts) = allowed_hosts else {
+            tracing::warn!("Deploying from URLs is disabled");
+            return Err(RvmError::Forbidden(String::from(
+                "deploying from URLs is disabled",
+            )));
+        };
+        let (bytes, hash) =
+            tokio::time::timeout(timeout, fetch_module(&body.url, &allowed_hosts, limit))
+                .await
+                .map_err(|_| RvmError::Timeout(String::from("download timed out")))??;
+        tracing::info!(size = bytes.len(), "Downloaded module");
+        deploy(key, query, state, headers, bytes, hash, permit).await
+    }
+
+    /// Downloads a module from `url`, following redirects as long as they
+    /// stay on https and allowed hosts.
+    async fn fetch_module(
+        url: &str,
+        allowed_hosts: &str,
+        limit: usize,
+    ) -> Result<(Bytes, blake3::Hash), RvmError> {
+        use http_body_util::{Empty, Limited};
+        use wasmtime_wasi_http::types::{default_send_request_handler, OutgoingRequestConfig};
+
+        const MAX_REDIRECTS: usize = 5;
+
+        let mut url: Uri = url
+            .parse()
+            .map_err(|_| RvmError::BadRequest(format!("invalid URL `{url}`")))?;
+        for _ in 0..=MAX_REDIRECTS {
+            if url.scheme() != Some(&hyper::http::uri::Scheme::HTTPS) {
+                return Err(RvmError::BadRequest(String::from(
+                    "modules can only be deployed from https URLs",
+                )));
+            }
+            let authority = url
+                .authority()
+                .cloned()
+                .ok_or_else(|| RvmError::BadRequest(format!("invalid URL `{url}`")))?;
+            let allowed = allowed_hosts
+                .split(',')
+                .map(str::trim)
+                .any(|allowed| allowed == "*" || allowed.eq_ignore_ascii_case(authority.host()));
+            if !allowed {
+                return Err(RvmError::Forbidden(format!(
+                    "`{}` is not an allowed host",
+                    authority.host()
+                )));
+            }
+
+            let request = hyper::Request::get(url.clone())
+                .header(hyper::header::HOST, authority.as_str())
+                .body(Empty::new().map_err(|e| match e {}).boxed())
+                .expect("valid request");
+            let config = OutgoingRequestConfig {
+                use_tls: true,
+                connect_timeout: Duration::from_secs(10),
+                first_byte_timeout: Duration::from_secs(30),
+                between_bytes_timeout: Duration::from_secs(30),
+            };
+            let response = default_send_request_handler(request, config)
+                .await
+                .map_err(|e| RvmError::DownloadFailed(format!("download failed: {e:?}")))?;
+            // Keeps the connection alive while the body is read
+            let _worker = response.worker;
+            let response = response.resp;
+
+            if response.status().is_redirection() {
+                let location = response
+                    .headers()
+                    .get(hyper::header::LOCATION)
+                    .and_then(|location| location.to_str().ok())
+                    .and_then(|location| location.parse::<Uri>().ok())
+                    .ok_or_else(invalid_redirect)?;
+                url = match location.authority() {
+                    Some(_) => location,
+                    // Relative to the URL that redirected
+                    None => Uri::builder()
+                        .scheme(hyper::http::uri::Scheme::HTTPS)
+                        .authority(authority)
+                        .path_and_query(location.path_and_query().map_or("/", |p| p.as_str()))
+                        .build()
+                        .map_err(|_| invalid_redirect())?,
+                };
+                continue;
+            }
+            if !response.status().is_success() {
+                return Err(RvmError::DownloadFailed(format!(
+                    "download responded with {}",
+                    response.status()
+                )));
+            }
+            return read_hashed(Limited::new(response.into_body(), limit))
+                .await
+                .map_err(|e| match is_length_limit(&*e) {
+                    true => RvmError::PayloadTooLarge(format!(
+                        "component exceeds the compile size limit of {limit} bytes"
+                    )),
+                    false => RvmError::DownloadFailed(format!("download failed: {e}")),
+                });
+        }
+        Err(RvmError::DownloadFailed(String::from("too many redirects")))
+    }
+
+    fn invalid_redirect() -> RvmError {
+        RvmError::DownloadFailed(String::from("invalid redirect"))
+    }
+
+    fn too_many_modules(config: &RvmConfig) -> RvmError {
+        RvmError::TooManyModules(config.max_modules.unwrap_or_default())
+    }
+
+    /// Checks the `x-rvm-signature` of a deployed component, if deploys must be signed.
+    fn verify_signature(
+        config: &RvmConfig,
+        headers: &HeaderMap,
+        bytes: &[u8],
+    ) -> Result<(), RvmError> {
+        use base64::{engine::general_purpose::STANDARD, Engine};
+
+        let Some(public_key) = &config.deploy_public_key else {
+            return Ok(());
+        };
+        let public_key = STANDARD.decode(public_key).map_err(|e| {
+            tracing::error!("Invalid deploy public key: {e}");
+            RvmError::Internal(String::from("the deploy public key is invalid"))
+        })?;
+        let signature = headers
+            .get("x-rvm-signature")
+            .and_then(|value| STANDARD.decode(value.as_bytes()).ok())
+            .ok_or_else(|| {
+                tracing::warn!("Rejected deploy without a valid signature header");
+                RvmError::Unauthorized(String::from("missing or invalid x-rvm-signature header"))
+            })?;
+        ring::signature::UnparsedPublicKey::new(&ring::signature::ED25519, public_key)
+            .verify(bytes, &signature)
+            .map_err(|_| {
+                tracing::warn!("Rejected deploy with a mismatching signature");
+                RvmError::Unauthorized(String::from("the signature doesn't match"))
+            })
+    }
+
+    #[derive(serde::Serialize)]
+    pub struct BatchFailure {
+        key: String,
+        error: String,
+    }
+
+    #[derive(serde::Serialize, Default)]
+    pub struct BatchDeployResponse {
+        /// Hashes of the deployed modules by key, empty unless every module was deployed.
+        deployed: BTreeMap<String, String>,
+        failed: Vec<BatchFailure>,
+    }
+
+    /// Deploys every module in a multipart body, where each part is named after
+    /// the module's key. Either all modules go live, or none of them do.
+    #[tracing::instrument(skip(state, request_headers, multipart))]
+    pub async fn deploy_batch(
+        RawQuery(query): RawQuery,
+        State(state): State<SharedState>,
+        request_headers: HeaderMap,
+        mut multipart: Multipart,
+    ) -> Result<(StatusCode, Json<BatchDeployResponse>), RvmError> {
+        let invalid = |e: axum::extract::multipart::MultipartError| match e.status() {
+            // Explained by `explain_payload_too_large`, which knows the limit
+            StatusCode::PAYLOAD_TOO_LARGE => RvmError::Rejected(e.status()),
+            _ => RvmError::BadRequest(format!("invalid multipart body: {}", e.body_text())),
+        };
+        // A batch counts as a single deploy, its modules are deployed one by one
+        let _permit = deploy_permit(&state).await?;
+        let mut modules = Vec::new();
+        while let Some(mut field) = multipart.next_field().await.map_err(invalid)? {
+            let key = field
+                .name()
+                .ok_or_else(|| RvmError::BadRequest(String::from("a part has no name")))?;
+            let key = tenant::scoped_key(&request_headers, key)?;
+            let headers = field.headers().clone();
+            // Hash each part as it arrives, like single deploys
+            let mut hasher = blake3::Hasher::new();
+            let mut bytes = Vec::new();
+            while let Some(chunk) = field.chunk().await.map_err(invalid)? {
+                hasher.update(&chunk);
+                bytes.extend_from_slice(&chunk);
+            }
+            modules.push((key, headers, Bytes::from(bytes), hasher.finalize()));
+        }
+
+        let mut state = state.write().await;
+        // Each part carries the signature of its own module
+        for (_, headers, bytes, _) in &modules {
+            verify_signature(&state.config, headers, bytes)?;
+        }
+        let keys: BTreeSet<&str> = modules.iter().map(|(key, ..)| key.as_str()).collect();
+        if state.exceeds_max_modules(keys) {
+            tracing::warn!("Too many modules deployed");
+            return Err(too_many_modules(&state.config));
+        }
+        let mut response = BatchDeployResponse::default();
+
+        // Compile and start every module before touching storage or the running instances
+        let mut started = Vec::new();
+        let mut seen = BTreeSet::new();
+        for (key, _, bytes, hash) in modules {
+            if !seen.insert(key.clone()) {
+                response.failed.push(BatchFailure {
+                    key,
+                    error: String::from("module is included more than once"),
+                });
+                continue;
+            }
+            if bytes.len() > state.config.max_component_bytes {
+                response.failed.push(BatchFailure {
+                    key,
+                    error: format!(
+                        "component exceeds the limit of {} bytes",
+                        state.config.max_component_bytes
+                    ),
+                });
+                continue;
+            }
+            let module_config = match manifest::module_config(&bytes, query.as_deref()) {
+                Ok(module_config) => module_config,
+                Err(e) => {
+                    let error = format!("{e:#}");
+                    response.failed.push(BatchFailure { key, error });
+                    continue;
+                }
+            };
+            let kind = module_config.kind;
+            let missing_export = match state.exports_kind(hash, &bytes, kind) {
+                Ok(exported) => {
+                    (!exported).then(|| format!("component doesn't export `{}`", kind.export()))
+                }
+                Err(e) => Some(format!("{e:#}")),
+            };
+            if let Some(error) = missing_export {
+                response.failed.push(BatchFailure { key, error });
+                continue;
+            }
+            match state
+                .start_worker(&key, hash, bytes.clone(), module_config)
+                .await
+            {
+                Ok(module) => started.push((key, hash, bytes, module)),
+                Err(e) => response.failed.push(BatchFailure {
+                    key,
+                    error: format!("{e:#}"),
+                }),
+            }
+        }
+
+        if response.failed.is_empty() {
+            let stored = started
+                .iter()
+                .map(|(key, hash, bytes, module)| {
+                    (key.clone(), *hash, bytes.clone(), &module.config)
+                })
+                .collect();
+            if let Err((key, e)) = state.store_modules(stored).await {
+                response.failed.push(BatchFailure {
+                    key,
+                    error: format!("failed to store module: {e:#}"),
+                });
+            }
+        }
+
+        if !response.failed.is_empty() {
+            drop(started);
+            state.evict_unused_components();
+            return Ok((StatusCode::UNPROCESSABLE_ENTITY, Json(response)));
+        }
+
+        for (key, hash, bytes, module) in started {
+            response
+                .deployed
+                .insert(key.clone(), state.config.hash_encoding.encode(&hash));
+            let event = AuditEvent::new(AuditAction::Deploy, &key, &hash, bytes.len());
+            state.insert_module(key, module);
+            state.audit(event).await;
+        }
+        Ok((StatusCode::OK, Json(response)))
+    }
+
+    /// Replaces the body of a `413` response with one that states the limit.
+    pub async fn explain_payload_too_large(limit: usize, response: Response) -> Response {
+        if response.status() != StatusCode::PAYLOAD_TOO_LARGE
+            || response.extensions().get::<LimitExplained>().is_some()
+        {
+            return response;
+        }
+        RvmError::PayloadTooLarge(format!(
+            "module exceeds the deploy size limit of {limit} bytes"
+        ))
+        .into_response()
+    }
+
+    /// Gives `405` responses a JSON body listing the methods in their `Allow` header.
+    pub fn explain_method_not_allowed(response: Response) -> Response {
+        if response.status() != StatusCode::METHOD_NOT_ALLOWED {
+            return response;
+        }
+        let allow = response.headers().get(hyper::header::ALLOW).cloned();
+        let allowed: Vec<&str> = allow
+            .as_ref()
+            .and_then(|allow| allow.to_str().ok())
+            .map(|allow| allow.split(',').map(str::trim).collect())
+            .unwrap_or_default();
+        let body = serde_json::json!({
+            "error": "method not allowed",
+            "allowed": allowed,
+        });
+        let mut explained = (StatusCode::METHOD_NOT_ALLOWED, Json(body)).into_response();
+        if let Some(allow) = allow {
+            explained.headers_mut().insert(hyper::header::ALLOW, allow);
+        }
+        explained
+    }
+
+    #[derive(Debug, serde::Deserialize)]
+    pub struct ReloadParams {
+        /// Stop running modules that are no longer in storage.
+        #[serde(default)]
+        remove_missing: bool,
+    }
+
+    /// Resyncs the running modules with storage, e.g. after another node deployed.
+    #[tracing::instrument(skip(state))]
+    pub async fn reload_all(
+        Query(params): Query<ReloadParams>,
+        State(state): State<SharedState>,
+    ) -> Result<Json<ReloadSummary>, StatusCode> {
+        let mut state = state.write().await;
+        let summary = state
+            .reload_modules(params.remove_missing)
+            .await
+            .map_err(|e| {
+                tracing::error!("Failed to reload modules: {e:?}");
+                StatusCode::INTERNAL_SERVER_ERROR
+            })?;
+        Ok(Json(summary))
+    }
+
+    #[derive(Debug, serde::Deserialize)]
+    pub struct GcParams {
+        /// Only evict modules that haven't been invoked for this long.
+        #[serde(default)]
+        idle_secs: u64,
+    }
+
+    #[derive(serde::Serialize)]
+    pub struct GcSummary {
+        evicted: Vec<String>,
+    }
+
+    /// Tears down the instances of idle modules right away, rather than waiting
+    /// for the idle timeout, e.g. to make room in the pool before a large deploy.
+    /// They are started again on their next invocation.
+    #[tracing::instrument(skip(state))]
+    pub async fn gc(
+        Query(params): Query<GcParams>,
+        State(state): State<SharedState>,
+    ) -> Json<GcSummary> {
+        let mut evicted = state
+            .write()
+            .await
+            .evict_idle_modules(Duration::from_secs(params.idle_secs));
+        evicted.sort();
+        tracing::info!(evicted = evicted.len(), "Evicted idle modules");
+        Json(GcSummary { evicted })
+    }
+
+    /// Starts shutting down like on `SIGTERM`, answering right away while the
+    /// invocations in flight are drained in the background.
+    #[tracing::instrument(skip(state))]
+    pub async fn shutdown(State(state): State<SharedState>) -> StatusCode {
+        if state.read().await.shutdown.begin() {
+            tracing::info!("Already shutting down");
+        }
+        StatusCode::ACCEPTED
+    }
+
+    #[derive(Debug, serde::Deserialize)]
+    pub struct HealthParams {
+        /// Check that modules can still run, rather than only that the host is up.
+        #[serde(default)]
+        deep: bool,
+    }
+
+    /// Answers while the host is up and, for deep checks, able to run modules.
+    pub async fn healthz(
+        Query(params): Query<HealthParams>,
+        State(state): State<SharedState>,
+    ) -> Result<&'static str, RvmError> {
+        if params.deep {
+            health::deep_check(&state).await.map_err(|e| {
+                tracing::error!("Deep health check failed: {e:?}");
+                RvmError::Unavailable(format!("{e:#}"))
+            })?;
+        }
+        Ok("ok")
+    }
+
+    #[derive(Debug, serde::Deserialize)]
+    pub struct AuditParams {
+        #[serde(default = "AuditParams::default_limit")]
+        limit: usize,
+    }
+
+    impl AuditParams {
+        fn default_limit() -> usize {
+            100
+        }
+    }
+
+    #[derive(serde::Serialize)]
+    pub struct AuditLog {
+        events: Vec<AuditEvent>,
+    }
+
+    /// Lists the most recent deploys and rollbacks, oldest first.
+    #[tracing::instrument(skip(state))]
+    pub async fn audit_log(
+        Query(params): Query<AuditParams>,
+        State(state): State<SharedState>,
+    ) -> Result<Json<AuditLog>, StatusCode> {
+        let storage = state.read().await.storage.clone();
+        let events = audit::recent(&storage, params.limit).await.map_err(|e| {
+            tracing::error!("Failed to read the audit log: {e:?}");
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+        Ok(Json(AuditLog { events }))
+    }
+
+    /// Restarts `key` from a previously deployed version and makes it the current one.
+    #[tracing::instrument(skip(state, headers))]
+    pub async fn rollback_module(
+        Path((key, hash)): Path<(String, String)>,
+        State(state): State<SharedState>,
+        headers: HeaderMap,
+    ) -> Result<Json<DeployResponse>, RvmError> {
+        let key = tenant::scoped_key(&headers, &key)?;
+        // Rolling back compiles and stores the version like a deploy would
+        let _permit = deploy_permit(&state).await?;
+        let mut state = state.write().await;
+        let hash = state
+            .config
+            .hash_encoding
+            .decode(&hash)
+            .map_err(RvmError::BadRequest)?;
+
+        let bytes = match read_version(&state.storage, &key, &hash).await {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == opendal::ErrorKind::NotFound => return Err(RvmError::NotFound),
+            Err(e) => {
+                tracing::error!("Failed to read the version: {e:?}");
+                return Err(RvmError::Storage(String::from(
+                    "failed to read the version",
+                )));
+            }
+        };
+
+        // Keep running with the same settings as the version being replaced
+        let module_config = state
+            .instances
+            .get(&key)
+            .map(|module| module.config.clone())
+            .unwrap_or_else(|| state.config.module_config(&key));
+        // Rolling back a deleted module deploys it again
+        if state.exceeds_max_modules([key.as_str()]) {
+            tracing::warn!("Too many modules deployed");
+            return Err(too_many_modules(&state.config));
+        }
+        let event = AuditEvent::new(AuditAction::Rollback, &key, &hash, bytes.len());
+        let module = state
+            .start_worker(&key, hash, bytes.clone(), module_config)
+            .await
+            .map_err(|e| RvmError::CompileFailed(format!("{e:#}")))?;
+        // Versions stored by older versions have no blob yet
+        let (storage, blob_lock) = (state.storage.clone(), state.blob_lock.clone());
+        let chunk_bytes = state.config.upload_chunk_bytes;
+        let live_key = key.clone();
+        let turn = state.start_store(&key, false);
+        tokio::spawn(async move {
+            let turn = turn.await;
+            // Marked again, in case the version was pruned since it was read
+            store_version(&storage, &blob_lock, &live_key, &hash, bytes, chunk_bytes).await?;
+            set_live_version(&storage, &live_key, &hash).await?;
+            turn.stored();
+            anyhow::Ok(())
+        })
         .await
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-
-        // Upload
-        let storage = state.storage.clone();
-        let module_name = format!("{key}.wasm");
-        tokio::spawn(async move { 
-            let mut w = storage.writer(&module_name).await?;
-            let len = bytes.len();
-            w.write(bytes).await?;
-            tracing::info!("Uploaded {len} bytes");
-            w.close().await?;
-            Ok::<_, anyhow::Error>(())
-         })
-            .await
-            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
-            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
-        state.instances.insert(key, tx);
+        .map_err(|e| RvmError::Internal(e.to_string()))?
+        .map_err(|e| {
+            tracing::error!("Failed to store the rolled back version: {e:?}");
+            RvmError::Storage(String::from("failed to store the rolled back version"))
+        })?;
+        state.insert_module(key, module);
+        state.audit(event).await;
+        tracing::info!(hash=%hash, "Rolled back module");
 
         Ok(DeployResponse {
-            hash: hash.to_string(),
+            hash: state.config.hash_encoding.encode(&hash),
         }
         .into())
     }
+
+    /// Routes invocations of `name` to the modules in the body, a JSON object
+    /// of module keys and their weights, e.g. `{"app-v1": 90, "app-v2": 10}`.
+    #[tracing::instrument(skip(state, headers))]
+    pub async fn set_alias(
+        Path(name): Path<String>,
+        State(state): State<SharedState>,
+        headers: HeaderMap,
+        Json(backends): Json<BTreeMap<String, u32>>,
+    ) -> Result<StatusCode, StatusCode> {
+        // The alias and its modules belong to the same tenant
+        let name = tenant::scoped_key(&headers, &name).map_err(|e| e.status())?;
+        let backends = backends
+            .into_iter()
+            .map(|(key, weight)| Ok((tenant::scoped_key(&headers, &key)?, weight)))
+            .collect::<Result<BTreeMap<_, _>, RvmError>>()
+            .map_err(|e| e.status())?;
+        let mut state = state.write().await;
+        if state.instances.contains_key(&name) {
+            return Err(StatusCode::CONFLICT);
+        }
+        if backends
+            .keys()
+            .any(|key| !state.instances.contains_key(key))
+        {
+            return Err(StatusCode::NOT_FOUND);
+        }
+        let alias = Alias::new(backends).ok_or(StatusCode::BAD_REQUEST)?;
+        state.aliases.insert(name, alias);
+        tracing::info!("Updated alias");
+        Ok(StatusCode::NO_CONTENT)
+    }
+
+    #[derive(serde::Serialize)]
+    pub struct ModuleDetails {
+        key: String,
+        hash: String,
+        /// Fuel consumed by all invocations of the module, across deploys and restarts.
+        fuel_consumed: u64,
+        /// Instances of the module replaced after serving `recycle_after_requests`,
+        /// across deploys.
+        instances_recycled: u64,
+        /// Whether the module is in storage, and survives restarts.
+        persisted: bool,
+        /// Whether the module has an instance, rather than being evicted while idle.
+        running: bool,
+        /// Whether new invocations are refused.
+        draining: bool,
+        /// Whether the module takes invocations, and if not why.
+        readiness: Readiness,
+        /// The most recent failure of the module, if any.
+        last_error: Option<LastError>,
+        /// Invocations waiting for the module's worker.
+        queued: usize,
+        /// Most invocations that have been waiting at once.
+        queue_high_water: usize,
+        /// What each invocation of the module may use.
+        budget: ResourceBudget,
+        /// Whether the module was restored from storage or deployed since rvm started.
+        origin: ModuleOrigin,
+    }
+
+    #[tracing::instrument(skip(state, headers))]
+    pub async fn module_details(
+        Path(key): Path<String>,
+        State(state): State<SharedState>,
+        headers: HeaderMap,
+    ) -> Result<Json<ModuleDetails>, StatusCode> {
+        let key = tenant::scoped_key(&headers, &key).map_err(|e| e.status())?;
+        let state = state.read().await;
+        let module = state.instances.get(&key).ok_or(StatusCode::NOT_FOUND)?;
+        let fuel_consumed = state
+            .fuel_consumed
+            .get(&key)
+            .map_or(0, |total| total.load(Ordering::Relaxed));
+        let instances_recycled = state
+            .instances_recycled
+            .get(&key)
+            .map_or(0, |recycled| recycled.load(Ordering::Relaxed));
+        let persisted = state.is_persisted(&key);
+
+        Ok(ModuleDetails {
+            hash: module.hash.to_string(),
+            key,
+            fuel_consumed,
+            instances_recycled,
+            persisted,
+            running: module.sender.is_some(),
+            draining: module.readiness == Readiness::Draining,
+            readiness: module.readiness,
+            last_error: module.errors.last(),
+            queued: module.queued(),
+            queue_high_water: module.queue_high_water.load(Ordering::Relaxed),
+            budget: ResourceBudget::new(&module.config, &state.config),
+            origin: module.origin,
+        }
+        .into())
+    }
+
+    /// Refuses new invocations of `key` with a `503`, letting those in flight complete.
+    #[tracing::instrument(skip(state, headers))]
+    pub async fn drain_module(
+        Path(key): Path<String>,
+        State(state): State<SharedState>,
+        headers: HeaderMap,
+    ) -> StatusCode {
+        set_draining(&headers, &key, &state, true).await
+    }
+
+    /// Lets a drained module take invocations again.
+    #[tracing::instrument(skip(state, headers))]
+    pub async fn undrain_module(
+        Path(key): Path<String>,
+        State(state): State<SharedState>,
+        headers: HeaderMap,
+    ) -> StatusCode {
+        set_draining(&headers, &key, &state, false).await
+    }
+
+    async fn set_draining(
+        headers: &HeaderMap,
+        key: &str,
+        state: &SharedState,
+        draining: bool,
+    ) -> StatusCode {
+        let key = match tenant::scoped_key(headers, key) {
+            Ok(key) => key,
+            Err(e) => return e.status(),
+        };
+        match state.write().await.instances.get_mut(&key) {
+            Some(module) => {
+                module.readiness = match (draining, module.readiness) {
+                    (true, _) => Readiness::Draining,
+                    (false, Readiness::Draining) => Readiness::Ready,
+                    (false, readiness) => readiness,
+                };
+                tracing::info!(draining, "Updated module");
+                StatusCode::NO_CONTENT
+            }
+            None => StatusCode::NOT_FOUND,
+        }
+    }
+
+    /// Reports the state of the host in the Prometheus text format.
+    pub async fn metrics(State(state): State<SharedState>) -> String {
+        let state = state.read().await;
+        let limit = state.config.max_concurrent_invocations;
+        let in_flight = state.invocations.in_flight();
+        let waiting = state.invocations.waiting();
+        let deploys = state.deploys_in_progress();
+        let mut metrics = format!(
+            "# HELP rvm_deploys_in_progress Deploys currently being received, compiled or stored.\n\
+             # TYPE rvm_deploys_in_progress gauge\n\
+             rvm_deploys_in_progress {deploys}\n\
+             # HELP rvm_invocations_in_flight Invocations currently being handled.\n\
+             # TYPE rvm_invocations_in_flight gauge\n\
+             rvm_invocations_in_flight {in_flight}\n\
+             # HELP rvm_invocations_limit Most invocations handled at once.\n\
+             # TYPE rvm_invocations_limit gauge\n\
+             rvm_invocations_limit {limit}\n\
+             # HELP rvm_invocations_waiting Invocations waiting for their turn.\n\
+             # TYPE rvm_invocations_waiting gauge\n\
+             rvm_invocations_waiting {waiting}\n"
+        );
+        let mut modules: Vec<_> = state.instances.iter().collect();
+        modules.sort_by_key(|(key, _)| *key);
+        metrics.push_str(
+            "# HELP rvm_module_queue_depth Invocations waiting for a module's worker.\n\
+             # TYPE rvm_module_queue_depth gauge\n",
+        );
+        for (key, module) in &modules {
+            let queued = module.queued();
+            metrics.push_str(&format!(
+                "rvm_module_queue_depth{{key=\"{key}\"}} {queued}\n"
+            ));
+        }
+        metrics.push_str(
+            "# HELP rvm_module_queue_high_water Most invocations that have waited for a module's worker at once.\n\
+             # TYPE rvm_module_queue_high_water gauge\n",
+        );
+        for (key, module) in &modules {
+            let high_water = module.queue_high_water.load(Ordering::Relaxed);
+            metrics.push_str(&format!(
+                "rvm_module_queue_high_water{{key=\"{key}\"}} {high_water}\n"
+            ));
+        }
+        metrics.push_str(
+            "# HELP rvm_module_fuel_consumed Fuel consumed by all invocations of a module.\n\
+             # TYPE rvm_module_fuel_consumed counter\n",
+        );
+        for (key, _) in &modules {
+            let consumed = state
+                .fuel_consumed
+                .get(*key)
+                .map_or(0, |total| total.load(Ordering::Relaxed));
+            metrics.push_str(&format!(
+                "rvm_module_fuel_consumed{{key=\"{key}\"}} {consumed}\n"
+            ));
+        }
+        metrics.push_str(
+            "# HELP rvm_module_host_calls Calls of each host function by a module.\n\
+             # TYPE rvm_module_host_calls counter\n",
+        );
+        for (key, _) in &modules {
+            let Some(calls) = state.host_calls.get(*key) else {
+                continue;
+            };
+            for (function, count) in calls.counts() {
+                metrics.push_str(&format!(
+                    "rvm_module_host_calls{{key=\"{key}\",function=\"{function}\"}} {count}\n"
+                ));
+            }
+        }
+        metrics.push_str(
+            "# HELP rvm_host_call_fuel Fuel charged for each call of a host function, as configured.\n\
+             # TYPE rvm_host_call_fuel gauge\n",
+        );
+        let mut prices: Vec<_> = state.config.host_call_fuel.iter().collect();
+        prices.sort();
+        for (function, fuel) in prices {
+            metrics.push_str(&format!(
+                "rvm_host_call_fuel{{function=\"{function}\"}} {fuel}\n"
+            ));
+        }
+        metrics.push_str(
+            "# HELP rvm_module_cpu_seconds Time invocations of a module spent running, not counting the time they waited on I/O.\n\
+             # TYPE rvm_module_cpu_seconds summary\n",
+        );
+        for (key, _) in &modules {
+            let (total, invocations) = state
+                .cpu_usage
+                .get(*key)
+                .map_or((Duration::ZERO, 0), |usage| {
+                    (usage.total(), usage.invocations())
+                });
+            metrics.push_str(&format!(
+                "rvm_module_cpu_seconds_sum{{key=\"{key}\"}} {}\n\
+                 rvm_module_cpu_seconds_count{{key=\"{key}\"}} {invocations}\n",
+                total.as_secs_f64(),
+            ));
+        }
+        metrics.push_str(
+            "# HELP rvm_module_wait_seconds Time invocations of a module waited for their turn.\n\
+             # TYPE rvm_module_wait_seconds summary\n",
+        );
+        for (key, _) in &modules {
+            let waits = state.invocations.waits(key);
+            metrics.push_str(&format!(
+                "rvm_module_wait_seconds_sum{{key=\"{key}\"}} {}\n\
+                 rvm_module_wait_seconds_count{{key=\"{key}\"}} {}\n",
+                waits.total.as_secs_f64(),
+                waits.invocations,
+            ));
+        }
+        metrics.push_str(
+            "# HELP rvm_module_cache_hits Invocations of a module answered from the response cache.\n\
+             # TYPE rvm_module_cache_hits counter\n",
+        );
+        for (key, _) in &modules {
+            let hits = state.cache.stats(key).hits;
+            metrics.push_str(&format!("rvm_module_cache_hits{{key=\"{key}\"}} {hits}\n"));
+        }
+        metrics.push_str(
+            "# HELP rvm_module_cache_misses Cacheable invocations of a module that reached it.\n\
+             # TYPE rvm_module_cache_misses counter\n",
+        );
+        for (key, _) in &modules {
+            let misses = state.cache.stats(key).misses;
+            metrics.push_str(&format!(
+                "rvm_module_cache_misses{{key=\"{key}\"}} {misses}\n"
+            ));
+        }
+        metrics.push_str(&format!(
+            "# HELP rvm_response_cache_bytes Bytes taken by cached responses.\n\
+             # TYPE rvm_response_cache_bytes gauge\n\
+             rvm_response_cache_bytes {}\n",
+            state.cache.bytes()
+        ));
+        metrics.push_str(&state.guest_metrics.render());
+        metrics
+    }
+
+    #[derive(serde::Serialize)]
+    pub struct VersionInfo {
+        rvm: &'static str,
+        wasmtime: &'static str,
+        features: Features,
+    }
+
+    /// Engine and host features that are enabled, as they affect how modules behave.
+    #[derive(serde::Serialize)]
+    pub struct Features {
+        pooling: bool,
+        fuel: bool,
+        cache: bool,
+        lazy_start: bool,
+        http2: bool,
+        tls: bool,
+    }
+
+    /// Reports what is actually running, to compare hosts.
+    pub async fn version(State(state): State<SharedState>) -> Json<VersionInfo> {
+        let state = state.read().await;
+        let config = &state.config;
+        Json(VersionInfo {
+            rvm: env!("CARGO_PKG_VERSION"),
+            wasmtime: env!("RVM_WASMTIME_VERSION"),
+            features: Features {
+                pooling: true,
+                fuel: true,
+                cache: config.cache_enabled,
+                lazy_start: config.lazy_start,
+                http2: config.http2,
+                tls: config.tls_cert.is_some() && config.tls_key.is_some(),
+            },
+        })
+    }
+
+    #[derive(serde::Serialize)]
+    pub struct ModuleLogs {
+        stdout: String,
+        stderr: String,
+    }
+
+    /// Returns the most recent output of `key`, if log buffers are enabled.
+    #[tracing::instrument(skip(state, headers))]
+    pub async fn module_logs(
+        Path(key): Path<String>,
+        State(state): State<SharedState>,
+        headers: HeaderMap,
+    ) -> Result<Json<ModuleLogs>, StatusCode> {
+        let key = tenant::scoped_key(&headers, &key).map_err(|e| e.status())?;
+        let state = state.read().await;
+        let module = state.instances.get(&key).ok_or(StatusCode::NOT_FOUND)?;
+        let (Some(stdout), Some(stderr)) = (module.stdio.stdout.logs(), module.stdio.stderr.logs())
+        else {
+            return Err(StatusCode::NOT_FOUND);
+        };
+        Ok(Json(ModuleLogs {
+            stdout: String::from_utf8_lossy(&stdout).into_owned(),
+            stderr: String::from_utf8_lossy(&stderr).into_owned(),
+        }))
+    }
+
+    /// Streams the component deployed as `key` from storage, or the part of it
+    /// asked for with a `Range` header.
+    #[tracing::instrument(skip(state, headers))]
+    pub async fn module_artifact(
+        Path(key): Path<String>,
+        State(state): State<SharedState>,
+        headers: HeaderMap,
+    ) -> Result<Response, RvmError> {
+        let key = tenant::scoped_key(&headers, &key)?;
+        let (storage, hash_encoding) = {
+            let state = state.read().await;
+            (state.storage.clone(), state.config.hash_encoding)
+        };
+        let storage_error = |e: opendal::Error| match e.kind() {
+            opendal::ErrorKind::NotFound => RvmError::NotFound,
+            _ => RvmError::Storage(format!("failed to read the module: {e}")),
+        };
+        let (path, hash) = stored_module(&storage, &key).await.map_err(storage_error)?;
+        let hash = match hash {
+            Some(hash) => hash,
+            // Stored before modules were deduplicated, under its key
+            None => blake3::hash(&storage.read(&path).await.map_err(storage_error)?.to_bytes()),
+        };
+        let len = storage
+            .stat(&path)
+            .await
+            .map_err(storage_error)?
+            .content_length();
+
+        let requested = headers
+            .get(hyper::header::RANGE)
+            .and_then(|range| requested_range(range, len));
+        let (status, range) = match requested {
+            None => (StatusCode::OK, 0..len),
+            Some(Ok(range)) => (StatusCode::PARTIAL_CONTENT, range),
+            Some(Err(())) => {
+                return Ok((
+                    StatusCode::RANGE_NOT_SATISFIABLE,
+                    [(hyper::header::CONTENT_RANGE, format!("bytes */{len}"))],
+                )
+                    .into_response())
+            }
+        };
+        let stream = storage
+            .reader(&path)
+            .await
+            .map_err(storage_error)?
+            .into_bytes_stream(range.clone())
+            .await
+            .map_err(storage_error)?;
+
+        let mut response = Response::builder()
+            .status(status)
+            .header(hyper::header::CONTENT_TYPE, "application/wasm")
+            .header(hyper::header::CONTENT_LENGTH, range.end - range.start)
+            .header(hyper::header::ACCEPT_RANGES, "bytes")
+            .header("x-rvm-module-hash", hash_encoding.encode(&hash));
+        if status == StatusCode::PARTIAL_CONTENT {
+            let content_range = format!("bytes {}-{}/{len}", range.start, range.end - 1);
+            response = response.header(hyper::header::CONTENT_RANGE, content_range);
+        }
+        response
+            .body(axum::body::Body::from_stream(stream))
+            .map_err(|e| RvmError::Internal(e.to_string()))
+    }
+
+    /// The bytes of a `len` bytes long artifact asked for by a `Range` header,
+    /// or `Err` if there are none. Only single byte ranges are served, the
+    /// whole artifact is sent for other headers.
+    fn requested_range(range: &HeaderValue, len: u64) -> Option<Result<std::ops::Range<u64>, ()>> {
+        let spec = range.to_str().ok()?.strip_prefix("bytes=")?;
+        if spec.contains(',') {
+            return None;
+        }
+        let (start, end) = spec.trim().split_once('-')?;
+        let range = match (start, end) {
+            ("", suffix) => len.saturating_sub(suffix.parse().ok()?)..len,
+            (start, "") => start.parse().ok()?..len,
+            (start, end) => {
+                let end: u64 = end.parse().ok()?;
+                start.parse().ok()?..end.saturating_add(1).min(len)
+            }
+        };
+        Some(match range.start < range.end {
+            true => Ok(range),
+            false => Err(()),
+        })
+    }
+
+    #[tracing::instrument(skip(state, headers))]
+    pub async fn list_versions(
+        Path(key): Path<String>,
+        State(state): State<SharedState>,
+        headers: HeaderMap,
+    ) -> Result<Json<Vec<ModuleVersion>>, StatusCode> {
+        let key = tenant::scoped_key(&headers, &key).map_err(|e| e.status())?;
+        let state = state.read().await;
+        let versions = crate::state::list_versions(&state.storage, &key)
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+        if versions.is_empty() {
+            return Err(StatusCode::NOT_FOUND);
+        }
+        Ok(versions.into())
+    }
+
+    #[tracing::instrument(skip(state, headers))]
+    pub async fn list_dead_letters(
+        Path(key): Path<String>,
+        State(state): State<SharedState>,
+        headers: HeaderMap,
+    ) -> Result<Json<Vec<DeadLetter>>, RvmError> {
+        let key = tenant::scoped_key(&headers, &key)?;
+        let storage = state.read().await.storage.clone();
+        let letters = dead_letter::list(&storage, &key).await.map_err(|e| {
+            tracing::error!("Failed to list dead letters: {e:?}");
+            RvmError::Storage(String::from("failed to list the dead letters"))
+        })?;
+        Ok(Json(letters))
+    }
+
+    /// Invokes `key` again with the request of its dead letter `id`, answering
+    /// with the module's response.
+    #[tracing::instrument(skip(state, headers))]
+    pub async fn replay_dead_letter(
+        Path((key, id)): Path<(String, String)>,
+        State(state): State<SharedState>,
+        headers: HeaderMap,
+    ) -> Result<Response, RvmError> {
+        let key = tenant::scoped_key(&headers, &key)?;
+        let storage = state.read().await.storage.clone();
+        let letter = dead_letter::read(&storage, &key, &id)
+            .await
+            .map_err(|e| {
+                tracing::error!("Failed to read dead letter: {e:?}");
+                RvmError::Storage(String::from("failed to read the dead letter"))
+            })?
+            .ok_or(RvmError::Rejected(StatusCode::NOT_FOUND))?;
+        let request = letter
+            .request
+            .into_request()
+            .map_err(|e| RvmError::BadRequest(format!("invalid dead letter: {e}")))?;
+
+        let response = invoke_module(&key, request, Scheme::Http, state, None).await?;
+        let (parts, body) = response.into_parts();
+        let body = body
+            .collect()
+            .await
+            .map_err(|e| RvmError::Trapped(format!("failed to read the response: {e}")))?
+            .to_bytes();
+        Ok(Response::from_parts(parts, axum::body::Body::from(body)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn split(path_and_query: &str) -> Option<(String, String)> {
+        split_key_and_forward(&PathAndQuery::from_str(path_and_query).unwrap())
+    }
+
+    #[test]
+    fn root_has_no_key() {
+        assert_eq!(split("/"), None);
+    }
+
+    #[test]
+    fn key_alone_forwards_root() {
+        assert_eq!(split("/key"), Some(("key".into(), "/".into())));
+    }
+
+    #[test]
+    fn key_with_trailing_slash_forwards_root() {
+        assert_eq!(split("/key/"), Some(("key".into(), "/".into())));
+    }
+
+    #[test]
+    fn rest_and_query_are_forwarded() {
+        assert_eq!(
+            split("/key/rest?q=1"),
+            Some(("key".into(), "/rest?q=1".into()))
+        );
+    }
+
+    fn authority_seen(request: hyper::Request<()>) -> (Option<String>, Uri) {
+        let mut request =
+            request.map(|()| http_body_util::Empty::new().map_err(|e| match e {}).boxed());
+        set_authority(&mut request);
+        let host = request
+            .headers()
+            .get(hyper::header::HOST)
+            .map(|host| host.to_str().unwrap().to_owned());
+        (host, request.uri().clone())
+    }
+
+    #[test]
+    fn host_header_is_kept() {
+        let request = hyper::Request::get("/key/rest")
+            .header(hyper::header::HOST, "example.com:8000")
+            .body(())
+            .unwrap();
+        let (host, uri) = authority_seen(request);
+        assert_eq!(host.as_deref(), Some("example.com:8000"));
+        assert_eq!(uri, "/key/rest");
+    }
+
+    #[test]
+    fn authority_of_absolute_uri_replaces_host() {
+        let request = hyper::Request::get("https://example.com/key/rest?q=1")
+            .header(hyper::header::HOST, "other.com")
+            .body(())
+            .unwrap();
+        let (host, uri) = authority_seen(request);
+        assert_eq!(host.as_deref(), Some("example.com"));
+        assert_eq!(uri, "/key/rest?q=1");
+    }
+
+    #[test]
+    fn missing_host_stays_missing() {
+        let request = hyper::Request::get("/key").body(()).unwrap();
+        let (host, uri) = authority_seen(request);
+        assert_eq!(host, None);
+        assert_eq!(uri, "/key");
+    }
+
+    /// A module of kind `run` answering `{}`, or trapping when given anything
+    /// longer than that, whose hash changes with `version`.
+    fn run_module(version: u32) -> Vec<u8> {
+        let wat = format!(
+            r#"
+(component
+  (core module $run
+    (memory (export "memory") 1)
+    (data (i32.const 0) "\00\00\00\00\10\00\00\00\02\00\00\00")
+    (data (i32.const 16) "{{}}")
+    (data (i32.const 32) "{version}")
+    (global $next (mut i32) (i32.const 1024))
+    (func (export "realloc") (param i32 i32 i32 i32) (result i32)
+      (local $at i32)
+      (local.set $at (global.get $next))
+      (global.set $next (i32.add (global.get $next) (local.get 3)))
+      (local.get $at))
+    (func (export "run") (param i32 i32) (result i32)
+      (if (i32.gt_u (local.get 1) (i32.const 2)) (then unreachable))
+      i32.const 0))
+  (core instance $run (instantiate $run))
+  (func $run (param "input" string) (result (result string (error string)))
+    (canon lift (core func $run "run") (memory $run "memory") (realloc (func $run "realloc"))))
+  (instance $exports (export "run" (func $run)))
+  (export "rvm:lambda/run@0.1.0" (instance $exports)))
+"#
+        );
+        wat::parse_str(wat).unwrap()
+    }
+
+    async fn invoke_status(state: &SharedState, key: &str) -> StatusCode {
+        invoke_status_with(state, key, "{}").await
+    }
+
+    async fn invoke_status_with(state: &SharedState, key: &str, body: &'static str) -> StatusCode {
+        let request = hyper::Request::post("/")
+            .header(hyper::header::HOST, "localhost")
+            .header(hyper::header::CONTENT_TYPE, "application/json")
+            .body(
+                http_body_util::Full::from(body)
+                    .map_err(|e| match e {})
+                    .boxed(),
+            )
+            .unwrap();
+        match services::invoke_module(key, request, Scheme::Http, state.clone(), None).await {
+            Ok(response) => response.status(),
+            Err(e) => e.into_response().status(),
+        }
+    }
+
+    /// A host storing its modules in memory.
+    async fn test_state(config: RvmConfig) -> SharedState {
+        let config = RvmConfig {
+            storage: config::StorageKind::Memory,
+            cache_enabled: false,
+            ..config
+        };
+        let (stopped_workers, stopped) = tokio::sync::mpsc::unbounded_channel();
+        let (local_invocations, local) = tokio::sync::mpsc::unbounded_channel();
+        let state = AppState::new(config, stopped_workers, local_invocations)
+            .await
+            .unwrap();
+        let state: SharedState = Arc::new(RwLock::new(state));
+        state::spawn_supervisor(state.clone(), stopped);
+        local::spawn_dispatcher(state.clone(), local);
+        state
+    }
+
+    #[tokio::test]
+    async fn trapped_modules_get_a_fresh_instance_for_the_next_invocation() {
+        let state = test_state(RvmConfig::default()).await;
+        let storage = state.read().await.storage.clone();
+        storage.write("echo.wasm", run_module(0)).await.unwrap();
+        state.write().await.reload_modules(false).await.unwrap();
+
+        assert_eq!(
+            invoke_status_with(&state, "echo", "[1,2]").await,
+            StatusCode::BAD_GATEWAY
+        );
+        assert_eq!(invoke_status(&state, "echo").await, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn admin_routes_only_reach_the_modules_of_the_tenant_given() {
+        let state = test_state(RvmConfig::default()).await;
+        let storage = state.read().await.storage.clone();
+        storage
+            .write("acme/echo.wasm", run_module(0))
+            .await
+            .unwrap();
+        state.write().await.reload_modules(false).await.unwrap();
+        let drain = |tenant: Option<&str>, key: &str| {
+            let mut headers = HeaderMap::new();
+            if let Some(tenant) = tenant {
+                headers.insert(tenant::X_RVM_TENANT, tenant.parse().unwrap());
+            }
+            services::drain_module(Path(key.to_owned()), State(state.clone()), headers)
+        };
+
+        assert_eq!(drain(Some("acme"), "echo").await, StatusCode::NO_CONTENT);
+        assert_eq!(drain(None, "echo").await, StatusCode::NOT_FOUND);
+        // Axum decodes `%2F`, which must not reach another tenant's modules
+        assert_eq!(drain(None, "acme/echo").await, StatusCode::BAD_REQUEST);
+        assert_eq!(drain(Some("acme"), "..").await, StatusCode::BAD_REQUEST);
+        assert_eq!(
+            drain(Some("other"), "../acme/echo").await,
+            StatusCode::BAD_REQUEST
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn invocations_during_a_reload_never_find_the_module_missing() {
+        let state = test_state(RvmConfig {
+            // Every reload leaves the module to be started by the next invocation
+            lazy_start: true,
+            ..Default::default()
+        })
+        .await;
+
+        let storage = state.read().await.storage.clone();
+        storage.write("echo.wasm", run_module(0)).await.unwrap();
+        state.write().await.reload_modules(false).await.unwrap();
+
+        let reloading = Arc::new(std::sync::atomic::AtomicBool::new(true));
+        let invokers: Vec<_> = (0..4)
+            .map(|_| {
+                let (state, reloading) = (state.clone(), reloading.clone());
+                tokio::spawn(async move {
+                    let mut statuses = Vec::new();
+                    while reloading.load(std::sync::atomic::Ordering::Relaxed) {
+                        statuses.push(invoke_status(&state, "echo").await);
+                    }
+                    statuses
+                })
+            })
+            .collect();
+
+        for version in 1..=20 {
+            match version % 2 {
+                // Redeployed by another host sharing the storage
+                0 => {
+                    storage
+                        .write("echo.wasm", run_module(version))
+                        .await
+                        .unwrap();
+                    state.write().await.reload_modules(false).await.unwrap();
+                }
+                // The worker stopped, as if it had crashed
+                _ => {
+                    let (closed, _) = tokio::sync::mpsc::channel(1);
+                    if let Some(module) = state.write().await.instances.get_mut("echo") {
+                        module.sender = Some(closed);
+                    }
+                }
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        reloading.store(false, std::sync::atomic::Ordering::Relaxed);
+
+        let mut statuses = Vec::new();
+        for invoker in invokers {
+            statuses.extend(invoker.await.unwrap());
+        }
+        assert!(!statuses.contains(&StatusCode::NOT_FOUND));
+        assert!(statuses
+            .iter()
+            .all(|status| [StatusCode::OK, StatusCode::SERVICE_UNAVAILABLE].contains(status)));
+        assert!(statuses.contains(&StatusCode::OK));
+        assert_eq!(invoke_status(&state, "echo").await, StatusCode::OK);
+    }
 }
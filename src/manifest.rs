@@ -0,0 +1,60 @@
+use std::collections::BTreeMap;
+
+use anyhow::Context;
+use wasmparser::{Parser, Payload};
+
+use crate::config::ModuleConfig;
+
+/// Name of the custom section a component can embed its deploy settings in,
+/// as a JSON object of the same settings that can be given when deploying it.
+pub const SECTION: &str = "rvm-manifest";
+
+/// The settings to deploy the component in `bytes` with: those embedded in its
+/// manifest, overridden by the ones in the deploy's `query`.
+pub fn module_config(bytes: &[u8], query: Option<&str>) -> anyhow::Result<ModuleConfig> {
+    let mut settings = match embedded(bytes) {
+        Some(manifest) => {
+            let manifest: serde_json::Map<String, serde_json::Value> =
+                serde_json::from_slice(manifest)
+                    .with_context(|| format!("invalid `{SECTION}` section"))?;
+            manifest
+                .into_iter()
+                .map(|(name, value)| match value {
+                    serde_json::Value::String(value) => Ok((name, value)),
+                    serde_json::Value::Bool(_) | serde_json::Value::Number(_) => {
+                        Ok((name, value.to_string()))
+                    }
+                    _ => anyhow::bail!("invalid `{SECTION}` section: `{name}` isn't a plain value"),
+                })
+                .collect::<anyhow::Result<BTreeMap<_, _>>>()?
+        }
+        None => BTreeMap::new(),
+    };
+    let query: Vec<(String, String)> =
+        serde_urlencoded::from_str(query.unwrap_or_default()).context("invalid query")?;
+    settings.extend(query);
+    // Parsed like a query, so both sources accept the same values
+    let settings = serde_urlencoded::to_string(&settings)?;
+    let module_config: ModuleConfig =
+        serde_urlencoded::from_str(&settings).context("invalid deploy settings")?;
+    module_config.socket_allowlist()?;
+    Ok(module_config)
+}
+
+/// The manifest of the component in `bytes`, if it has one. Sections of the
+/// modules nested in it are ignored. Invalid components have no manifest,
+/// compiling them tells what is wrong.
+fn embedded(bytes: &[u8]) -> Option<&[u8]> {
+    let mut depth = 0;
+    for payload in Parser::new(0).parse_all(bytes) {
+        match payload.ok()? {
+            Payload::Version { .. } => depth += 1,
+            Payload::End(_) => depth -= 1,
+            Payload::CustomSection(section) if depth == 1 && section.name() == SECTION => {
+                return Some(section.data());
+            }
+            _ => {}
+        }
+    }
+    None
+}
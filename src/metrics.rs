@@ -0,0 +1,147 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Write,
+    sync::Mutex,
+};
+
+/// Metrics that modules record through the `host` interface, by module key.
+///
+/// Each module can use at most `max_names` distinct names, so a module
+/// deriving names from its input can't blow up the size of `/metrics`.
+pub struct GuestMetrics {
+    max_names: usize,
+    modules: Mutex<HashMap<String, HashMap<String, Metric>>>,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Metric {
+    Counter(u64),
+    Summary { sum: f64, count: u64 },
+}
+
+impl GuestMetrics {
+    pub fn new(max_names: usize) -> Self {
+        Self {
+            max_names,
+            modules: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Adds `value` to the counter `name` of `key`.
+    pub fn incr(&self, key: &str, name: &str, value: u64) {
+        self.record(key, name, Metric::Counter(value), |metric| match metric {
+            Metric::Counter(total) => {
+                *total = total.saturating_add(value);
+                true
+            }
+            Metric::Summary { .. } => false,
+        });
+    }
+
+    /// Records an observation of `value` in the summary `name` of `key`.
+    pub fn observe(&self, key: &str, name: &str, value: f64) {
+        if !value.is_finite() {
+            tracing::warn!(key=%key, "Ignoring non-finite observation of metric `{name}`");
+            return;
+        }
+        let observed = Metric::Summary {
+            sum: value,
+            count: 1,
+        };
+        self.record(key, name, observed, |metric| match metric {
+            Metric::Summary { sum, count } => {
+                *sum += value;
+                *count += 1;
+                true
+            }
+            Metric::Counter(_) => false,
+        });
+    }
+
+    /// Forgets the metrics of `key`, once it is removed.
+    pub fn remove(&self, key: &str) {
+        self.modules.lock().unwrap().remove(key);
+    }
+
+    /// Updates the metric `name` of `key`, or starts it at `new` if it has room
+    /// for another name. `update` tells whether the existing metric is of the
+    /// same kind.
+    fn record(&self, key: &str, name: &str, new: Metric, update: impl FnOnce(&mut Metric) -> bool) {
+        if !is_metric_name(name) {
+            tracing::warn!(key=%key, "Ignoring metric with invalid name `{name}`");
+            return;
+        }
+        let mut modules = self.modules.lock().unwrap();
+        let metrics = modules.entry(key.to_owned()).or_default();
+        let full = metrics.len() >= self.max_names;
+        match metrics.get_mut(name) {
+            Some(metric) => {
+                if !update(metric) {
+                    tracing::warn!(key=%key, "Ignoring metric `{name}` recorded as another kind");
+                }
+            }
+            None if full => {
+                let max = self.max_names;
+                tracing::warn!(key=%key, "Ignoring metric `{name}`, the module has {max} already");
+            }
+            None => {
+                metrics.insert(name.to_owned(), new);
+            }
+        }
+    }
+
+    /// The metrics in the Prometheus text format, as `rvm_guest_{name}` with
+    /// the module's key as label. Counters get a `_total` suffix.
+    pub fn render(&self) -> String {
+        let modules = self.modules.lock().unwrap();
+        let mut families: BTreeMap<String, Vec<(&str, Metric)>> = BTreeMap::new();
+        for (key, metrics) in modules.iter() {
+            for (name, metric) in metrics {
+                let family = match metric {
+                    Metric::Counter(_) => format!("rvm_guest_{name}_total"),
+                    Metric::Summary { .. } => format!("rvm_guest_{name}"),
+                };
+                families.entry(family).or_default().push((key, *metric));
+            }
+        }
+
+        let mut rendered = String::new();
+        for (family, mut metrics) in families {
+            metrics.sort_by_key(|(key, _)| *key);
+            let kind = metrics[0].1.kind();
+            let _ = writeln!(rendered, "# TYPE {family} {kind}");
+            // A counter `x` and a summary `x_total` of another module share a family
+            for (key, metric) in metrics.into_iter().filter(|(_, m)| m.kind() == kind) {
+                let _ = match metric {
+                    Metric::Counter(total) => {
+                        writeln!(rendered, "{family}{{key=\"{key}\"}} {total}")
+                    }
+                    Metric::Summary { sum, count } => writeln!(
+                        rendered,
+                        "{family}_sum{{key=\"{key}\"}} {sum}\n\
+                         {family}_count{{key=\"{key}\"}} {count}"
+                    ),
+                };
+            }
+        }
+        rendered
+    }
+}
+
+impl Metric {
+    fn kind(&self) -> &'static str {
+        match self {
+            Metric::Counter(_) => "counter",
+            Metric::Summary { .. } => "summary",
+        }
+    }
+}
+
+/// Whether `name` can be used in a Prometheus metric name.
+fn is_metric_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
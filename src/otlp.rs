@@ -0,0 +1,327 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::Context as _;
+use http_body_util::{BodyExt, Full};
+use hyper::Uri;
+use tokio::sync::mpsc;
+use tracing::{
+    field::{Field, Visit},
+    span, Subscriber,
+};
+use tracing_subscriber::{layer::Context, registry::LookupSpan, Layer};
+use wasmtime_wasi_http::types::{default_send_request_handler, OutgoingRequestConfig};
+
+/// Most spans waiting to be exported, more are dropped until the collector catches up.
+const QUEUE_CAPACITY: usize = 4096;
+/// Most spans sent to the collector at once.
+const BATCH_SIZE: usize = 512;
+/// How long a span waits for a batch to fill up before it is sent anyway.
+const BATCH_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Exports the spans of rvm to an OpenTelemetry collector, over OTLP/HTTP in
+/// its JSON encoding, see [`layer`].
+pub struct OtlpLayer {
+    spans: mpsc::Sender<SpanData>,
+}
+
+/// A span that closed, with the fields recorded on it as attributes.
+#[derive(Debug, Clone)]
+pub struct SpanData {
+    trace_id: [u8; 16],
+    span_id: [u8; 8],
+    parent_span_id: Option<[u8; 8]>,
+    name: &'static str,
+    start: SystemTime,
+    end: SystemTime,
+    attributes: Vec<(&'static str, Value)>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Value {
+    String(String),
+    Int(i64),
+    Double(f64),
+    Bool(bool),
+}
+
+/// Exports the spans of rvm to the collector at `endpoint`, e.g.
+/// `http://localhost:4318`, from a task of its own.
+pub fn layer(endpoint: &str) -> anyhow::Result<OtlpLayer> {
+    let traces = format!("{}/v1/traces", endpoint.trim_end_matches('/'));
+    let traces: Uri = traces
+        .parse()
+        .with_context(|| format!("invalid OTLP endpoint `{endpoint}`"))?;
+    anyhow::ensure!(
+        traces.authority().is_some(),
+        "invalid OTLP endpoint `{endpoint}`, it has no host"
+    );
+    let (spans, mut queued) = mpsc::channel(QUEUE_CAPACITY);
+    tokio::spawn(async move {
+        let mut batch = Vec::with_capacity(BATCH_SIZE);
+        loop {
+            let deadline = tokio::time::sleep(BATCH_INTERVAL);
+            tokio::pin!(deadline);
+            let closed = loop {
+                tokio::select! {
+                    span = queued.recv() => match span {
+                        Some(span) => batch.push(span),
+                        None => break true,
+                    },
+                    _ = &mut deadline => break false,
+                }
+                if batch.len() >= BATCH_SIZE {
+                    break false;
+                }
+            };
+            if !batch.is_empty() {
+                let spans = batch.len();
+                if let Err(e) = export(&traces, std::mem::take(&mut batch)).await {
+                    tracing::warn!("Failed to export {spans} spans: {e:#}");
+                }
+            }
+            if closed {
+                return;
+            }
+        }
+    });
+    Ok(OtlpLayer { spans })
+}
+
+/// Sends `spans` to the collector at `traces`.
+async fn export(traces: &Uri, spans: Vec<SpanData>) -> anyhow::Result<()> {
+    let body = serde_json::to_vec(&payload(&spans))?;
+    let authority = traces.authority().context("no host")?.as_str();
+    let request = hyper::Request::post(traces.clone())
+        .header(hyper::header::HOST, authority)
+        .header(hyper::header::CONTENT_TYPE, "application/json")
+        .body(Full::from(body).map_err(|e| match e {}).boxed())?;
+    let config = OutgoingRequestConfig {
+        use_tls: traces.scheme() == Some(&hyper::http::uri::Scheme::HTTPS),
+        connect_timeout: Duration::from_secs(10),
+        first_byte_timeout: Duration::from_secs(10),
+        between_bytes_timeout: Duration::from_secs(10),
+    };
+    let response = default_send_request_handler(request, config)
+        .await
+        .map_err(|e| anyhow::anyhow!("{e:?}"))?;
+    // Keeps the connection alive while the response is read
+    let _worker = response.worker;
+    let status = response.resp.status();
+    anyhow::ensure!(status.is_success(), "the collector responded with {status}");
+    Ok(())
+}
+
+/// The OTLP `ExportTraceServiceRequest` of `spans`, in its JSON encoding.
+fn payload(spans: &[SpanData]) -> serde_json::Value {
+    let spans: Vec<_> = spans.iter().map(SpanData::to_json).collect();
+    serde_json::json!({
+        "resourceSpans": [{
+            "resource": {
+                "attributes": [attribute("service.name", &Value::String(String::from("rvm")))],
+            },
+            "scopeSpans": [{
+                "scope": { "name": env!("CARGO_CRATE_NAME"), "version": env!("CARGO_PKG_VERSION") },
+                "spans": spans,
+            }],
+        }],
+    })
+}
+
+fn attribute(key: &str, value: &Value) -> serde_json::Value {
+    let value = match value {
+        Value::String(value) => serde_json::json!({ "stringValue": value }),
+        // 64 bit integers are encoded as strings
+        Value::Int(value) => serde_json::json!({ "intValue": value.to_string() }),
+        Value::Double(value) => serde_json::json!({ "doubleValue": value }),
+        Value::Bool(value) => serde_json::json!({ "boolValue": value }),
+    };
+    serde_json::json!({ "key": key, "value": value })
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+fn unix_nanos(time: SystemTime) -> String {
+    let nanos = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    nanos.as_nanos().to_string()
+}
+
+impl SpanData {
+    fn to_json(&self) -> serde_json::Value {
+        // Invocations answered with a server error failed, anything else is left unset
+        let failed = self.attributes.iter().any(|(name, value)| {
+            *name == "status" && matches!(value, Value::Int(status) if *status >= 500)
+        });
+        let mut span = serde_json::json!({
+            "traceId": hex(&self.trace_id),
+            "spanId": hex(&self.span_id),
+            "name": self.name,
+            // SPAN_KIND_SERVER
+            "kind": 2,
+            "startTimeUnixNano": unix_nanos(self.start),
+            "endTimeUnixNano": unix_nanos(self.end),
+            "attributes": self
+                .attributes
+                .iter()
+                .map(|(name, value)| attribute(name, value))
+                .collect::<Vec<_>>(),
+            // STATUS_CODE_ERROR or STATUS_CODE_UNSET
+            "status": { "code": if failed { 2 } else { 0 } },
+        });
+        if let Some(parent) = &self.parent_span_id {
+            span["parentSpanId"] = hex(parent).into();
+        }
+        span
+    }
+
+    fn set(&mut self, name: &'static str, value: Value) {
+        match self.attributes.iter_mut().find(|(set, _)| *set == name) {
+            Some((_, set)) => *set = value,
+            None => self.attributes.push((name, value)),
+        }
+    }
+}
+
+impl Visit for SpanData {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.set(field.name(), Value::String(value.to_owned()));
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.set(field.name(), Value::Int(value));
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.set(
+            field.name(),
+            Value::Int(value.try_into().unwrap_or(i64::MAX)),
+        );
+    }
+
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.set(field.name(), Value::Double(value));
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.set(field.name(), Value::Bool(value));
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        self.set(field.name(), Value::String(format!("{value:?}")));
+    }
+}
+
+impl<S> Layer<S> for OtlpLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &span::Attributes<'_>, id: &span::Id, ctx: Context<'_, S>) {
+        // Only the spans of rvm itself, not those of the crates it uses
+        if !attrs
+            .metadata()
+            .target()
+            .starts_with(env!("CARGO_CRATE_NAME"))
+        {
+            return;
+        }
+        let Some(span) = ctx.span(id) else { return };
+        let parent = span
+            .parent()
+            .and_then(|parent| parent.extensions().get::<SpanData>().cloned());
+        let random = uuid::Uuid::new_v4().into_bytes();
+        let span_id = random[..8].try_into().expect("8 bytes");
+        let mut data = SpanData {
+            trace_id: match &parent {
+                Some(parent) => parent.trace_id,
+                None => uuid::Uuid::new_v4().into_bytes(),
+            },
+            span_id,
+            parent_span_id: parent.map(|parent| parent.span_id),
+            name: attrs.metadata().name(),
+            start: SystemTime::now(),
+            end: SystemTime::now(),
+            attributes: Vec::new(),
+        };
+        attrs.record(&mut data);
+        span.extensions_mut().insert(data);
+    }
+
+    fn on_record(&self, id: &span::Id, values: &span::Record<'_>, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(id) else { return };
+        let mut extensions = span.extensions_mut();
+        if let Some(data) = extensions.get_mut::<SpanData>() {
+            values.record(data);
+        }
+    }
+
+    fn on_close(&self, id: span::Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(&id) else { return };
+        let Some(mut data) = span.extensions_mut().remove::<SpanData>() else {
+            return;
+        };
+        data.end = SystemTime::now();
+        if self.spans.try_send(data).is_err() {
+            tracing::debug!("Dropped span, the OTLP exporter is falling behind");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tracing_subscriber::layer::SubscriberExt;
+
+    use super::*;
+
+    #[tracing::instrument(fields(status = tracing::field::Empty))]
+    fn invoke(key: &str, status: u16) {
+        tracing::Span::current().record("status", status);
+        child();
+    }
+
+    #[tracing::instrument]
+    fn child() {}
+
+    /// The spans recorded while running `f`, as exported.
+    fn exported(f: impl FnOnce()) -> Vec<serde_json::Value> {
+        let (spans, mut closed) = mpsc::channel(16);
+        let subscriber = tracing_subscriber::registry().with(OtlpLayer { spans });
+        tracing::subscriber::with_default(subscriber, f);
+        let mut exported = Vec::new();
+        while let Ok(span) = closed.try_recv() {
+            exported.push(span);
+        }
+        payload(&exported)["resourceSpans"][0]["scopeSpans"][0]["spans"]
+            .as_array()
+            .unwrap()
+            .clone()
+    }
+
+    fn attribute_of<'a>(span: &'a serde_json::Value, key: &str) -> &'a serde_json::Value {
+        let attributes = span["attributes"].as_array().unwrap();
+        let attribute = attributes.iter().find(|a| a["key"] == key).unwrap();
+        &attribute["value"]
+    }
+
+    #[test]
+    fn spans_are_exported_with_their_fields_as_attributes() {
+        let spans = exported(|| invoke("echo", 200));
+        let [child, invoke] = &spans[..] else {
+            panic!("expected two spans, got {spans:?}");
+        };
+        assert_eq!(invoke["name"], "invoke");
+        assert_eq!(attribute_of(invoke, "status")["intValue"], "200");
+        assert_eq!(attribute_of(invoke, "key")["stringValue"], "echo");
+        assert_eq!(invoke["status"]["code"], 0);
+        assert_eq!(child["traceId"], invoke["traceId"]);
+        assert_eq!(child["parentSpanId"], invoke["spanId"]);
+        assert!(invoke.get("parentSpanId").is_none());
+    }
+
+    #[test]
+    fn spans_of_server_errors_are_failed() {
+        let spans = exported(|| invoke("echo", 503));
+        let invoke = spans.iter().find(|span| span["name"] == "invoke").unwrap();
+        assert_eq!(invoke["status"]["code"], 2);
+    }
+}
@@ -0,0 +1,54 @@
+use std::{
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// A token bucket limiting how often a module is invoked.
+pub struct RateLimiter {
+    /// Tokens added per second.
+    rate: f64,
+    /// Most tokens the bucket holds, i.e. the largest burst of requests allowed.
+    burst: f64,
+    bucket: Mutex<Bucket>,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl RateLimiter {
+    /// Allows `rate` requests per second on average, and bursts of up to `burst` requests.
+    pub fn new(rate: f64, burst: u32) -> Self {
+        let burst = f64::from(burst.max(1));
+        Self {
+            rate,
+            burst,
+            bucket: Mutex::new(Bucket {
+                tokens: burst,
+                updated: Instant::now(),
+            }),
+        }
+    }
+
+    /// Whether `other` allows the same rate and bursts as this limiter.
+    pub fn has_limits_of(&self, other: &RateLimiter) -> bool {
+        self.rate == other.rate && self.burst == other.burst
+    }
+
+    /// Takes a token for a request, or returns how long to wait until one is available.
+    pub fn check(&self) -> Result<(), Duration> {
+        let mut bucket = self.bucket.lock().unwrap();
+        let now = Instant::now();
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.rate).min(self.burst);
+        bucket.updated = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / self.rate))
+        }
+    }
+}
@@ -0,0 +1,193 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use tokio::sync::oneshot;
+
+/// Hands out the permits for the invocations in flight across all modules.
+///
+/// Once they run out, invocations wait in a queue per module, and freed permits
+/// go to the queues in turn rather than to whoever asked first, so a busy module
+/// can't starve the others.
+pub struct FairScheduler {
+    limit: usize,
+    inner: Mutex<Inner>,
+}
+
+struct Inner {
+    available: usize,
+    /// Invocations waiting for a permit, by module, in the order their modules
+    /// are handed the next permits.
+    queues: VecDeque<(String, VecDeque<oneshot::Sender<Permit>>)>,
+    waits: HashMap<String, WaitStats>,
+}
+
+/// How long the invocations of a module have waited for a permit.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct WaitStats {
+    pub total: Duration,
+    pub invocations: u64,
+}
+
+/// Lets an invocation run, handing its place to the next one when dropped.
+pub struct Permit {
+    /// Unset for a permit that was never handed out.
+    scheduler: Option<Arc<FairScheduler>>,
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        if let Some(scheduler) = self.scheduler.take() {
+            scheduler.release();
+        }
+    }
+}
+
+impl FairScheduler {
+    pub fn new(limit: usize) -> Arc<Self> {
+        Arc::new(Self {
+            limit,
+            inner: Mutex::new(Inner {
+                available: limit,
+                queues: VecDeque::new(),
+                waits: HashMap::new(),
+            }),
+        })
+    }
+
+    /// Waits up to `max_wait` for a permit to invoke `key` with.
+    pub async fn acquire(self: &Arc<Self>, key: &str, max_wait: Duration) -> Option<Permit> {
+        let started = Instant::now();
+        let (tx, rx) = oneshot::channel();
+        {
+            let mut inner = self.inner.lock().unwrap();
+            if inner.available > 0 && inner.queues.is_empty() {
+                inner.available -= 1;
+                inner.record(key, Duration::ZERO);
+                return Some(Permit {
+                    scheduler: Some(self.clone()),
+                });
+            }
+            if max_wait.is_zero() {
+                return None;
+            }
+            match inner.queues.iter_mut().find(|(queued, _)| queued == key) {
+                Some((_, waiters)) => waiters.push_back(tx),
+                None => inner
+                    .queues
+                    .push_back((key.to_owned(), VecDeque::from([tx]))),
+            }
+        }
+        let mut waiting = Waiting {
+            scheduler: self,
+            key,
+            rx,
+        };
+        let permit = tokio::time::timeout(max_wait, &mut waiting.rx)
+            .await
+            .ok()?
+            .ok()?;
+        self.inner.lock().unwrap().record(key, started.elapsed());
+        Some(permit)
+    }
+
+    /// Hands a freed permit to the module whose turn it is.
+    fn release(self: &Arc<Self>) {
+        let mut inner = self.inner.lock().unwrap();
+        while let Some((key, mut waiters)) = inner.queues.pop_front() {
+            let Some(waiter) = waiters.pop_front() else {
+                continue;
+            };
+            if !waiters.is_empty() {
+                inner.queues.push_back((key, waiters));
+            }
+            let permit = Permit {
+                scheduler: Some(self.clone()),
+            };
+            match waiter.send(permit) {
+                Ok(()) => return,
+                // Gave up waiting, try the next one instead of releasing it again
+                Err(mut permit) => permit.scheduler = None,
+            }
+        }
+        inner.available += 1;
+    }
+
+    /// Invocations currently holding a permit.
+    pub fn in_flight(&self) -> usize {
+        self.limit - self.inner.lock().unwrap().available
+    }
+
+    /// Invocations waiting for a permit.
+    pub fn waiting(&self) -> usize {
+        let inner = self.inner.lock().unwrap();
+        inner.queues.iter().map(|(_, waiters)| waiters.len()).sum()
+    }
+
+    pub fn waits(&self, key: &str) -> WaitStats {
+        let inner = self.inner.lock().unwrap();
+        inner.waits.get(key).copied().unwrap_or_default()
+    }
+
+    /// Forgets how long the invocations of `key` waited, once it is removed.
+    pub fn remove(&self, key: &str) {
+        self.inner.lock().unwrap().waits.remove(key);
+    }
+}
+
+/// An invocation waiting in the queue of `key`, which leaves the queue when it
+/// stops waiting, having timed out or been cancelled.
+struct Waiting<'a> {
+    scheduler: &'a FairScheduler,
+    key: &'a str,
+    rx: oneshot::Receiver<Permit>,
+}
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        // A permit sent meanwhile is released once `rx` is dropped, after the lock
+        self.rx.close();
+        let mut inner = self.scheduler.inner.lock().unwrap();
+        if let Some(at) = inner.queues.iter().position(|(key, _)| key == self.key) {
+            let waiters = &mut inner.queues[at].1;
+            waiters.retain(|waiter| !waiter.is_closed());
+            if waiters.is_empty() {
+                inner.queues.remove(at);
+            }
+        }
+    }
+}
+
+impl Inner {
+    fn record(&mut self, key: &str, waited: Duration) {
+        let stats = self.waits.entry(key.to_owned()).or_default();
+        stats.total += waited;
+        stats.invocations += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn invocations_that_stop_waiting_leave_the_queue() {
+        let scheduler = FairScheduler::new(1);
+        let permit = scheduler.acquire("a", Duration::ZERO).await.unwrap();
+
+        let timed_out = scheduler.acquire("a", Duration::from_millis(10)).await;
+        assert!(timed_out.is_none());
+        let cancelled = tokio::time::timeout(
+            Duration::from_millis(10),
+            scheduler.acquire("b", Duration::from_secs(60)),
+        )
+        .await;
+        assert!(cancelled.is_err());
+        assert_eq!(scheduler.waiting(), 0);
+
+        drop(permit);
+        assert_eq!(scheduler.in_flight(), 0);
+    }
+}
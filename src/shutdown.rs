@@ -0,0 +1,93 @@
+use std::{sync::Arc, time::Duration};
+
+use tokio::sync::watch;
+
+use crate::{
+    fuel::{self, FuelSink},
+    state::SharedState,
+};
+
+/// Whether rvm is shutting down, asked for with a signal or `POST /shutdown`.
+#[derive(Clone)]
+pub struct Shutdown(Arc<watch::Sender<bool>>);
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Self(Arc::new(watch::Sender::new(false)))
+    }
+}
+
+impl Shutdown {
+    /// Starts shutting down, returning whether it had already started.
+    pub fn begin(&self) -> bool {
+        self.0.send_replace(true)
+    }
+
+    pub fn is_started(&self) -> bool {
+        *self.0.borrow()
+    }
+
+    /// Waits until shutting down starts.
+    pub async fn started(&self) {
+        let mut started = self.0.subscribe();
+        // The sender lives as long as `self`
+        let _ = started.wait_for(|started| *started).await;
+    }
+}
+
+/// Starts shutting down on `SIGTERM` or `Ctrl-C`.
+pub fn spawn_signal_handler(shutdown: Shutdown) {
+    tokio::spawn(async move {
+        #[cfg(unix)]
+        let terminate = async {
+            let mut terminate =
+                tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+                    .expect("failed to listen for SIGTERM");
+            terminate.recv().await;
+        };
+        #[cfg(not(unix))]
+        let terminate = std::future::pending::<()>();
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => tracing::info!("Received Ctrl-C"),
+            _ = terminate => tracing::info!("Received SIGTERM"),
+        }
+        shutdown.begin();
+    });
+}
+
+/// Once shutting down, waits up to `timeout` for the invocations in flight
+/// and the uploads of modules deployed in the background to finish, then
+/// persists the fuel totals and exits.
+pub fn spawn_drain(state: SharedState, shutdown: Shutdown, timeout: Duration) {
+    tokio::spawn(async move {
+        shutdown.started().await;
+        tracing::info!("Shutting down, draining invocations in flight");
+        let drained = tokio::time::timeout(timeout, async {
+            loop {
+                {
+                    let state = state.read().await;
+                    let uploaded = state.instances.keys().all(|key| state.is_persisted(key));
+                    if state.invocations.in_flight() == 0
+                        && state.invocations.waiting() == 0
+                        && uploaded
+                    {
+                        break;
+                    }
+                }
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+        })
+        .await;
+        if drained.is_err() {
+            tracing::warn!("Gave up draining after {}s", timeout.as_secs());
+        }
+
+        if state.read().await.config.fuel_sink == FuelSink::Storage {
+            if let Err(e) = fuel::flush(&state).await {
+                tracing::error!("Failed to persist fuel totals: {e}");
+            }
+        }
+        tracing::info!("Shut down");
+        std::process::exit(0);
+    });
+}
@@ -1,83 +1,1924 @@
-use std::{collections::HashMap, sync::Arc};
+use std::{
+    collections::{BTreeSet, HashMap, HashSet},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
 
+use anyhow::Context as _;
+use axum::body::Bytes;
 use opendal::EntryMode;
-use tokio::sync::{mpsc, RwLock};
-use wasmtime::*;
+use tokio::sync::{mpsc, OwnedSemaphorePermit, RwLock, Semaphore};
+use wasmtime::{component::Component, *};
 
-use crate::{compile_and_start_instance_worker, host::RvmState, InvokeRequest};
+use crate::{
+    audit::{self, AuditEvent},
+    budget::{self, CpuUsage},
+    cache::ResponseCache,
+    compile_and_start_instance_worker,
+    config::{ModuleConfig, ModuleKind, RvmConfig, StorageKind},
+    fuel::{self, FuelMeter, FuelSink, HostCallMeter, HostCalls},
+    host::{Dependency, ErrorLog, HostComponent, ModuleOutput, RvmState},
+    local::{LocalInvocation, LocalInvoker},
+    metrics::GuestMetrics,
+    rate_limit::RateLimiter,
+    scheduler::FairScheduler,
+    shutdown::Shutdown,
+    stdio::GuestStdio,
+    tenant,
+    transform::Pipeline,
+    InvokeRequest,
+};
 
 pub type SharedState = Arc<RwLock<AppState>>;
 pub struct AppState {
     pub engine: wasmtime::Engine,
-    pub instances: HashMap<String, tokio::sync::mpsc::UnboundedSender<InvokeRequest>>,
+    pub instances: HashMap<String, ModuleHandle>,
+    /// Compiled components by hash, shared by every module deployed with the same bytes.
+    pub components: HashMap<blake3::Hash, Arc<Component>>,
+    /// Fuel consumed by each module key, across all of its deploys.
+    pub fuel_consumed: HashMap<String, Arc<AtomicU64>>,
+    /// Time spent running invocations of each module key, across all of its deploys.
+    pub cpu_usage: HashMap<String, Arc<CpuUsage>>,
+    /// Instances of each module key replaced after serving `recycle_after_requests`,
+    /// across all of its deploys.
+    pub instances_recycled: HashMap<String, Arc<AtomicU64>>,
+    /// Calls of each host function by each module key, across all of its deploys.
+    pub host_calls: HashMap<String, Arc<HostCalls>>,
+    /// Limits how often each module key is invoked, across all of its deploys
+    /// with the same limits.
+    pub rate_limiters: HashMap<String, Arc<RateLimiter>>,
+    /// Orders the stores of each module key, across all of its deploys.
+    pub stores: HashMap<String, Arc<StoreOrder>>,
+    /// Keeps the blobs versions are being stored with from being collected.
+    pub blob_lock: BlobLock,
     pub storage: opendal::Operator,
-    pub linker: wasmtime::component::Linker<RvmState>
+    pub linker: wasmtime::component::Linker<RvmState>,
+    /// Keys that route invocations to a weighted choice of modules, by name.
+    pub aliases: HashMap<String, Alias>,
+    /// Permits for the invocations in flight, across all modules.
+    pub invocations: Arc<FairScheduler>,
+    /// Permits for the deploys in progress, see `max_concurrent_deploys`.
+    pub deploys: Arc<Semaphore>,
+    /// Applied around every invocation.
+    pub transforms: Arc<Pipeline>,
+    pub cache: Arc<ResponseCache>,
+    /// Metrics recorded by the modules themselves.
+    pub guest_metrics: Arc<GuestMetrics>,
+    /// Receives the keys of modules whose worker stopped, see [`spawn_supervisor`].
+    pub stopped_workers: mpsc::UnboundedSender<String>,
+    /// Dispatches the invocations modules make of each other.
+    pub local: LocalInvoker,
+    pub shutdown: Shutdown,
+    pub config: RvmConfig,
+}
+
+/// A deployed module.
+pub struct ModuleHandle {
+    /// Sends requests to the module's worker, which gets killed when this is dropped.
+    /// `None` while the module is idle and its instance has been torn down.
+    pub sender: Option<mpsc::Sender<InvokeRequest>>,
+    pub hash: blake3::Hash,
+    /// Most invocations that have been waiting for the worker at once.
+    pub queue_high_water: AtomicUsize,
+    /// When the module was last invoked, or started.
+    pub last_invoked: Mutex<Instant>,
+    /// Whether the module takes invocations.
+    pub readiness: Readiness,
+    /// The most recent failure of the module.
+    pub errors: ErrorLog,
+    pub config: ModuleConfig,
+    pub stdio: GuestStdio,
+    /// Limits how often the module is invoked, if configured to.
+    pub rate_limiter: Option<Arc<RateLimiter>>,
+    pub origin: ModuleOrigin,
+}
+
+/// Orders the stores of a module key, so the version deployed last is the one
+/// made live, however long the uploads of the versions before it take.
+#[derive(Default)]
+pub struct StoreOrder {
+    /// Held by the store whose turn it is, one version is stored at a time.
+    storing: Arc<tokio::sync::Mutex<()>>,
+    /// Number of the store started last.
+    latest: AtomicU64,
+    /// Whether the module running as the key isn't in storage, and won't
+    /// survive restarts. Only set while, or after failing, uploading in the background.
+    unpersisted: AtomicBool,
+}
+
+/// The turn of a store to write a version of its key, see [`AppState::start_store`].
+pub struct StoreTurn {
+    order: Arc<StoreOrder>,
+    number: u64,
+    _storing: tokio::sync::OwnedMutexGuard<()>,
+}
+
+impl StoreTurn {
+    /// Whether the key was deployed again after this store started, whose
+    /// store makes its own version live instead.
+    pub fn superseded(&self) -> bool {
+        self.order.latest.load(Ordering::Relaxed) != self.number
+    }
+
+    /// Records that the version is live in storage, marking the module as
+    /// persisted unless it was deployed again meanwhile.
+    pub fn stored(&self) {
+        if !self.superseded() {
+            self.order.unpersisted.store(false, Ordering::Relaxed);
+        }
+    }
+}
+
+/// How a module came to run in this process.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ModuleOrigin {
+    /// Started from storage, on startup or when reloading.
+    Restored,
+    /// Deployed, or rolled back, through the admin API.
+    Deployed,
+}
+
+/// Whether a module takes invocations.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Readiness {
+    /// Being started, by its first invocation since it was idle or after its
+    /// worker stopped, which the invocations arriving meanwhile are told to
+    /// retry after.
+    Starting,
+    /// Running, or idle and started on its next invocation.
+    Ready,
+    /// Failed to start when last woken up or restarted, which the next
+    /// invocation retries.
+    Failed,
+    /// Refusing new invocations, while those in flight complete.
+    Draining,
+}
+
+impl ModuleHandle {
+    /// Invocations waiting for the worker to pick them up.
+    pub fn queued(&self) -> usize {
+        self.sender
+            .as_ref()
+            .map_or(0, |sender| sender.max_capacity() - sender.capacity())
+    }
+}
+
+/// A key whose invocations are split between other modules, e.g. for canary deploys.
+pub struct Alias {
+    /// Modules by key, with their share of the invocations.
+    backends: Vec<(String, u32)>,
+    total_weight: u32,
+}
+
+impl Alias {
+    /// Returns `None` unless at least one backend has a positive weight.
+    pub fn new(backends: impl IntoIterator<Item = (String, u32)>) -> Option<Self> {
+        let backends: Vec<_> = backends
+            .into_iter()
+            .filter(|(_, weight)| *weight > 0)
+            .collect();
+        let total_weight = backends
+            .iter()
+            .try_fold(0u32, |total, (_, weight)| total.checked_add(*weight))?;
+        (total_weight > 0).then_some(Self {
+            backends,
+            total_weight,
+        })
+    }
+
+    /// Picks a backend at random, weighted by their shares.
+    pub fn pick(&self) -> &str {
+        use cap_rand::Rng;
+        let mut n =
+            cap_rand::thread_rng(cap_rand::ambient_authority()).gen_range(0..self.total_weight);
+        for (key, weight) in &self.backends {
+            if n < *weight {
+                return key;
+            }
+            n -= weight;
+        }
+        unreachable!("weights add up to the total")
+    }
+}
+
+/// A previously deployed version of a module that is kept around for rollbacks.
+#[derive(serde::Serialize)]
+pub struct ModuleVersion {
+    pub hash: String,
+    /// When this version was stored, in RFC 3339 format.
+    pub deployed_at: Option<String>,
+}
+
+/// Modules that changed when reloading them from storage.
+#[derive(Default, serde::Serialize)]
+pub struct ReloadSummary {
+    pub added: Vec<String>,
+    pub restarted: Vec<String>,
+    pub removed: Vec<String>,
+}
+
+/// Compiles the component in `bytes`, or loads it if it was precompiled with
+/// `wasmtime compile`.
+fn compile_component(engine: &Engine, bytes: &[u8]) -> Result<Component> {
+    if Engine::detect_precompiled(bytes).is_none() {
+        return Component::from_binary(engine, bytes);
+    }
+    // SAFETY: precompiled artifacts are native code run as is, they're trusted
+    // like everything else deployed through the admin API. Wasmtime checks that
+    // they were built by the same version and with the same settings, e.g. with
+    // fuel support, refusing them otherwise.
+    unsafe { Component::deserialize(engine, bytes) }.context(
+        "the precompiled artifact can't be run by this engine, precompile it with the \
+         same wasmtime version and settings as RVM or deploy the wasm component instead",
+    )
+}
+
+/// Path marking that `key` was deployed at version `hash`. Its component is
+/// the blob of the same hash.
+pub fn version_path(key: &str, hash: &blake3::Hash) -> String {
+    format!("versions/{key}/{hash}.wasm")
+}
+
+/// Path of the stored component with `hash`, shared by every key and version
+/// deployed with it.
+pub fn blob_path(hash: impl std::fmt::Display) -> String {
+    format!("blobs/{hash}.wasm")
+}
+
+/// The keys of the modules in `storage`, those in `restore_order` first and
+/// the others in order of their keys, so modules are loaded in the same order
+/// whatever the storage lists first.
+pub async fn stored_keys<'a>(
+    storage: &opendal::Operator,
+    restore_order: impl Iterator<Item = &'a str>,
+) -> opendal::Result<Vec<String>> {
+    let mut entries = storage.list("").await?;
+    // The modules of a tenant are stored in a directory named after it
+    let tenants: Vec<_> = entries
+        .iter()
+        .filter(|entry| matches!(entry.metadata().mode(), EntryMode::DIR))
+        .filter(|entry| tenant::is_tenant(entry.name().trim_end_matches('/')))
+        .map(|entry| entry.path().to_owned())
+        .collect();
+    for tenant in tenants {
+        entries.extend(storage.list(&tenant).await?);
+    }
+    let mut names = BTreeSet::new();
+    for module_entry in entries {
+        // Settings are stored next to the modules, in `{key}.json`
+        if !matches!(module_entry.metadata().mode(), EntryMode::FILE) {
+            continue;
+        }
+        let path = module_entry.path();
+        if let Some(name) = path
+            .strip_suffix(".hash")
+            .or_else(|| path.strip_suffix(".wasm"))
+        {
+            names.insert(name.to_owned());
+        }
+    }
+    let mut ordered: Vec<String> = restore_order.filter_map(|key| names.take(key)).collect();
+    ordered.extend(names);
+    Ok(ordered)
+}
+
+/// The component currently deployed as `key`, the blob named by `{key}.hash`.
+/// Modules stored by older versions, or copied to the storage by hand, are
+/// read from `{key}.wasm` instead.
+pub async fn read_module(storage: &opendal::Operator, key: &str) -> opendal::Result<Bytes> {
+    let (path, _) = stored_module(storage, key).await?;
+    Ok(storage.read(&path).await?.to_bytes())
+}
+
+/// Where the component currently deployed as `key` is stored, see
+/// [`read_module`], along with its hash unless it's stored as `{key}.wasm`.
+pub async fn stored_module(
+    storage: &opendal::Operator,
+    key: &str,
+) -> opendal::Result<(String, Option<blake3::Hash>)> {
+    let hash = match storage.read(&format!("{key}.hash")).await {
+        Ok(hash) => hash.to_vec(),
+        Err(e) if e.kind() == opendal::ErrorKind::NotFound => {
+            return Ok((format!("{key}.wasm"), None))
+        }
+        Err(e) => return Err(e),
+    };
+    let hash = std::str::from_utf8(&hash)
+        .ok()
+        .and_then(|hash| blake3::Hash::from_hex(hash.trim()).ok())
+        .ok_or_else(|| {
+            opendal::Error::new(
+                opendal::ErrorKind::Unexpected,
+                format!("invalid hash stored for `{key}`"),
+            )
+        })?;
+    Ok((blob_path(hash), Some(hash)))
+}
+
+/// The component of `key` at version `hash`. Versions stored by older versions
+/// hold a copy of the component rather than sharing its blob.
+pub async fn read_version(
+    storage: &opendal::Operator,
+    key: &str,
+    hash: &blake3::Hash,
+) -> opendal::Result<Bytes> {
+    let version = version_path(key, hash);
+    storage.stat(&version).await?;
+    match storage.read(&blob_path(hash)).await {
+        Ok(blob) => Ok(blob.to_bytes()),
+        Err(e) if e.kind() == opendal::ErrorKind::NotFound => {
+            Ok(storage.read(&version).await?.to_bytes())
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Stores `bytes` as the blob of `hash`, unless a module deployed with the
+/// same component already did.
+pub async fn store_blob(
+    storage: &opendal::Operator,
+    hash: &blake3::Hash,
+    bytes: Bytes,
+    chunk_bytes: usize,
+) -> Result<()> {
+    let blob = blob_path(hash);
+    match storage.stat(&blob).await {
+        Ok(_) => tracing::debug!("Blob {hash} is stored already"),
+        Err(e) if e.kind() == opendal::ErrorKind::NotFound => {
+            let len = bytes.len();
+            upload(storage, &blob, bytes, chunk_bytes).await?;
+            tracing::info!("Uploaded {len} bytes");
+        }
+        Err(e) => return Err(e.into()),
+    }
+    Ok(())
+}
+
+/// Held by versions being stored and, exclusively, by blobs being collected,
+/// so a blob isn't removed between a version referring to it being marked
+/// and the blob being found stored already.
+pub type BlobLock = Arc<tokio::sync::RwLock<()>>;
+
+/// Stores `bytes` as the version `hash` of `key`, without making it live.
+pub async fn store_version(
+    storage: &opendal::Operator,
+    blob_lock: &BlobLock,
+    key: &str,
+    hash: &blake3::Hash,
+    bytes: Bytes,
+    chunk_bytes: usize,
+) -> Result<()> {
+    let _storing = blob_lock.read().await;
+    // Marked before storing the blob, so pruning another key deployed
+    // with the same component meanwhile keeps it
+    storage
+        .write(&version_path(key, hash), Vec::<u8>::new())
+        .await?;
+    store_blob(storage, hash, bytes, chunk_bytes).await
+}
+
+/// The object at `path`, if there is one.
+async fn read_existing(
+    storage: &opendal::Operator,
+    path: &str,
+) -> opendal::Result<Option<opendal::Buffer>> {
+    match storage.read(path).await {
+        Ok(stored) => Ok(Some(stored)),
+        Err(e) if e.kind() == opendal::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Writes `stored` to `path`, or removes `path` if there was nothing to write.
+async fn restore(
+    storage: &opendal::Operator,
+    path: &str,
+    stored: Option<opendal::Buffer>,
+) -> opendal::Result<()> {
+    match stored {
+        Some(stored) => storage.write(path, stored).await.map(|_| ()),
+        None => storage.delete(path).await,
+    }
+}
+
+/// What storage held for a module of a batch before it was stored, put back
+/// if storing another module of the batch fails.
+struct Replaced {
+    key: String,
+    hash: blake3::Hash,
+    /// Whether the version was already stored, by an earlier deploy.
+    had_version: bool,
+    live: Option<opendal::Buffer>,
+    settings: Option<opendal::Buffer>,
+}
+
+impl Replaced {
+    async fn read(storage: &opendal::Operator, key: &str, hash: &blake3::Hash) -> Result<Self> {
+        let had_version = read_existing(storage, &version_path(key, hash))
+            .await?
+            .is_some();
+        Ok(Self {
+            key: key.to_owned(),
+            hash: *hash,
+            had_version,
+            live: read_existing(storage, &format!("{key}.hash")).await?,
+            settings: read_existing(storage, &format!("{key}.json")).await?,
+        })
+    }
+
+    /// Makes the version of the module live before the batch again.
+    async fn switch_back(&self, storage: &opendal::Operator) -> opendal::Result<()> {
+        let key = &self.key;
+        restore(storage, &format!("{key}.hash"), self.live.clone()).await?;
+        restore(storage, &format!("{key}.json"), self.settings.clone()).await
+    }
+}
+
+/// Makes the blob of `hash` the live version of `key`.
+pub async fn set_live_version(
+    storage: &opendal::Operator,
+    key: &str,
+    hash: &blake3::Hash,
+) -> Result<()> {
+    storage
+        .write(&format!("{key}.hash"), hash.to_string())
+        .await?;
+    // Left over from before modules were deduplicated
+    storage.delete(&format!("{key}.wasm")).await?;
+    Ok(())
 }
 
 impl AppState {
-    pub async fn new() -> Result<AppState> {
+    pub async fn new(
+        rvm_config: RvmConfig,
+        stopped_workers: mpsc::UnboundedSender<String>,
+        local_invocations: mpsc::UnboundedSender<LocalInvocation>,
+    ) -> Result<AppState> {
         let mut config = Config::new();
-        // Enable the compilation cache, using the default cache configuration
-        // settings.
-        config.cache_config_load_default()?;
+        // Enable the compilation cache. Running without it is only slower, so
+        // don't refuse to start if it can't be set up.
+        let mut rvm_config = rvm_config;
+        if let Err(e) = configure_cache(&mut config, &rvm_config) {
+            tracing::warn!("Compilation cache disabled: {e:?}");
+            config.disable_cache();
+            rvm_config.cache_enabled = false;
+        }
         config.async_support(true);
+        config.parallel_compilation(rvm_config.parallel_compilation);
+        config.cranelift_opt_level(rvm_config.opt_level.into());
+        rvm_config.check_features()?;
+        config.wasm_backtrace(rvm_config.wasm_backtrace);
+        config.native_unwind_info(rvm_config.native_unwind_info);
+        config.wasm_reference_types(rvm_config.wasm_reference_types);
+        config.cranelift_nan_canonicalization(rvm_config.nan_canonicalization);
+        config.wasm_bulk_memory(rvm_config.wasm_bulk_memory);
+        config.wasm_simd(rvm_config.wasm_simd);
+        // Relaxed SIMD builds on SIMD, and wasmtime refuses to disable only the latter
+        config.wasm_relaxed_simd(rvm_config.wasm_simd);
+        rvm_config.check_memory()?;
+        if let Some(bytes) = rvm_config.memory_reservation {
+            config.memory_reservation(bytes);
+        }
+        if let Some(bytes) = rvm_config.memory_guard_size {
+            config.memory_guard_size(bytes);
+        }
+        if let Some(bytes) = rvm_config.memory_reservation_for_growth {
+            config.memory_reservation_for_growth(bytes);
+        }
 
-        // Configure and enable the pooling allocator with space for 100 memories of
-        // up to 268 KiB in size, 100 tables holding up to 10000 elements, and with a
-        // limit of no more than 100 concurrent instances.
+        // Configure and enable the pooling allocator, by default with space for 100
+        // memories of up to 268 KiB in size, 100 tables holding up to 10000 elements,
+        // and with a limit of no more than 100 concurrent instances.
+        let limits = &rvm_config.pool;
         let mut pool = PoolingAllocationConfig::new();
-        pool.total_memories(100);
-        pool.max_memory_size(1 << 28); // ~268KiB
-        pool.total_tables(100);
-        pool.table_elements(10_000);
-        pool.total_core_instances(100);
+        pool.total_memories(limits.total_memories);
+        pool.max_memory_size(limits.max_memory_size);
+        pool.total_tables(limits.total_tables);
+        pool.table_elements(limits.table_elements);
+        pool.total_core_instances(limits.total_core_instances);
 
         config.allocation_strategy(InstanceAllocationStrategy::Pooling(pool));
         config.memory_init_cow(true);
         config.consume_fuel(true);
+        // Lets invocations running past their timeout be interrupted
+        config.epoch_interruption(true);
 
         // Create an engine with our configuration.
         let engine = Engine::new(&config)?;
+        budget::spawn_epoch_ticker(engine.clone());
 
         // Create an opendal operator for publishing wasm modules
         // We use opendal so you can pick your backing store as you like.
         // For this demo, we use a simple filesystem, but could use redis, gcs, tikv etc.
         // Just switch the service here for something else.
-        let builder = opendal::services::Fs::default().root("./module-store");
-        let storage: opendal::Operator = opendal::Operator::new(builder)?.finish();
+        let storage: opendal::Operator = match rvm_config.storage {
+            StorageKind::Fs => {
+                let root = rvm_config.storage_root.to_string_lossy();
+                // Uploads are written next to the modules and renamed into place once
+                // complete, so an aborted upload never leaves a truncated module behind.
+                let uploads = rvm_config.storage_root.join(".uploads");
+                let builder = opendal::services::Fs::default()
+                    .root(&root)
+                    .atomic_write_dir(&uploads.to_string_lossy());
+                opendal::Operator::new(builder)?.finish()
+            }
+            StorageKind::Memory => {
+                tracing::warn!("Storing modules in memory, they are lost when rvm stops");
+                opendal::Operator::new(opendal::services::Memory::default())?.finish()
+            }
+        };
 
         let mut linker = wasmtime::component::Linker::new(&engine);
         crate::host::rvm::lambda::host::add_to_linker(&mut linker, RvmState::host)?;
+        crate::host::rvm::lambda::kv::add_to_linker(&mut linker, RvmState::host)?;
         wasmtime_wasi_http::add_only_http_to_linker_async(&mut linker)?;
         wasmtime_wasi::add_to_linker_async(&mut linker)?;
-            
+
         let mut state = AppState {
             engine,
             instances: Default::default(),
+            aliases: Default::default(),
+            components: Default::default(),
+            fuel_consumed: Default::default(),
+            cpu_usage: Default::default(),
+            instances_recycled: Default::default(),
+            host_calls: Default::default(),
+            rate_limiters: Default::default(),
+            stores: Default::default(),
+            blob_lock: Default::default(),
             storage,
             linker,
+            invocations: FairScheduler::new(rvm_config.max_concurrent_invocations),
+            deploys: Arc::new(Semaphore::new(
+                rvm_config
+                    .max_concurrent_deploys
+                    .unwrap_or(Semaphore::MAX_PERMITS),
+            )),
+            transforms: Arc::new(Pipeline::new(&rvm_config)),
+            guest_metrics: Arc::new(GuestMetrics::new(rvm_config.max_guest_metrics)),
+            cache: ResponseCache::new(
+                rvm_config.response_cache_bytes,
+                rvm_config.response_cache_max_entry_bytes,
+            ),
+            stopped_workers,
+            local: LocalInvoker::new(local_invocations, rvm_config.max_call_depth),
+            shutdown: Shutdown::default(),
+            config: rvm_config,
         };
 
-        for module_entry in state.storage.list("").await? {
-            if !matches!(module_entry.metadata().mode(), EntryMode::FILE) {
-                continue;
+        if state.config.fuel_sink == FuelSink::Storage {
+            for (key, total) in fuel::load_totals(&state.storage).await? {
+                state
+                    .fuel_consumed
+                    .insert(key, Arc::new(AtomicU64::new(total)));
             }
+        }
+
+        state.reload_modules(false).await?;
+
+        Ok(state)
+    }
+
+    /// Starts the modules found in storage that aren't running yet, and restarts
+    /// the ones whose stored version differs from the running one. Running modules
+    /// missing from storage are stopped if `remove_missing` is set.
+    pub async fn reload_modules(&mut self, remove_missing: bool) -> Result<ReloadSummary> {
+        let mut summary = ReloadSummary::default();
+        let mut stored = HashSet::new();
+        let ordered = stored_keys(&self.storage, self.config.restore_order()).await?;
+        for name in ordered {
             // FIXME:(rasviitanen) run this concurrently
-            let module = state.storage.read(module_entry.path()).await?.to_bytes();
+            let module = read_module(&self.storage, &name).await?;
             tracing::info!("Downloaded {} bytes", module.len());
-            let (tx, rx) = mpsc::unbounded_channel();
             let hash = blake3::hash(&module);
 
-            let name = module_entry.name().trim_end_matches(".wasm").to_owned();
-            tracing::info!(
-                "Restarting previously deployed module `{}` with hash {}",
-                module_entry.name(),
+            stored.insert(name.clone());
+            let module_config = match self.instances.get(&name) {
+                Some(running) if running.hash == hash => continue,
+                Some(running) => {
+                    summary.restarted.push(name.clone());
+                    running.config.clone()
+                }
+                None => {
+                    summary.added.push(name.clone());
+                    // Modules stored without settings don't know their kind, but the
+                    // component's exports tell. Lazily started modules are told when
+                    // they are woken up.
+                    let stored = self.stored_module_config(&name).await;
+                    let kind = match self.config.lazy_start {
+                        true => stored.kind,
+                        false => module_kind(&*self.compile(hash, &module)?),
+                    };
+                    ModuleConfig { kind, ..stored }
+                }
+            };
+            tracing::info!("Starting stored module `{name}` with hash {hash}");
+            let mut module = self
+                .start_worker(&name, hash, module, module_config)
+                .await?;
+            module.origin = ModuleOrigin::Restored;
+            // Runs the version in storage now, unless one is being uploaded
+            if let Some(order) = self.stores.get(&name) {
+                if order.storing.try_lock().is_ok() {
+                    order.unpersisted.store(false, Ordering::Relaxed);
+                }
+            }
+            self.insert_module(name, module);
+        }
+
+        if remove_missing {
+            let (invocations, guest_metrics) = (&self.invocations, &self.guest_metrics);
+            self.instances.retain(|key, _| {
+                let keep = stored.contains(key);
+                if !keep {
+                    tracing::info!(key=%key, "Stopping module missing from storage");
+                    invocations.remove(key);
+                    guest_metrics.remove(key);
+                    summary.removed.push(key.clone());
+                }
+                keep
+            });
+            self.evict_unused_components();
+        }
+        Ok(summary)
+    }
+
+    /// Starts a worker for `bytes`, without registering it as `key`.
+    ///
+    /// The bytes are only compiled if no other module already uses the same component.
+    /// With `lazy_start` nothing is compiled, the module starts idle and is
+    /// started from storage on its first invocation.
+    pub async fn start_worker(
+        &mut self,
+        key: &str,
+        hash: blake3::Hash,
+        bytes: Bytes,
+        module_config: ModuleConfig,
+    ) -> Result<ModuleHandle> {
+        let stdio = GuestStdio::new(&module_config, &self.config);
+        let errors = ErrorLog::default();
+        let sender = match self.config.lazy_start {
+            true => None,
+            false => Some(
+                self.spawn_worker(
+                    key,
+                    hash,
+                    &bytes,
+                    &module_config,
+                    stdio.clone(),
+                    errors.clone(),
+                )
+                .await?,
+            ),
+        };
+        Ok(ModuleHandle {
+            sender,
+            hash,
+            queue_high_water: AtomicUsize::new(0),
+            last_invoked: Mutex::new(Instant::now()),
+            readiness: Readiness::Ready,
+            errors,
+            rate_limiter: self.rate_limiter(key, &module_config),
+            config: module_config,
+            stdio,
+            origin: ModuleOrigin::Deployed,
+        })
+    }
+
+    /// Compiles `bytes` and starts a worker instantiating it, returning the
+    /// sender to invoke it through.
+    async fn spawn_worker(
+        &mut self,
+        key: &str,
+        hash: blake3::Hash,
+        bytes: &Bytes,
+        module_config: &ModuleConfig,
+        stdio: GuestStdio,
+        errors: ErrorLog,
+    ) -> Result<mpsc::Sender<InvokeRequest>> {
+        let component = self.compile(hash, bytes)?;
+
+        let fuel_consumed = self.fuel_consumed.entry(key.to_owned()).or_default();
+        let fuel = FuelMeter::new(key.to_owned(), fuel_consumed.clone(), self.config.fuel_sink);
+        let cpu = self.cpu_usage.entry(key.to_owned()).or_default().clone();
+        let recycled = self
+            .instances_recycled
+            .entry(key.to_owned())
+            .or_default()
+            .clone();
+        let calls = self.host_call_meter(key);
+
+        let linked;
+        let linker = match module_config.dependencies().next() {
+            None => &self.linker,
+            Some(_) => {
+                linked = self
+                    .link_dependencies(key, &component, module_config)
+                    .await?;
+                &linked
+            }
+        };
+
+        let (tx, rx) = mpsc::channel(self.config.worker_queue_capacity);
+        let started = compile_and_start_instance_worker(
+            HostComponent::new(
+                key.to_owned(),
                 hash,
-            );
-            compile_and_start_instance_worker(name.clone(), &state.engine,  &state.linker, rx, module).await?;
-            state.instances.insert(name, tx);
+                self.storage.clone(),
+                self.config.kv_max_value_bytes,
+                self.guest_metrics.clone(),
+                self.local.clone(),
+                calls,
+            ),
+            linker,
+            rx,
+            component,
+            &self.config,
+            module_config.clone(),
+            ModuleOutput {
+                fuel,
+                cpu,
+                recycled,
+                stdio,
+                errors,
+            },
+        )
+        .await;
+        if started.is_err() {
+            self.evict_unused_components();
         }
+        let worker = started?;
 
-        Ok(state)
+        // Panics, e.g. in the host's handling of a request, stop the worker
+        // without the module noticing until it is invoked again.
+        let stopped_workers = self.stopped_workers.clone();
+        let key = key.to_owned();
+        tokio::spawn(async move {
+            if let Err(e) = worker.await {
+                tracing::error!(key=%key, "Worker stopped: {e}");
+            }
+            let _ = stopped_workers.send(key);
+        });
+        Ok(tx)
+    }
+
+    /// The host's linker, with the imports of `component` that its dependencies
+    /// export forwarded to them. Instantiating `component` fails if an import is
+    /// provided by neither.
+    async fn link_dependencies(
+        &mut self,
+        key: &str,
+        component: &Component,
+        module_config: &ModuleConfig,
+    ) -> Result<wasmtime::component::Linker<RvmState>> {
+        let mut linker = self.linker.clone();
+        for dependency in module_config.dependencies() {
+            anyhow::ensure!(dependency != key, "a module can't depend on itself");
+            // Dependencies that aren't running yet, e.g. while reloading, are
+            // started from storage.
+            let (dependency_config, stdio) = match self.instances.get(dependency) {
+                Some(module) => (module.config.clone(), module.stdio.clone()),
+                None => {
+                    let dependency_config = self.stored_module_config(dependency).await;
+                    let stdio = GuestStdio::new(&dependency_config, &self.config);
+                    (dependency_config, stdio)
+                }
+            };
+            let bytes = match read_module(&self.storage, dependency).await {
+                Ok(bytes) => bytes,
+                Err(e) if e.kind() == opendal::ErrorKind::NotFound => {
+                    anyhow::bail!("dependency `{dependency}` isn't deployed")
+                }
+                Err(e) => {
+                    Err(e).with_context(|| format!("failed to read dependency `{dependency}`"))?
+                }
+            };
+            let hash = blake3::hash(&bytes);
+            let dependency_component = self.compile(hash, &bytes)?;
+            let calls = self.host_call_meter(dependency);
+            let fuel_consumed = self.fuel_consumed.entry(dependency.to_owned()).or_default();
+            let linked = Dependency::new(
+                HostComponent::new(
+                    dependency.to_owned(),
+                    hash,
+                    self.storage.clone(),
+                    self.config.kv_max_value_bytes,
+                    self.guest_metrics.clone(),
+                    self.local.clone(),
+                    calls,
+                ),
+                &self.linker,
+                &dependency_component,
+                &self.config,
+                dependency_config,
+                stdio,
+                FuelMeter::new(
+                    dependency.to_owned(),
+                    fuel_consumed.clone(),
+                    self.config.fuel_sink,
+                ),
+            )?
+            .link(&mut linker, component)?;
+            tracing::info!(key=%key, dependency=%dependency, interfaces=?linked, "Linked dependency");
+        }
+        Ok(linker)
+    }
+
+    /// Limits how often `key` is invoked, as configured by `module_config`. The
+    /// tokens left are kept unless the limits changed, so restarting the module
+    /// doesn't grant its clients another burst.
+    fn rate_limiter(
+        &mut self,
+        key: &str,
+        module_config: &ModuleConfig,
+    ) -> Option<Arc<RateLimiter>> {
+        let Some(limiter) = module_config.rate_limiter() else {
+            self.rate_limiters.remove(key);
+            return None;
+        };
+        match self.rate_limiters.get(key) {
+            Some(kept) if kept.has_limits_of(&limiter) => Some(kept.clone()),
+            _ => {
+                let limiter = Arc::new(limiter);
+                self.rate_limiters.insert(key.to_owned(), limiter.clone());
+                Some(limiter)
+            }
+        }
+    }
+
+    /// Meters the host calls of an instance of `key`, at the configured prices.
+    fn host_call_meter(&mut self, key: &str) -> HostCallMeter {
+        let calls = self.host_calls.entry(key.to_owned()).or_default().clone();
+        HostCallMeter::new(Arc::new(self.config.host_call_fuel.clone()), calls)
+    }
+
+    /// Compiles `bytes`, unless a module already uses the same component.
+    fn compile(&mut self, hash: blake3::Hash, bytes: &Bytes) -> Result<Arc<Component>> {
+        match self.components.get(&hash) {
+            Some(component) => {
+                tracing::info!(hash=%hash, "Reusing compiled component");
+                Ok(component.clone())
+            }
+            None => {
+                let component = Arc::new(compile_component(&self.engine, bytes)?);
+                self.components.insert(hash, component.clone());
+                Ok(component)
+            }
+        }
+    }
+
+    /// Whether the component in `bytes` exports the interface modules of `kind`
+    /// are invoked through, in any version.
+    pub fn exports_kind(
+        &mut self,
+        hash: blake3::Hash,
+        bytes: &Bytes,
+        kind: ModuleKind,
+    ) -> Result<bool> {
+        let component = self.compile(hash, bytes)?;
+        let exported = component
+            .component_type()
+            .exports(&self.engine)
+            .any(|(name, _)| name.split('@').next() == Some(kind.export()));
+        Ok(exported)
+    }
+
+    /// Registers `module` as `key`, replacing and stopping any previous module
+    /// with the same key. An alias named `key` is removed, so it doesn't shadow the module.
+    pub fn insert_module(&mut self, key: String, module: ModuleHandle) {
+        if self.aliases.remove(&key).is_some() {
+            tracing::info!(key=%key, "Removed alias replaced by a module");
+        }
+        self.instances.insert(key, module);
+        self.evict_unused_components();
+    }
+
+    /// Recovers `key` if its worker has stopped, so it stops answering with
+    /// errors. The module is restarted from storage if configured to, and
+    /// unregistered otherwise.
+    ///
+    /// A restarting module stays registered, so invocations arriving meanwhile
+    /// are told to retry rather than that it doesn't exist. If it fails to
+    /// start, the next invocation tries again.
+    pub async fn recover_module(state: &SharedState, key: &str) {
+        {
+            let mut state = state.write().await;
+            let restart = state.config.restart_stopped_modules;
+            let Some(module) = state.instances.get_mut(key).filter(|module| {
+                module
+                    .sender
+                    .as_ref()
+                    .is_some_and(|sender| sender.is_closed())
+            }) else {
+                return;
+            };
+            // Drained modules are started by their first invocation once undrained
+            let draining = module.readiness == Readiness::Draining;
+            match restart {
+                true => {
+                    tracing::warn!(key=%key, "Restarting module with a stopped worker");
+                    module.sender = None;
+                }
+                false => {
+                    state.instances.remove(key);
+                    state.invocations.remove(key);
+                    state.guest_metrics.remove(key);
+                    tracing::warn!(key=%key, "Removed module with a stopped worker");
+                }
+            }
+            state.evict_unused_components();
+            if !restart || draining {
+                return;
+            }
+        }
+        match AppState::wake_module(state, key).await {
+            Ok(_) => tracing::info!(key=%key, "Restarted module from storage"),
+            Err(e) => tracing::error!(key=%key, "Failed to restart module: {e:?}"),
+        }
+    }
+
+    /// The settings `key` was stored with, or the configured ones if it has
+    /// none, e.g. when it was stored by an older version.
+    pub async fn stored_module_config(&self, key: &str) -> ModuleConfig {
+        let stored = match self.storage.read(&format!("{key}.json")).await {
+            Ok(stored) => stored.to_bytes(),
+            Err(e) => {
+                if e.kind() != opendal::ErrorKind::NotFound {
+                    tracing::warn!(key=%key, "Failed to read stored settings: {e}");
+                }
+                return self.config.module_config(key);
+            }
+        };
+        serde_json::from_slice(&stored).unwrap_or_else(|e| {
+            tracing::warn!(key=%key, "Ignoring invalid stored settings: {e}");
+            self.config.module_config(key)
+        })
+    }
+
+    /// Drops compiled components that no running module uses anymore.
+    pub fn evict_unused_components(&mut self) {
+        let instances = &self.instances;
+        self.components.retain(|hash, _| {
+            instances
+                .values()
+                .any(|module| module.hash == *hash && module.sender.is_some())
+        });
+    }
+
+    /// Tears down the instances of modules that haven't been invoked for
+    /// `idle_for`, freeing their pool slots, and returns their keys. Pinned
+    /// modules, and modules that aren't in storage yet, are kept running.
+    pub fn evict_idle_modules(&mut self, idle_for: Duration) -> Vec<String> {
+        let mut evicted = Vec::new();
+        let stores = &self.stores;
+        for (key, module) in &mut self.instances {
+            let idle = module.last_invoked.lock().unwrap().elapsed() >= idle_for;
+            if idle && module.sender.is_some() && !module.config.pinned && is_persisted(stores, key)
+            {
+                tracing::info!(key=%key, "Evicting idle module");
+                module.sender = None;
+                evicted.push(key.clone());
+            }
+        }
+        self.evict_unused_components();
+        evicted
+    }
+
+    /// Whether the module running as `key` is in storage, so it survives restarts.
+    pub fn is_persisted(&self, key: &str) -> bool {
+        is_persisted(&self.stores, key)
+    }
+
+    /// Starts a store of `key`, whose turn comes once the stores of `key`
+    /// started before it are done. The module is unpersisted until a store
+    /// started `in_background` is done.
+    pub fn start_store(
+        &mut self,
+        key: &str,
+        in_background: bool,
+    ) -> impl std::future::Future<Output = StoreTurn> + Send + 'static {
+        let order = self.stores.entry(key.to_owned()).or_default().clone();
+        let number = order.latest.fetch_add(1, Ordering::Relaxed) + 1;
+        if in_background {
+            order.unpersisted.store(true, Ordering::Relaxed);
+        }
+        async move {
+            let storing = order.storing.clone().lock_owned().await;
+            StoreTurn {
+                order,
+                number,
+                _storing: storing,
+            }
+        }
+    }
+
+    /// A permit for a deploy, unless `max_concurrent_deploys` are in progress.
+    pub fn try_deploy(&self) -> Option<OwnedSemaphorePermit> {
+        self.deploys.clone().try_acquire_owned().ok()
+    }
+
+    /// Deploys in progress, holding a permit.
+    pub fn deploys_in_progress(&self) -> usize {
+        let limit = self
+            .config
+            .max_concurrent_deploys
+            .unwrap_or(Semaphore::MAX_PERMITS);
+        limit - self.deploys.available_permits()
+    }
+
+    /// The module invocations of `key` go to, picking a backend if it is an alias.
+    pub fn resolve(&self, key: &str) -> String {
+        match self.aliases.get(key) {
+            Some(alias) => alias.pick().to_owned(),
+            None => key.to_owned(),
+        }
+    }
+
+    /// Whether deploying `new_keys` would exceed `max_modules`. Redeploying an
+    /// existing module doesn't count.
+    pub fn exceeds_max_modules<'a>(&self, new_keys: impl IntoIterator<Item = &'a str>) -> bool {
+        let Some(max) = self.config.max_modules else {
+            return false;
+        };
+        let added = new_keys
+            .into_iter()
+            .filter(|key| !self.instances.contains_key(*key))
+            .count();
+        self.instances.len() + added > max
+    }
+
+    /// Whether `key` is a deployed module or an alias.
+    pub fn is_routable(&self, key: &str) -> bool {
+        self.instances.contains_key(key) || self.aliases.contains_key(key)
+    }
+
+    /// Starts a new instance of `key` if it is idle, from its stored version.
+    ///
+    /// The component is compiled without holding up other invocations, which
+    /// see the module as starting meanwhile. Returns whether the module is
+    /// running, which it isn't yet if another invocation is starting it.
+    pub async fn wake_module(state: &SharedState, key: &str) -> Result<bool> {
+        let (storage, engine) = {
+            let mut state = state.write().await;
+            let Some(module) = state.instances.get_mut(key).filter(|m| m.sender.is_none()) else {
+                return Ok(true);
+            };
+            if module.readiness == Readiness::Starting {
+                return Ok(false);
+            }
+            module.readiness = Readiness::Starting;
+            (state.storage.clone(), state.engine.clone())
+        };
+        let compiled = async {
+            let bytes = read_module(&storage, key).await?;
+            let hash = blake3::hash(&bytes);
+            let cached = state.read().await.components.get(&hash).cloned();
+            let component = match cached {
+                Some(component) => component,
+                None => {
+                    // Off the runtime, so it keeps serving other invocations
+                    let bytes = bytes.clone();
+                    let compiled =
+                        tokio::task::spawn_blocking(move || compile_component(&engine, &bytes));
+                    Arc::new(compiled.await??)
+                }
+            };
+            anyhow::Ok((hash, bytes, component))
+        }
+        .await;
+
+        let mut state = state.write().await;
+        let state = &mut *state;
+        let Some(module) = state
+            .instances
+            .get(key)
+            .filter(|m| m.readiness == Readiness::Starting)
+        else {
+            // Redeployed, removed or drained meanwhile
+            return Ok(true);
+        };
+        let (mut module_config, stdio, errors) = (
+            module.config.clone(),
+            module.stdio.clone(),
+            module.errors.clone(),
+        );
+        let started = async {
+            let (hash, bytes, component) = compiled?;
+            // Modules that were never started don't know their kind yet
+            module_config.kind = module_kind(&component);
+            state.components.entry(hash).or_insert(component);
+            let sender = state
+                .spawn_worker(key, hash, &bytes, &module_config, stdio, errors.clone())
+                .await?;
+            anyhow::Ok((hash, sender))
+        }
+        .await;
+        let module = state.instances.get_mut(key).expect("module exists");
+        let (hash, sender) = match started {
+            Ok(started) => started,
+            Err(e) => {
+                errors.record(format!("failed to start: {e:#}"));
+                module.readiness = Readiness::Failed;
+                return Err(e);
+            }
+        };
+        tracing::info!(key=%key, hash=%hash, "Woke up idle module");
+        module.hash = hash;
+        module.config = module_config;
+        module.sender = Some(sender);
+        module.readiness = Readiness::Ready;
+        Ok(true)
+    }
+
+    /// Appends `event` to the audit log. The change it records has already been
+    /// made, so failing to record it is only logged.
+    pub async fn audit(&self, event: AuditEvent) {
+        let storage = self.storage.clone();
+        let key = event.key.clone();
+        // Written from a task of its own, like uploads
+        let recorded = tokio::spawn(async move { audit::record(&storage, &event).await });
+        match recorded.await {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => tracing::error!(key=%key, "Failed to record audit event: {e:?}"),
+            Err(e) => tracing::error!(key=%key, "Failed to record audit event: {e:?}"),
+        }
+    }
+
+    /// Stores `bytes` as the current version of `key`, and keeps a copy of it
+    /// among the versions of `key` that can be rolled back to.
+    pub async fn store_module(
+        &mut self,
+        key: &str,
+        hash: &blake3::Hash,
+        bytes: Bytes,
+        module_config: &ModuleConfig,
+    ) -> Result<()> {
+        self.store_task(key, hash, bytes, module_config, false)
+            .await?
+    }
+
+    /// Like [`AppState::store_module`], but in the background. The version
+    /// isn't made live if `key` is deployed again before the upload is done.
+    pub fn spawn_store_module(
+        &mut self,
+        key: &str,
+        hash: &blake3::Hash,
+        bytes: Bytes,
+        module_config: &ModuleConfig,
+    ) -> tokio::task::JoinHandle<Result<()>> {
+        self.store_task(key, hash, bytes, module_config, true)
+    }
+
+    fn store_task(
+        &mut self,
+        key: &str,
+        hash: &blake3::Hash,
+        bytes: Bytes,
+        module_config: &ModuleConfig,
+        in_background: bool,
+    ) -> tokio::task::JoinHandle<Result<()>> {
+        let turn = self.start_store(key, in_background);
+        let storage = self.storage.clone();
+        let blob_lock = self.blob_lock.clone();
+        let retention = self.config.version_retention;
+        let chunk_bytes = self.config.upload_chunk_bytes;
+        let key = key.to_owned();
+        let hash = *hash;
+        let module_config = serde_json::to_vec(module_config);
+        tokio::spawn(async move {
+            let turn = turn.await;
+            store_version(&storage, &blob_lock, &key, &hash, bytes, chunk_bytes).await?;
+            if turn.superseded() {
+                tracing::info!(key=%key, hash=%hash, "Not making version live, the module was deployed again");
+                return Ok(());
+            }
+            set_live_version(&storage, &key, &hash).await?;
+            // Lets the module be restarted with the settings it was deployed with
+            storage
+                .write(&format!("{key}.json"), module_config?)
+                .await?;
+            turn.stored();
+            prune_versions(&storage, &blob_lock, &key, &hash, retention).await
+        })
+    }
+
+    /// Stores the modules of a batch as their current versions, all of them
+    /// or none: no module is made live until every version is stored, and the
+    /// modules made live are switched back if making another live fails. The
+    /// error names the module that failed to store.
+    pub async fn store_modules(
+        &mut self,
+        modules: Vec<(String, blake3::Hash, Bytes, &ModuleConfig)>,
+    ) -> Result<(), (String, anyhow::Error)> {
+        // Taken in order of their keys, so batches sharing keys don't wait on each other
+        let keys: BTreeSet<&str> = modules.iter().map(|(key, ..)| key.as_str()).collect();
+        let turns: Vec<_> = keys
+            .into_iter()
+            .map(|key| self.start_store(key, false))
+            .collect();
+        let storage = self.storage.clone();
+        let blob_lock = self.blob_lock.clone();
+        let retention = self.config.version_retention;
+        let chunk_bytes = self.config.upload_chunk_bytes;
+        let modules = modules
+            .into_iter()
+            .map(
+                |(key, hash, bytes, module_config)| match serde_json::to_vec(module_config) {
+                    Ok(module_config) => Ok((key, hash, bytes, module_config)),
+                    Err(e) => Err((key, e.into())),
+                },
+            )
+            .collect::<Result<Vec<_>, _>>()?;
+        // Written from a task of its own, so a batch is never left half stored
+        let stored = tokio::spawn(async move {
+            let mut taken = Vec::with_capacity(turns.len());
+            for turn in turns {
+                taken.push(turn.await);
+            }
+            let mut replaced = Vec::with_capacity(modules.len());
+            for (key, hash, ..) in &modules {
+                let before = Replaced::read(&storage, key, hash).await;
+                replaced.push(before.map_err(|e| (key.clone(), e))?);
+            }
+
+            for (key, hash, bytes, _) in &modules {
+                let stored =
+                    store_version(&storage, &blob_lock, key, hash, bytes.clone(), chunk_bytes);
+                if let Err(e) = stored.await {
+                    unstore_batch(&storage, &blob_lock, &replaced).await;
+                    return Err((key.clone(), e));
+                }
+            }
+
+            for (switched, (key, hash, _, module_config)) in modules.iter().enumerate() {
+                let made_live = async {
+                    storage
+                        .write(&format!("{key}.hash"), hash.to_string())
+                        .await?;
+                    storage
+                        .write(&format!("{key}.json"), module_config.clone())
+                        .await
+                };
+                if let Err(e) = made_live.await {
+                    for before in &replaced[..=switched] {
+                        if let Err(e) = before.switch_back(&storage).await {
+                            tracing::error!(key=%before.key, "Failed to switch back module: {e:?}");
+                        }
+                    }
+                    unstore_batch(&storage, &blob_lock, &replaced).await;
+                    return Err((key.clone(), e.into()));
+                }
+            }
+
+            // The batch is live, what's left over only takes up space
+            for turn in &taken {
+                turn.stored();
+            }
+            for (key, hash, ..) in &modules {
+                let cleaned = async {
+                    storage.delete(&format!("{key}.wasm")).await?;
+                    prune_versions(&storage, &blob_lock, key, hash, retention).await
+                };
+                if let Err(e) = cleaned.await {
+                    tracing::warn!(key=%key, "Failed to clean up after storing module: {e:?}");
+                }
+            }
+            Ok(())
+        });
+        stored
+            .await
+            .map_err(|e| (String::new(), anyhow::Error::from(e)))?
+    }
+}
+
+fn is_persisted(stores: &HashMap<String, Arc<StoreOrder>>, key: &str) -> bool {
+    stores
+        .get(key)
+        .is_none_or(|order| !order.unpersisted.load(Ordering::Relaxed))
+}
+
+/// Removes the versions a failed batch stored, unless an earlier deploy
+/// stored them too.
+async fn unstore_batch(storage: &opendal::Operator, blob_lock: &BlobLock, replaced: &[Replaced]) {
+    let mut unstored = Vec::new();
+    for before in replaced.iter().filter(|before| !before.had_version) {
+        match storage
+            .delete(&version_path(&before.key, &before.hash))
+            .await
+        {
+            Ok(()) => unstored.push(before.hash.to_string()),
+            Err(e) => {
+                tracing::error!(key=%before.key, "Failed to remove version of failed batch: {e:?}")
+            }
+        }
+    }
+    if let Err(e) = collect_blobs(storage, blob_lock, unstored).await {
+        tracing::error!("Failed to remove blobs of failed batch: {e:?}");
+    }
+}
+
+/// Writes `bytes` to `path` in chunks of `chunk_bytes`, each written once the
+/// storage has accepted the previous one. A failed upload is aborted rather
+/// than leaving a partial object behind.
+async fn upload(
+    storage: &opendal::Operator,
+    path: &str,
+    bytes: Bytes,
+    chunk_bytes: usize,
+) -> Result<()> {
+    let mut w = storage.writer(path).await?;
+    let result = async {
+        let mut written = 0;
+        while written < bytes.len() {
+            let end = bytes.len().min(written + chunk_bytes.max(1));
+            w.write(bytes.slice(written..end)).await?;
+            written = end;
+            tracing::debug!("Uploaded {written} of {} bytes to {path}", bytes.len());
+        }
+        w.close().await
+    }
+    .await;
+
+    if let Err(e) = result {
+        if let Err(abort) = w.abort().await {
+            tracing::warn!("Failed to abort upload to {path}: {abort}");
+        }
+        return Err(e.into());
+    }
+    Ok(())
+}
+
+/// The interface `component` exports.
+fn module_kind(component: &Component) -> ModuleKind {
+    match component.export_index(None, "rvm:lambda/run@0.1.0") {
+        Some(_) => ModuleKind::Run,
+        None => ModuleKind::Http,
+    }
+}
+
+/// Recovers the modules sent by their stopped workers, restarting them from
+/// storage if configured to.
+pub fn spawn_supervisor(state: SharedState, mut stopped: mpsc::UnboundedReceiver<String>) {
+    tokio::spawn(async move {
+        while let Some(key) = stopped.recv().await {
+            AppState::recover_module(&state, &key).await;
+        }
+    });
+}
+
+/// Evicts idle modules, checking for them every so often.
+pub fn spawn_reaper(state: SharedState, idle_for: Duration) {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(idle_for.min(Duration::from_secs(60)));
+        loop {
+            ticker.tick().await;
+            state.write().await.evict_idle_modules(idle_for);
+        }
+    });
+}
+
+/// Sets up the compilation cache of `config` as configured by `rvm_config`.
+fn configure_cache(config: &mut Config, rvm_config: &RvmConfig) -> Result<()> {
+    if !rvm_config.cache_enabled {
+        config.disable_cache();
+        return Ok(());
+    }
+    let Some(dir) = &rvm_config.cache_dir else {
+        config.cache_config_load_default()?;
+        return Ok(());
+    };
+
+    // wasmtime only loads cache settings from a file, so write one next to the cache.
+    let dir = std::path::absolute(dir)?;
+    std::fs::create_dir_all(&dir)?;
+    let dir_str = dir
+        .to_str()
+        .ok_or_else(|| anyhow::anyhow!("cache directory {dir:?} is not valid UTF-8"))?;
+    let path = dir.join("rvm-cache.toml");
+    let settings = toml::to_string(&CacheFile {
+        cache: CacheSettings {
+            enabled: true,
+            directory: dir_str,
+        },
+    })?;
+    std::fs::write(&path, settings)?;
+    config.cache_config_load(&path)?;
+    Ok(())
+}
+
+/// The cache settings wasmtime loads, see [`configure_cache`].
+#[derive(serde::Serialize)]
+struct CacheFile<'a> {
+    cache: CacheSettings<'a>,
+}
+
+#[derive(serde::Serialize)]
+struct CacheSettings<'a> {
+    enabled: bool,
+    directory: &'a str,
+}
+
+/// Lists the stored versions of `key`, newest first.
+pub async fn list_versions(storage: &opendal::Operator, key: &str) -> Result<Vec<ModuleVersion>> {
+    let mut versions = Vec::new();
+    for entry in storage.list(&format!("versions/{key}/")).await? {
+        if !matches!(entry.metadata().mode(), EntryMode::FILE) {
+            continue;
+        }
+        let Some(hash) = entry.name().strip_suffix(".wasm") else {
+            continue;
+        };
+        let deployed_at = storage.stat(entry.path()).await?.last_modified();
+        versions.push((hash.to_owned(), deployed_at));
+    }
+    versions.sort_by(|(_, a), (_, b)| b.cmp(a));
+
+    Ok(versions
+        .into_iter()
+        .map(|(hash, deployed_at)| ModuleVersion {
+            hash,
+            deployed_at: deployed_at.map(|t| t.to_rfc3339()),
+        })
+        .collect())
+}
+
+/// Removes the oldest versions of `key` beyond `retention`, never removing
+/// the version that is currently live, along with the blobs no version
+/// refers to anymore.
+async fn prune_versions(
+    storage: &opendal::Operator,
+    blob_lock: &BlobLock,
+    key: &str,
+    live: &blake3::Hash,
+    retention: usize,
+) -> Result<()> {
+    let live = live.to_string();
+    let mut pruned = Vec::new();
+    for version in list_versions(storage, key)
+        .await?
+        .into_iter()
+        .filter(|v| v.hash != live)
+        .skip(retention.saturating_sub(1))
+    {
+        tracing::info!(key=%key, hash=%version.hash, "Pruning old module version");
+        storage
+            .delete(&format!("versions/{key}/{}.wasm", version.hash))
+            .await?;
+        pruned.push(version.hash);
+    }
+    collect_blobs(storage, blob_lock, pruned).await
+}
+
+/// Removes the blobs of `hashes` that no version of any module refers to.
+async fn collect_blobs(
+    storage: &opendal::Operator,
+    blob_lock: &BlobLock,
+    hashes: Vec<String>,
+) -> Result<()> {
+    if hashes.is_empty() {
+        return Ok(());
+    }
+    let _collecting = blob_lock.write().await;
+    let referenced: HashSet<String> = storage
+        .list_with("versions/")
+        .recursive(true)
+        .await?
+        .into_iter()
+        .map(|entry| entry.name().to_owned())
+        .collect();
+    let unreferenced: BTreeSet<String> = hashes
+        .into_iter()
+        .filter(|hash| !referenced.contains(&format!("{hash}.wasm")))
+        .collect();
+    for hash in unreferenced {
+        tracing::info!(hash=%hash, "Removing unreferenced blob");
+        storage.delete(&blob_path(hash)).await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::*;
+
+    /// A storage holding the modules `keys`, written in the order given.
+    async fn storage_with(keys: &[&str]) -> opendal::Operator {
+        let storage = opendal::Operator::new(opendal::services::Memory::default())
+            .unwrap()
+            .finish();
+        for key in keys {
+            storage.write(&format!("{key}.wasm"), "").await.unwrap();
+            storage.write(&format!("{key}.json"), "{}").await.unwrap();
+        }
+        storage
+    }
+
+    #[tokio::test]
+    async fn modules_are_restored_in_order_of_their_keys() {
+        let written = ["b", "acme/c", "a", "acme/a"];
+        let storage = storage_with(&written).await;
+        let reversed: Vec<_> = written.iter().rev().copied().collect();
+        let storage_reversed = storage_with(&reversed).await;
+
+        let keys = stored_keys(&storage, std::iter::empty()).await.unwrap();
+        assert_eq!(keys, ["a", "acme/a", "acme/c", "b"]);
+        let keys_reversed = stored_keys(&storage_reversed, std::iter::empty())
+            .await
+            .unwrap();
+        assert_eq!(keys, keys_reversed);
+    }
+
+    #[tokio::test]
+    async fn configured_modules_are_restored_first() {
+        let storage = storage_with(&["a", "b", "c", "acme/d"]).await;
+        let configured = ["acme/d", "missing", "b"].into_iter();
+        let keys = stored_keys(&storage, configured).await.unwrap();
+        assert_eq!(keys, ["acme/d", "b", "a", "c"]);
+    }
+
+    /// Storage that fails the write of the chunk numbered `fail_at` of every
+    /// upload, or only of uploads to `fail_path` if set, recording the chunks
+    /// it accepted and whether uploads were aborted.
+    #[derive(Debug, Clone, Default)]
+    struct FlakyStorage {
+        fail_at: Option<usize>,
+        fail_path: Option<String>,
+        chunks: Arc<AtomicUsize>,
+        aborted: Arc<AtomicBool>,
+    }
+
+    impl<A: opendal::raw::Access> opendal::raw::Layer<A> for FlakyStorage {
+        type LayeredAccess = FlakyAccessor<A>;
+
+        fn layer(&self, inner: A) -> Self::LayeredAccess {
+            FlakyAccessor {
+                inner,
+                storage: self.clone(),
+            }
+        }
+    }
+
+    #[derive(Debug)]
+    struct FlakyAccessor<A> {
+        inner: A,
+        storage: FlakyStorage,
+    }
+
+    struct FlakyWriter<W> {
+        inner: W,
+        written: usize,
+        failing: bool,
+        storage: FlakyStorage,
+    }
+
+    impl<A: opendal::raw::Access> opendal::raw::LayeredAccess for FlakyAccessor<A> {
+        type Inner = A;
+        type Reader = A::Reader;
+        type BlockingReader = A::BlockingReader;
+        type Writer = FlakyWriter<A::Writer>;
+        type BlockingWriter = A::BlockingWriter;
+        type Lister = A::Lister;
+        type BlockingLister = A::BlockingLister;
+        type Deleter = A::Deleter;
+        type BlockingDeleter = A::BlockingDeleter;
+
+        fn inner(&self) -> &A {
+            &self.inner
+        }
+
+        async fn read(
+            &self,
+            path: &str,
+            args: opendal::raw::OpRead,
+        ) -> opendal::Result<(opendal::raw::RpRead, A::Reader)> {
+            self.inner.read(path, args).await
+        }
+
+        async fn write(
+            &self,
+            path: &str,
+            args: opendal::raw::OpWrite,
+        ) -> opendal::Result<(opendal::raw::RpWrite, Self::Writer)> {
+            let (rp, inner) = self.inner.write(path, args).await?;
+            let failing = self
+                .storage
+                .fail_path
+                .as_ref()
+                .is_none_or(|fail_path| fail_path == path);
+            let writer = FlakyWriter {
+                inner,
+                written: 0,
+                failing,
+                storage: self.storage.clone(),
+            };
+            Ok((rp, writer))
+        }
+
+        async fn list(
+            &self,
+            path: &str,
+            args: opendal::raw::OpList,
+        ) -> opendal::Result<(opendal::raw::RpList, A::Lister)> {
+            self.inner.list(path, args).await
+        }
+
+        async fn delete(&self) -> opendal::Result<(opendal::raw::RpDelete, A::Deleter)> {
+            self.inner.delete().await
+        }
+
+        fn blocking_read(
+            &self,
+            path: &str,
+            args: opendal::raw::OpRead,
+        ) -> opendal::Result<(opendal::raw::RpRead, A::BlockingReader)> {
+            self.inner.blocking_read(path, args)
+        }
+
+        fn blocking_write(
+            &self,
+            path: &str,
+            args: opendal::raw::OpWrite,
+        ) -> opendal::Result<(opendal::raw::RpWrite, A::BlockingWriter)> {
+            self.inner.blocking_write(path, args)
+        }
+
+        fn blocking_list(
+            &self,
+            path: &str,
+            args: opendal::raw::OpList,
+        ) -> opendal::Result<(opendal::raw::RpList, A::BlockingLister)> {
+            self.inner.blocking_list(path, args)
+        }
+
+        fn blocking_delete(&self) -> opendal::Result<(opendal::raw::RpDelete, A::BlockingDeleter)> {
+            self.inner.blocking_delete()
+        }
+    }
+
+    impl<W: opendal::raw::oio::Write> opendal::raw::oio::Write for FlakyWriter<W> {
+        async fn write(&mut self, bs: opendal::Buffer) -> opendal::Result<()> {
+            if self.failing && self.storage.fail_at == Some(self.written) {
+                return Err(opendal::Error::new(
+                    opendal::ErrorKind::Unexpected,
+                    "the storage went away",
+                ));
+            }
+            // Slow enough that chunks would pile up if not written one at a time
+            tokio::time::sleep(Duration::from_millis(1)).await;
+            self.inner.write(bs).await?;
+            self.written += 1;
+            self.storage.chunks.fetch_add(1, Ordering::Relaxed);
+            Ok(())
+        }
+
+        async fn close(&mut self) -> opendal::Result<opendal::Metadata> {
+            self.inner.close().await
+        }
+
+        async fn abort(&mut self) -> opendal::Result<()> {
+            self.storage.aborted.store(true, Ordering::Relaxed);
+            self.inner.abort().await
+        }
+    }
+
+    fn flaky_storage(flaky: &FlakyStorage) -> opendal::Operator {
+        opendal::Operator::new(opendal::services::Memory::default())
+            .unwrap()
+            .layer(flaky.clone())
+            .finish()
+    }
+
+    #[tokio::test]
+    async fn uploads_are_written_in_chunks() {
+        let flaky = FlakyStorage::default();
+        let storage = flaky_storage(&flaky);
+        let bytes = Bytes::from(vec![7; 10_000]);
+
+        upload(&storage, "module.wasm", bytes.clone(), 4096)
+            .await
+            .unwrap();
+        assert_eq!(flaky.chunks.load(Ordering::Relaxed), 3);
+        assert!(!flaky.aborted.load(Ordering::Relaxed));
+        let stored = storage.read("module.wasm").await.unwrap().to_bytes();
+        assert_eq!(stored, bytes);
+    }
+
+    #[tokio::test]
+    async fn failed_uploads_are_aborted_without_leaving_a_partial_module() {
+        let flaky = FlakyStorage {
+            fail_at: Some(1),
+            ..Default::default()
+        };
+        let storage = flaky_storage(&flaky);
+        let bytes = Bytes::from(vec![7; 10_000]);
+
+        let uploaded = upload(&storage, "module.wasm", bytes, 4096).await;
+        assert!(uploaded.is_err());
+        assert_eq!(flaky.chunks.load(Ordering::Relaxed), 1);
+        assert!(flaky.aborted.load(Ordering::Relaxed));
+        let stored = storage.stat("module.wasm").await;
+        assert_eq!(stored.unwrap_err().kind(), opendal::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn cache_directories_are_written_as_valid_toml() {
+        let name = format!("rvm \"cache\" \\ \u{7f} {}", std::process::id());
+        let dir = std::env::temp_dir().join(name);
+        let rvm_config = RvmConfig {
+            cache_dir: Some(dir.clone()),
+            ..Default::default()
+        };
+        configure_cache(&mut Config::new(), &rvm_config).unwrap();
+
+        let written = std::fs::read_to_string(dir.join("rvm-cache.toml")).unwrap();
+        let written: toml::Value = toml::from_str(&written).unwrap();
+        assert_eq!(written["cache"]["directory"].as_str(), dir.to_str());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// Every file in `storage`, with its contents.
+    async fn snapshot(storage: &opendal::Operator) -> BTreeMap<String, Bytes> {
+        let mut files = BTreeMap::new();
+        for entry in storage.list_with("").recursive(true).await.unwrap() {
+            if entry.metadata().mode() == EntryMode::FILE {
+                let stored = storage.read(entry.path()).await.unwrap().to_bytes();
+                files.insert(entry.path().to_owned(), stored);
+            }
+        }
+        files
+    }
+
+    #[tokio::test]
+    async fn restarted_modules_keep_their_rate_limit_tokens() {
+        let mut state = memory_state().await;
+        // Started without compiling anything
+        state.config.lazy_start = true;
+        let limited = ModuleConfig {
+            rate_limit_rps: Some(0.01),
+            rate_limit_burst: Some(1),
+            ..Default::default()
+        };
+        let (bytes, hash) = (Bytes::new(), blake3::hash(&[]));
+        let limiter = |module: ModuleHandle| module.rate_limiter.unwrap();
+
+        let started = state.start_worker("a", hash, bytes.clone(), limited.clone());
+        assert!(limiter(started.await.unwrap()).check().is_ok());
+        let restarted = state.start_worker("a", hash, bytes.clone(), limited.clone());
+        assert!(limiter(restarted.await.unwrap()).check().is_err());
+
+        let reconfigured = ModuleConfig {
+            rate_limit_burst: Some(2),
+            ..limited
+        };
+        let redeployed = state.start_worker("a", hash, bytes, reconfigured);
+        assert!(limiter(redeployed.await.unwrap()).check().is_ok());
+    }
+
+    #[tokio::test]
+    async fn modules_missing_from_storage_lose_their_metrics() {
+        let mut state = memory_state().await;
+        state.config.lazy_start = true;
+        state.storage.write("a.wasm", "").await.unwrap();
+        state.reload_modules(true).await.unwrap();
+        state.guest_metrics.incr("a", "requests", 1);
+        assert!(state
+            .guest_metrics
+            .render()
+            .contains("rvm_guest_requests_total"));
+
+        state.storage.delete("a.wasm").await.unwrap();
+        state.reload_modules(true).await.unwrap();
+        assert!(!state.instances.contains_key("a"));
+        assert!(state.guest_metrics.render().is_empty());
+    }
+
+    #[tokio::test]
+    async fn pruning_keeps_the_blobs_other_modules_refer_to() {
+        let state = memory_state().await;
+        let storage = &state.storage;
+        let [shared, own, live] =
+            [b"shared", b"own!!!", b"live!!"].map(|bytes| blake3::hash(bytes));
+        for hash in [shared, own, live] {
+            storage.write(&version_path("a", &hash), "").await.unwrap();
+            storage.write(&blob_path(hash), "").await.unwrap();
+        }
+        storage
+            .write(&version_path("b", &shared), "")
+            .await
+            .unwrap();
+
+        prune_versions(storage, &state.blob_lock, "a", &live, 1)
+            .await
+            .unwrap();
+        assert!(storage.exists(&blob_path(shared)).await.unwrap());
+        assert!(!storage.exists(&blob_path(own)).await.unwrap());
+        assert!(storage.exists(&blob_path(live)).await.unwrap());
+        assert_eq!(list_versions(storage, "a").await.unwrap().len(), 1);
+    }
+
+    /// A host storing its modules in memory.
+    async fn memory_state() -> AppState {
+        let (stopped_workers, _) = mpsc::unbounded_channel();
+        let (local_invocations, _) = mpsc::unbounded_channel();
+        let config = RvmConfig {
+            storage: StorageKind::Memory,
+            cache_enabled: false,
+            ..Default::default()
+        };
+        AppState::new(config, stopped_workers, local_invocations)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn precompiled_artifacts_are_loaded_if_built_with_the_same_settings() {
+        let state = memory_state().await;
+        let component = wat::parse_str("(component)").unwrap();
+        let precompiled = state.engine.precompile_component(&component).unwrap();
+        compile_component(&state.engine, &precompiled).unwrap();
+
+        let mut without_fuel = Config::new();
+        without_fuel.epoch_interruption(true);
+        let without_fuel = Engine::new(&without_fuel).unwrap();
+        let precompiled = without_fuel.precompile_component(&component).unwrap();
+        let Err(e) = compile_component(&state.engine, &precompiled) else {
+            panic!("loaded a component precompiled without fuel support");
+        };
+        assert!(format!("{e:#}").contains("without fuel support"), "{e:#}");
+    }
+
+    #[tokio::test]
+    async fn background_uploads_finishing_last_leave_the_later_version_live() {
+        let mut state = memory_state().await;
+        // Uploads the older version slowly, chunk by chunk
+        state.storage = state.storage.layer(FlakyStorage::default());
+        state.config.upload_chunk_bytes = 1000;
+        let module_config = ModuleConfig::default();
+        let (older, newer) = (Bytes::from(vec![1; 100_000]), Bytes::from("newer"));
+
+        let upload = state.spawn_store_module("a", &blake3::hash(&older), older, &module_config);
+        assert!(!state.is_persisted("a"));
+        state
+            .store_module("a", &blake3::hash(&newer), newer.clone(), &module_config)
+            .await
+            .unwrap();
+        upload.await.unwrap().unwrap();
+
+        assert_eq!(read_module(&state.storage, "a").await.unwrap(), newer);
+        assert!(state.is_persisted("a"));
+    }
+
+    /// Stores a batch deploying a new version of `a` along with `b`, in a
+    /// storage that fails to write `fail_path` and holds a version of `a`.
+    async fn store_failing_batch(fail_path: impl Fn(blake3::Hash) -> String) {
+        let mut state = memory_state().await;
+        let module_config = ModuleConfig::default();
+        let (a_before, a_after, b) = (
+            Bytes::from("a, before"),
+            Bytes::from("a, after"),
+            Bytes::from("b"),
+        );
+        state
+            .store_module("a", &blake3::hash(&a_before), a_before, &module_config)
+            .await
+            .unwrap();
+        let flaky = FlakyStorage {
+            fail_at: Some(0),
+            fail_path: Some(fail_path(blake3::hash(&b))),
+            ..Default::default()
+        };
+        state.storage = state.storage.layer(flaky);
+        let before = snapshot(&state.storage).await;
+        assert!(before.contains_key("a.hash"));
+
+        let batch = vec![
+            (
+                String::from("a"),
+                blake3::hash(&a_after),
+                a_after,
+                &module_config,
+            ),
+            (String::from("b"), blake3::hash(&b), b, &module_config),
+        ];
+        let (failed, _) = state.store_modules(batch).await.unwrap_err();
+        assert_eq!(failed, "b");
+        assert_eq!(snapshot(&state.storage).await, before);
+    }
+
+    #[tokio::test]
+    async fn batches_failing_to_store_a_version_leave_the_storage_unchanged() {
+        store_failing_batch(blob_path).await;
+    }
+
+    #[tokio::test]
+    async fn batches_failing_to_make_a_version_live_leave_the_storage_unchanged() {
+        store_failing_batch(|_| String::from("b.json")).await;
     }
 }
@@ -0,0 +1,140 @@
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+};
+
+use axum::body::Bytes;
+use wasmtime_wasi::{pipe::SinkOutputStream, OutputStream, Pollable, StdoutStream, StreamResult};
+
+use crate::config::{ModuleConfig, RvmConfig, StdioMode};
+
+/// The stdout and stderr of a module, shared by every instance of it.
+#[derive(Clone)]
+pub struct GuestStdio {
+    pub stdout: GuestOutput,
+    pub stderr: GuestOutput,
+}
+
+impl GuestStdio {
+    /// The stdio of a module, its own `stdio` taking precedence over the host's.
+    /// Captured output is kept in buffers holding the last `log_buffer_bytes`
+    /// of each stream.
+    pub fn new(module_config: &ModuleConfig, config: &RvmConfig) -> Self {
+        let mode = module_config.stdio.unwrap_or(config.stdio);
+        let log_buffer_bytes = match mode {
+            StdioMode::Capture => config.log_buffer_bytes,
+            StdioMode::Inherit | StdioMode::Null => 0,
+        };
+        let stream = |host: Box<dyn OutputStream>| match mode {
+            StdioMode::Null => Box::new(SinkOutputStream),
+            StdioMode::Inherit | StdioMode::Capture => host,
+        };
+        Self {
+            stdout: GuestOutput::new(stream(wasmtime_wasi::stdout().stream()), log_buffer_bytes),
+            stderr: GuestOutput::new(stream(wasmtime_wasi::stderr().stream()), log_buffer_bytes),
+        }
+    }
+
+    /// Collects the output written from now on, instead of forwarding it to the host.
+    pub fn start_capture(&self) {
+        self.stdout.start_capture();
+        self.stderr.start_capture();
+    }
+
+    /// Stops capturing and returns what was written to stdout and stderr.
+    pub fn end_capture(&self) -> (Vec<u8>, Vec<u8>) {
+        (self.stdout.end_capture(), self.stderr.end_capture())
+    }
+}
+
+/// An output stream of a guest, kept in its log buffer or forwarded to the
+/// host's stream of the same kind, unless an invocation is capturing it.
+#[derive(Clone)]
+pub struct GuestOutput {
+    host: Arc<Mutex<Box<dyn OutputStream>>>,
+    capture: Arc<Mutex<Option<Vec<u8>>>>,
+    log: Option<Arc<Mutex<LogBuffer>>>,
+}
+
+impl GuestOutput {
+    fn new(host: Box<dyn OutputStream>, log_buffer_bytes: usize) -> Self {
+        Self {
+            host: Arc::new(Mutex::new(host)),
+            capture: Default::default(),
+            log: (log_buffer_bytes > 0).then(|| {
+                Arc::new(Mutex::new(LogBuffer {
+                    bytes: VecDeque::new(),
+                    capacity: log_buffer_bytes,
+                }))
+            }),
+        }
+    }
+
+    /// The most recent output kept in the log buffer, `None` if it isn't kept.
+    pub fn logs(&self) -> Option<Vec<u8>> {
+        let log = self.log.as_ref()?.lock().unwrap();
+        Some(log.bytes.iter().copied().collect())
+    }
+
+    fn start_capture(&self) {
+        *self.capture.lock().unwrap() = Some(Vec::new());
+    }
+
+    fn end_capture(&self) -> Vec<u8> {
+        self.capture.lock().unwrap().take().unwrap_or_default()
+    }
+}
+
+/// Keeps the last `capacity` bytes written to it.
+struct LogBuffer {
+    bytes: VecDeque<u8>,
+    capacity: usize,
+}
+
+impl LogBuffer {
+    fn push(&mut self, bytes: &[u8]) {
+        let bytes = &bytes[bytes.len().saturating_sub(self.capacity)..];
+        let overflow = (self.bytes.len() + bytes.len()).saturating_sub(self.capacity);
+        self.bytes.drain(..overflow);
+        self.bytes.extend(bytes);
+    }
+}
+
+impl StdoutStream for GuestOutput {
+    fn stream(&self) -> Box<dyn OutputStream> {
+        Box::new(self.clone())
+    }
+
+    fn isatty(&self) -> bool {
+        false
+    }
+}
+
+#[wasmtime_wasi::async_trait]
+impl OutputStream for GuestOutput {
+    fn write(&mut self, bytes: Bytes) -> StreamResult<()> {
+        if let Some(captured) = self.capture.lock().unwrap().as_mut() {
+            captured.extend_from_slice(&bytes);
+            return Ok(());
+        }
+        if let Some(log) = &self.log {
+            log.lock().unwrap().push(&bytes);
+            return Ok(());
+        }
+        self.host.lock().unwrap().write(bytes)
+    }
+
+    fn flush(&mut self) -> StreamResult<()> {
+        self.host.lock().unwrap().flush()
+    }
+
+    fn check_write(&mut self) -> StreamResult<usize> {
+        self.host.lock().unwrap().check_write()
+    }
+}
+
+#[wasmtime_wasi::async_trait]
+impl Pollable for GuestOutput {
+    // Writes to the host's stdio block rather than wait for readiness.
+    async fn ready(&mut self) {}
+}
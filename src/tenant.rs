@@ -0,0 +1,47 @@
+use hyper::HeaderMap;
+
+use crate::error::RvmError;
+
+/// Header naming the tenant a module is deployed and invoked for. The modules
+/// of a tenant are registered and stored as `{tenant}/{key}`, so they can't
+/// collide with those of other tenants.
+pub const X_RVM_TENANT: &str = "x-rvm-tenant";
+
+/// Directories in storage holding other things than the modules of a tenant.
+const RESERVED: [&str; 6] = ["versions", "blobs", "kv", "fuel", "audit", "deadletter"];
+
+/// Whether `name` can be used as a tenant, and a directory in storage named
+/// `name` holds the modules of that tenant.
+pub fn is_tenant(name: &str) -> bool {
+    !name.is_empty()
+        && !RESERVED.contains(&name)
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// Whether `key` can name a module, scoped to a tenant or not. Keys can't
+/// contain a `/`, as they would name the modules of a tenant or another path
+/// in storage, nor be `.` or `..`.
+pub fn is_key(key: &str) -> bool {
+    !key.is_empty() && key != "." && key != ".." && !key.contains('/')
+}
+
+/// The key `key` is registered and stored as, scoped to the tenant named in
+/// `headers` if any.
+pub fn scoped_key(headers: &HeaderMap, key: &str) -> Result<String, RvmError> {
+    if !is_key(key) {
+        tracing::warn!("Invalid key {key:?}");
+        return Err(RvmError::BadRequest(format!("invalid key `{key}`")));
+    }
+    let Some(tenant) = headers.get(X_RVM_TENANT) else {
+        return Ok(key.to_owned());
+    };
+    match tenant.to_str() {
+        Ok(tenant) if is_tenant(tenant) => Ok(format!("{tenant}/{key}")),
+        _ => {
+            tracing::warn!("Invalid tenant {tenant:?}");
+            Err(RvmError::BadRequest(format!("invalid {X_RVM_TENANT}")))
+        }
+    }
+}
@@ -0,0 +1,143 @@
+use hyper::{
+    header::{self, HeaderMap, HeaderName},
+    StatusCode,
+};
+use wasmtime_wasi_http::body::HyperOutgoingBody;
+
+use crate::{config::RvmConfig, host::InvokeBody};
+
+/// Host-side policy applied around every invocation, so modules don't each
+/// have to implement it.
+pub trait Transform: Send + Sync {
+    /// Inspects or modifies `request` before the guest sees it, refusing it
+    /// with the returned status.
+    fn request(&self, _request: &mut hyper::Request<InvokeBody>) -> Result<(), StatusCode> {
+        Ok(())
+    }
+
+    /// Inspects or modifies the guest's `response` before the client sees it,
+    /// answering with the returned status instead.
+    fn response(
+        &self,
+        _response: &mut hyper::Response<HyperOutgoingBody>,
+    ) -> Result<(), StatusCode> {
+        Ok(())
+    }
+}
+
+/// The transforms applied to invocations, in order.
+#[derive(Default)]
+pub struct Pipeline(Vec<Box<dyn Transform>>);
+
+impl Pipeline {
+    /// The built-in transforms named by `transforms`.
+    pub fn new(config: &RvmConfig) -> Self {
+        let mut pipeline = Self::default();
+        for name in config
+            .transforms
+            .split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+        {
+            match name {
+                "strip-hop-by-hop" => pipeline.push(StripHopByHop),
+                "max-headers" => pipeline.push(MaxHeaders(config.max_headers)),
+                _ => tracing::warn!("Ignoring unknown transform `{name}`"),
+            }
+        }
+        pipeline
+    }
+
+    pub fn push(&mut self, transform: impl Transform + 'static) {
+        self.0.push(Box::new(transform));
+    }
+
+    pub fn request(&self, request: &mut hyper::Request<InvokeBody>) -> Result<(), StatusCode> {
+        self.0
+            .iter()
+            .try_for_each(|transform| transform.request(request))
+    }
+
+    pub fn response(
+        &self,
+        response: &mut hyper::Response<HyperOutgoingBody>,
+    ) -> Result<(), StatusCode> {
+        self.0
+            .iter()
+            .try_for_each(|transform| transform.response(response))
+    }
+}
+
+/// Removes the headers that only concern a single connection, those listed
+/// in `Connection` included. `Trailer` is kept, as streamed responses announce
+/// their trailers with it.
+pub struct StripHopByHop;
+
+impl StripHopByHop {
+    const HEADERS: [HeaderName; 8] = [
+        header::CONNECTION,
+        HeaderName::from_static("keep-alive"),
+        HeaderName::from_static("proxy-connection"),
+        header::PROXY_AUTHENTICATE,
+        header::PROXY_AUTHORIZATION,
+        header::TE,
+        header::TRANSFER_ENCODING,
+        header::UPGRADE,
+    ];
+
+    fn strip(headers: &mut HeaderMap) {
+        let listed: Vec<HeaderName> = headers
+            .get_all(header::CONNECTION)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .filter_map(|name| name.trim().parse().ok())
+            .collect();
+        for name in Self::HEADERS.iter().chain(&listed) {
+            headers.remove(name);
+        }
+    }
+}
+
+impl Transform for StripHopByHop {
+    fn request(&self, request: &mut hyper::Request<InvokeBody>) -> Result<(), StatusCode> {
+        Self::strip(request.headers_mut());
+        Ok(())
+    }
+
+    fn response(
+        &self,
+        response: &mut hyper::Response<HyperOutgoingBody>,
+    ) -> Result<(), StatusCode> {
+        Self::strip(response.headers_mut());
+        Ok(())
+    }
+}
+
+/// Refuses requests, and guest responses, with more than this many headers.
+pub struct MaxHeaders(pub usize);
+
+impl Transform for MaxHeaders {
+    fn request(&self, request: &mut hyper::Request<InvokeBody>) -> Result<(), StatusCode> {
+        match request.headers().len() > self.0 {
+            true => Err(StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE),
+            false => Ok(()),
+        }
+    }
+
+    fn response(
+        &self,
+        response: &mut hyper::Response<HyperOutgoingBody>,
+    ) -> Result<(), StatusCode> {
+        match response.headers().len() > self.0 {
+            true => {
+                tracing::warn!(
+                    headers = response.headers().len(),
+                    "Guest sent too many headers"
+                );
+                Err(StatusCode::BAD_GATEWAY)
+            }
+            false => Ok(()),
+        }
+    }
+}